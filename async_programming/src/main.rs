@@ -5,13 +5,16 @@
     unused_mut,
     unreachable_code
 )]
-use std::{error::Error, time::Duration};
+use proxy::{Balance, Proxy, ProxyConfig};
+use std::{env, error::Error, time::Duration};
 use tokio::{
     fs,
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
 };
 
+mod proxy;
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
     let port = 7878u16;
@@ -19,6 +22,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let listener = TcpListener::bind(listen_address).await.unwrap();
     println!("Listening on port {}", port);
 
+    // reverse proxy mode:
+    // cargo run -- --proxy 127.0.0.1:9001,127.0.0.1:9002 [--least-conn]
+    let args: Vec<String> = env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--proxy") {
+        let backends = args
+            .get(i + 1)
+            .ok_or("--proxy needs a comma separated list of host:port backends")?
+            .split(',')
+            .map(String::from)
+            .collect();
+        let mut config = ProxyConfig::new(backends);
+        if args.iter().any(|arg| arg == "--least-conn") {
+            config.balance = Balance::LeastConnections;
+        }
+        println!("Proxying to {:?} ({:?})", config.backends, config.balance);
+
+        let proxy = Proxy::new(config);
+        proxy.spawn_health_checks();
+        proxy.serve(listener).await?;
+        return Ok(());
+    }
+
     loop {
         let (stream, _) = listener.accept().await.unwrap();
         tokio::spawn(async { handle_connection(stream).await });
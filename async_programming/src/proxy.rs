@@ -0,0 +1,1187 @@
+// A small HTTP/1.1 reverse proxy / load balancer built on the same tokio
+// primitives as the static file server in main.rs.
+//
+// Every client request is parsed, an upstream backend is picked (round-robin
+// or least-connections), the request is forwarded over a pooled keep-alive
+// connection and the response is relayed back to the client.
+// Backends that fail are ejected for a while (passive health checking) and a
+// background task probes every backend periodically (active health checking).
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+
+/// How the proxy chooses a backend for the next request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+/// Settings for [`Proxy`].
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// upstream backends as `host:port`
+    pub backends: Vec<String>,
+    pub balance: Balance,
+    /// how long to wait for a TCP connection to a backend
+    pub connect_timeout: Duration,
+    /// how long to wait for a backend to send back a complete response
+    pub response_timeout: Duration,
+    /// how often the active health check probes every backend
+    pub health_check_interval: Duration,
+    /// `None` only checks that a TCP connection can be opened,
+    /// `Some(path)` also expects a 2xx/3xx answer to `GET path`
+    pub health_check_path: Option<String>,
+    /// consecutive failed requests before a backend is ejected
+    pub max_failures: u32,
+    /// how long an ejected backend is left out of the rotation
+    pub ejection_time: Duration,
+    /// idle keep-alive connections kept per backend
+    pub max_idle_per_backend: usize,
+    /// largest request body accepted from a client, bigger ones get a 413
+    pub max_body: usize,
+}
+
+impl ProxyConfig {
+    pub fn new(backends: Vec<String>) -> ProxyConfig {
+        ProxyConfig {
+            backends,
+            balance: Balance::RoundRobin,
+            connect_timeout: Duration::from_secs(2),
+            response_timeout: Duration::from_secs(30),
+            health_check_interval: Duration::from_secs(10),
+            health_check_path: None,
+            max_failures: 3,
+            ejection_time: Duration::from_secs(30),
+            max_idle_per_backend: 8,
+            max_body: 10 * 1024 * 1024,
+        }
+    }
+}
+
+// keep-alive connection to a backend, the BufReader keeps any bytes that
+// were read ahead so the connection can be handed to the next request
+type Upstream = BufReader<TcpStream>;
+
+struct Backend {
+    addr: String,
+    // requests currently in flight, used by least-connections
+    active: AtomicUsize,
+    // result of the last active health check
+    healthy: AtomicBool,
+    // consecutive failed requests, used for passive ejection
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    idle: Mutex<Vec<Upstream>>,
+}
+
+impl Backend {
+    fn new(addr: String) -> Backend {
+        Backend {
+            addr,
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // the ejection is over, give the backend another chance
+                *ejected_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+    }
+
+    fn record_failure(&self, config: &ProxyConfig) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= config.max_failures {
+            println!("Ejecting backend {} after {} failures", self.addr, failures);
+            *self.ejected_until.lock().unwrap() = Some(Instant::now() + config.ejection_time);
+            self.failures.store(0, Ordering::SeqCst);
+            // pooled connections to a broken backend are most likely dead too
+            self.idle.lock().unwrap().clear();
+        }
+    }
+
+    fn take_idle(&self) -> Option<Upstream> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    fn put_idle(&self, upstream: Upstream, max_idle: usize) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push(upstream);
+        }
+    }
+}
+
+// decrements the in-flight counter even if the request future is dropped
+struct ActiveGuard<'a>(&'a Backend);
+
+impl<'a> ActiveGuard<'a> {
+    fn new(backend: &'a Backend) -> ActiveGuard<'a> {
+        backend.active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard(backend)
+    }
+}
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+enum ProxyError {
+    // nothing was sent yet, so another backend can be tried
+    Connect(io::Error),
+    Upstream(io::Error),
+    Timeout,
+    NoBackend,
+}
+
+impl ProxyError {
+    fn status(&self) -> (u16, &'static str) {
+        match self {
+            ProxyError::Connect(_) | ProxyError::Upstream(_) => (502, "Bad Gateway"),
+            ProxyError::Timeout => (504, "Gateway Timeout"),
+            ProxyError::NoBackend => (503, "Service Unavailable"),
+        }
+    }
+}
+
+/// A reverse proxy that spreads requests over [`ProxyConfig::backends`].
+pub struct Proxy {
+    config: ProxyConfig,
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+}
+
+impl Proxy {
+    /// Create a new Proxy.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if there are no backends.
+    pub fn new(config: ProxyConfig) -> Arc<Proxy> {
+        assert!(!config.backends.is_empty());
+
+        let backends = config.backends.iter().cloned().map(Backend::new).collect();
+        Arc::new(Proxy {
+            config,
+            backends,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Accept clients forever, every connection runs in its own task.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let proxy = Arc::clone(&self);
+            tokio::spawn(async move { proxy.handle_client(stream, peer).await });
+        }
+    }
+
+    /// Probe every backend each `health_check_interval` in the background.
+    pub fn spawn_health_checks(self: &Arc<Self>) -> JoinHandle<()> {
+        let proxy = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(proxy.config.health_check_interval);
+            loop {
+                interval.tick().await;
+                proxy.check_backends().await;
+            }
+        })
+    }
+
+    /// Run one round of active health checks.
+    pub async fn check_backends(&self) {
+        for backend in &self.backends {
+            let healthy = self.probe(backend).await;
+            let was_healthy = backend.healthy.swap(healthy, Ordering::SeqCst);
+            if was_healthy != healthy {
+                println!(
+                    "Backend {} is now {}",
+                    backend.addr,
+                    if healthy { "up" } else { "down" }
+                );
+            }
+        }
+    }
+
+    async fn probe(&self, backend: &Backend) -> bool {
+        let mut stream = match self.connect(backend).await {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let path = match &self.config.health_check_path {
+            Some(path) => path,
+            None => return true,
+        };
+
+        let check = async {
+            let request = format!(
+                "GET {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                backend.addr
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut status_line = String::new();
+            BufReader::new(&mut stream)
+                .read_line(&mut status_line)
+                .await?;
+            Ok::<_, io::Error>(status_line)
+        };
+        match timeout(self.config.response_timeout, check).await {
+            Ok(Ok(status_line)) => matches!(
+                status_line.split_whitespace().nth(1),
+                Some(code) if code.starts_with('2') || code.starts_with('3')
+            ),
+            _ => false,
+        }
+    }
+
+    fn pick(&self, skip: &[usize]) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.backends.len())
+            .filter(|i| !skip.contains(i) && self.backends[*i].is_available())
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        match self.config.balance {
+            Balance::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::SeqCst);
+                Some(candidates[n % candidates.len()])
+            }
+            // ties go to the backend listed first
+            Balance::LeastConnections => candidates
+                .into_iter()
+                .min_by_key(|i| self.backends[*i].active.load(Ordering::SeqCst)),
+        }
+    }
+
+    async fn handle_client(&self, stream: TcpStream, peer: SocketAddr) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            let mut request = match read_request(&mut reader, self.config.max_body).await {
+                Ok(Some(request)) => request,
+                // the client hung up between requests
+                Ok(None) => return,
+                // the body is left unread, so the connection can't go on
+                Err(e) if e.kind() == io::ErrorKind::QuotaExceeded => {
+                    println!("Request head from {} is too large: {}", peer, e);
+                    let _ = writer
+                        .write_all(&error_response(431, "Request Header Fields Too Large"))
+                        .await;
+                    return;
+                }
+                Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                    println!("Request body from {} is too large: {}", peer, e);
+                    let _ = writer
+                        .write_all(&error_response(413, "Payload Too Large"))
+                        .await;
+                    return;
+                }
+                Err(e) => {
+                    println!("Bad request from {}: {}", peer, e);
+                    let _ = writer.write_all(&error_response(400, "Bad Request")).await;
+                    return;
+                }
+            };
+            println!("Incoming request for {} {}", request.method, request.target);
+            add_forwarded_for(&mut request.headers, peer);
+
+            let (response, keep_alive) = match self.forward(&request).await {
+                Ok(response) => {
+                    let keep_alive = request.keep_alive() && response.keep_alive;
+                    (response.to_bytes(keep_alive), keep_alive)
+                }
+                Err(e) => {
+                    println!("Proxy error for {}: {:?}", request.target, e);
+                    let (code, reason) = e.status();
+                    (error_response(code, reason), false)
+                }
+            };
+
+            if writer.write_all(&response).await.is_err() || !keep_alive {
+                return;
+            }
+        }
+    }
+
+    async fn forward(&self, request: &Request) -> Result<Response, ProxyError> {
+        let mut tried = Vec::new();
+        loop {
+            let index = self.pick(&tried).ok_or(ProxyError::NoBackend)?;
+            tried.push(index);
+            let backend = &self.backends[index];
+            let _active = ActiveGuard::new(backend);
+
+            match self.forward_to(backend, request).await {
+                Ok(response) => {
+                    backend.record_success();
+                    return Ok(response);
+                }
+                // the request never reached this backend, try the next one
+                Err(ProxyError::Connect(e)) => {
+                    println!("Could not connect to {}: {}", backend.addr, e);
+                    backend.record_failure(&self.config);
+                }
+                Err(e) => {
+                    backend.record_failure(&self.config);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn forward_to(
+        &self,
+        backend: &Backend,
+        request: &Request,
+    ) -> Result<Response, ProxyError> {
+        let bytes = request.to_bytes(&backend.addr);
+
+        // a pooled connection may have been closed by the backend in the
+        // meantime; if it was gone before any of the response arrived, the
+        // backend never saw the request and it is sent again on a fresh
+        // connection, but only if sending it twice can't do any harm
+        if let Some(mut upstream) = backend.take_idle() {
+            match timeout(
+                self.config.response_timeout,
+                exchange(&mut upstream, &bytes, &request.method),
+            )
+            .await
+            {
+                Ok(Ok(response)) => {
+                    if response.keep_alive {
+                        backend.put_idle(upstream, self.config.max_idle_per_backend);
+                    }
+                    return Ok(response);
+                }
+                Ok(Err(Failed::Stale(_))) if retryable(&request.method) => {}
+                Ok(Err(Failed::Stale(e) | Failed::Upstream(e))) => {
+                    return Err(ProxyError::Upstream(e))
+                }
+                Err(_) => return Err(ProxyError::Timeout),
+            }
+        }
+
+        let stream = self.connect(backend).await.map_err(ProxyError::Connect)?;
+        let mut upstream = BufReader::new(stream);
+        let response = timeout(
+            self.config.response_timeout,
+            exchange(&mut upstream, &bytes, &request.method),
+        )
+        .await
+        .map_err(|_| ProxyError::Timeout)?
+        .map_err(|(Failed::Stale(e) | Failed::Upstream(e))| ProxyError::Upstream(e))?;
+
+        if response.keep_alive {
+            backend.put_idle(upstream, self.config.max_idle_per_backend);
+        }
+        Ok(response)
+    }
+
+    async fn connect(&self, backend: &Backend) -> io::Result<TcpStream> {
+        match timeout(
+            self.config.connect_timeout,
+            TcpStream::connect(&backend.addr),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+        }
+    }
+}
+
+// how sending a request over a connection failed
+enum Failed {
+    // the connection was gone before any of the response arrived
+    Stale(io::Error),
+    Upstream(io::Error),
+}
+
+async fn exchange(
+    upstream: &mut Upstream,
+    request: &[u8],
+    method: &str,
+) -> Result<Response, Failed> {
+    upstream
+        .get_mut()
+        .write_all(request)
+        .await
+        .map_err(Failed::Stale)?;
+    match upstream.fill_buf().await {
+        Ok([]) => {
+            return Err(Failed::Stale(invalid("backend closed the connection")));
+        }
+        Ok(_) => {}
+        Err(e) if is_reset(&e) => return Err(Failed::Stale(e)),
+        Err(e) => return Err(Failed::Upstream(e)),
+    }
+    read_response(upstream, method == "HEAD")
+        .await
+        .map_err(Failed::Upstream)
+}
+
+fn is_reset(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+// methods without side effects, which may reach a backend twice
+fn retryable(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE")
+}
+
+// headers that only make sense for a single hop and are not forwarded
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "expect",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
+
+type Headers = Vec<(String, String)>;
+
+fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn add_forwarded_for(headers: &mut Headers, peer: SocketAddr) {
+    let ip = peer.ip().to_string();
+    match headers
+        .iter_mut()
+        .find(|(key, _)| key.eq_ignore_ascii_case("x-forwarded-for"))
+    {
+        // append ourselves to the chain of proxies
+        Some((_, value)) => *value = format!("{value}, {ip}"),
+        None => headers.push(("X-Forwarded-For".to_string(), ip)),
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    version: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn keep_alive(&self) -> bool {
+        let connection = header(&self.headers, "connection").unwrap_or("");
+        if self.version == "HTTP/1.0" {
+            connection.eq_ignore_ascii_case("keep-alive")
+        } else {
+            !connection.eq_ignore_ascii_case("close")
+        }
+    }
+
+    fn to_bytes(&self, host: &str) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.target);
+        if header(&self.headers, "host").is_none() {
+            head.push_str(&format!("Host: {host}\r\n"));
+        }
+        for (key, value) in &self.headers {
+            let lower = key.to_ascii_lowercase();
+            if !HOP_BY_HOP.contains(&lower.as_str()) && lower != "content-length" {
+                head.push_str(&format!("{key}: {value}\r\n"));
+            }
+        }
+        if !self.body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("Connection: keep-alive\r\n\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+struct Response {
+    status_line: String,
+    headers: Headers,
+    body: Vec<u8>,
+    // a response to HEAD, or a 101, 204 or 304: its Content-Length, if
+    // any, describes a body that isn't sent
+    bodiless: bool,
+    // whether the upstream connection can be reused
+    keep_alive: bool,
+}
+
+impl Response {
+    fn to_bytes(&self, keep_alive: bool) -> Vec<u8> {
+        let mut head = format!("{}\r\n", self.status_line);
+        for (key, value) in &self.headers {
+            let lower = key.to_ascii_lowercase();
+            // the backend's length is kept when there is no body to measure
+            if !HOP_BY_HOP.contains(&lower.as_str()) && (lower != "content-length" || self.bodiless)
+            {
+                head.push_str(&format!("{key}: {value}\r\n"));
+            }
+        }
+        if !self.bodiless {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        head.push_str(&format!("Connection: {connection}\r\n\r\n"));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+fn error_response(code: u16, reason: &str) -> Vec<u8> {
+    let contents = format!("{code} {reason}\n");
+    let length = contents.len();
+    format!("HTTP/1.1 {code} {reason}\r\nContent-Length: {length}\r\nConnection: close\r\n\r\n{contents}")
+        .into_bytes()
+}
+
+// longest start, header or chunk-size line accepted
+const MAX_LINE: usize = 8 * 1024;
+// most header lines accepted in a request or response
+const MAX_HEADERS: usize = 100;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn too_large(max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        format!("the body is larger than {max} bytes"),
+    )
+}
+
+fn head_too_large(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::QuotaExceeded, message.to_string())
+}
+
+// like `read_line`, but stops after `MAX_LINE` bytes, `None` means the line
+// was longer than that
+async fn read_short_line<R>(reader: &mut R) -> io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_line(&mut line)
+        .await?;
+    if line.len() == MAX_LINE && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+// reads the start line and headers, `None` means a clean EOF before the start line
+async fn read_head<R>(reader: &mut R) -> io::Result<Option<(String, Headers)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let start_line = read_short_line(reader)
+        .await?
+        .ok_or_else(|| head_too_large("start line too long"))?;
+    if start_line.is_empty() {
+        return Ok(None);
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_short_line(reader)
+            .await?
+            .ok_or_else(|| head_too_large("header line too long"))?;
+        if line.is_empty() {
+            return Err(invalid("connection closed inside the headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            // blank line is end of headers
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(head_too_large("too many headers"));
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(Some((start_line.trim_end().to_string(), headers)))
+}
+
+// whether the last transfer coding, over all Transfer-Encoding headers, is
+// chunked; that decides how the body ends whatever comes before it
+fn is_chunked(headers: &Headers) -> bool {
+    headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .rfind(|coding| !coding.is_empty())
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+// bodies over `max` bytes are refused before anything is read
+async fn read_body<R>(reader: &mut R, headers: &Headers, max: usize) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    if is_chunked(headers) {
+        return read_chunked(reader, max).await.map(Some);
+    }
+    // any other transfer coding overrides the length
+    if header(headers, "transfer-encoding").is_some() {
+        return Ok(None);
+    }
+    match header(headers, "content-length") {
+        Some(length) => {
+            let length: u64 = length.parse().map_err(|_| invalid("bad Content-Length"))?;
+            if length > max as u64 {
+                return Err(too_large(max));
+            }
+            // grows as the bytes arrive instead of trusting the length up front
+            let mut body = Vec::new();
+            reader.take(length).read_to_end(&mut body).await?;
+            if body.len() as u64 != length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(Some(body))
+        }
+        // the body (if any) is delimited by the end of the connection
+        None => Ok(None),
+    }
+}
+
+async fn read_chunked<R>(reader: &mut R, max: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let size_line = read_short_line(reader)
+            .await?
+            .ok_or_else(|| invalid("chunk size line too long"))?;
+        let size = size_line.trim().split(';').next().unwrap_or("");
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+        if size == 0 {
+            // skip trailers up to the final blank line
+            for _ in 0..=MAX_HEADERS {
+                let line = read_short_line(reader)
+                    .await?
+                    .ok_or_else(|| invalid("trailer line too long"))?;
+                if line.trim().is_empty() {
+                    return Ok(body);
+                }
+            }
+            return Err(invalid("too many trailers"));
+        }
+        let start = body.len();
+        if size > max - start {
+            return Err(too_large(max));
+        }
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+    }
+}
+
+async fn read_request<R>(reader: &mut R, max_body: usize) -> io::Result<Option<Request>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let (request_line, headers) = match read_head(reader).await? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(invalid("malformed request line")),
+    };
+    // requests without a length have no body, but a transfer coding
+    // that doesn't end in chunked leaves no way to tell where it ends
+    let body = match read_body(reader, &headers, max_body).await? {
+        Some(body) => body,
+        None if header(&headers, "transfer-encoding").is_some() => {
+            return Err(invalid("request body of unknown length"))
+        }
+        None => Vec::new(),
+    };
+
+    Ok(Some(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+        body,
+    }))
+}
+
+async fn read_response<R>(reader: &mut R, head_only: bool) -> io::Result<Response>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // interim responses (100 Continue, 103 Early Hints, ...) come before the
+    // real one and are dropped, relaying one would leave the final response
+    // unread on a connection that goes back to the pool
+    let (status_line, headers, code) = loop {
+        let (status_line, headers) = read_head(reader)
+            .await?
+            .ok_or_else(|| invalid("backend closed the connection"))?;
+        let code: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid("malformed status line"))?;
+        if !(100..200).contains(&code) || code == 101 {
+            break (status_line, headers, code);
+        }
+    };
+    // after 101 Switching Protocols the connection no longer speaks HTTP
+    let close = code == 101
+        || header(&headers, "connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+
+    // these responses never carry a body
+    if head_only || code < 200 || code == 204 || code == 304 {
+        return Ok(Response {
+            status_line,
+            headers,
+            body: Vec::new(),
+            bodiless: true,
+            keep_alive: !close,
+        });
+    }
+
+    // backends are trusted with any size
+    let (body, keep_alive) = match read_body(reader, &headers, usize::MAX).await? {
+        Some(body) => (body, !close),
+        None => {
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body).await?;
+            (body, false)
+        }
+    };
+    Ok(Response {
+        status_line,
+        headers,
+        body,
+        bodiless: false,
+        keep_alive,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a tiny keep-alive backend that answers with its name and the
+    // X-Forwarded-For header it received, `/sleep` takes a while to answer
+    async fn spawn_backend(name: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&connections);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    while let Ok(Some(request)) = read_request(&mut reader, usize::MAX).await {
+                        if request.target == "/sleep" {
+                            tokio::time::sleep(Duration::from_millis(500)).await;
+                        }
+                        let xff = header(&request.headers, "x-forwarded-for").unwrap_or("");
+                        let contents = format!("{name} {xff}");
+                        let response = match request.target.as_str() {
+                            "/hints" => format!(
+                                "HTTP/1.1 100 Continue\r\n\r\n\
+                                 HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+                                 HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{contents}",
+                                contents.len()
+                            ),
+                            "/cached" => "HTTP/1.1 304 Not Modified\r\nContent-Length: 42\r\n\r\n"
+                                .to_string(),
+                            // the length a GET would get
+                            _ if request.method == "HEAD" => format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                                contents.len()
+                            ),
+                            _ => format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{contents}",
+                                contents.len()
+                            ),
+                        };
+                        if writer.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, connections)
+    }
+
+    async fn spawn_proxy(config: ProxyConfig) -> (SocketAddr, Arc<Proxy>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = Proxy::new(config);
+        tokio::spawn(Arc::clone(&proxy).serve(listener));
+        (addr, proxy)
+    }
+
+    // returns the status code and body
+    async fn get(proxy: SocketAddr, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let response = read_response(&mut reader, false).await.unwrap();
+        let code = response.status_line.split_whitespace().nth(1).unwrap();
+        (
+            code.parse().unwrap(),
+            String::from_utf8(response.body).unwrap(),
+        )
+    }
+
+    fn closed_port() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn round_robin_alternates_backends() {
+        let (a, _) = spawn_backend("a").await;
+        let (b, _) = spawn_backend("b").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a, b])).await;
+
+        let mut names = Vec::new();
+        for _ in 0..4 {
+            let (code, body) = get(proxy, "/").await;
+            assert_eq!(code, 200);
+            names.push(body.split(' ').next().unwrap().to_string());
+        }
+        assert_eq!(names, ["a", "b", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn least_connections_avoids_busy_backend() {
+        let (a, _) = spawn_backend("a").await;
+        let (b, _) = spawn_backend("b").await;
+        let mut config = ProxyConfig::new(vec![a, b]);
+        config.balance = Balance::LeastConnections;
+        let (proxy, _) = spawn_proxy(config).await;
+
+        // the slow request keeps `a` busy
+        let slow = tokio::spawn(async move { get(proxy, "/sleep").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for _ in 0..3 {
+            let (_, body) = get(proxy, "/").await;
+            assert!(body.starts_with("b "));
+        }
+        let (_, body) = slow.await.unwrap();
+        assert!(body.starts_with("a "));
+    }
+
+    #[tokio::test]
+    async fn adds_x_forwarded_for() {
+        let (a, _) = spawn_backend("a").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut BufReader::new(stream), false)
+            .await
+            .unwrap();
+        assert_eq!(response.body, b"a 10.0.0.1, 127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn reuses_pooled_connections() {
+        let (a, connections) = spawn_backend("a").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        for _ in 0..3 {
+            assert_eq!(get(proxy, "/").await.0, 200);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn ejects_failing_backend() {
+        let dead = closed_port();
+        let (a, _) = spawn_backend("a").await;
+        let mut config = ProxyConfig::new(vec![dead, a]);
+        config.max_failures = 1;
+        let (proxy_addr, proxy) = spawn_proxy(config).await;
+
+        // the dead backend fails over to `a` and is then left out
+        for _ in 0..4 {
+            let (code, body) = get(proxy_addr, "/").await;
+            assert_eq!(code, 200);
+            assert!(body.starts_with("a "));
+        }
+        assert!(!proxy.backends[0].is_available());
+        assert!(proxy.backends[1].is_available());
+    }
+
+    #[tokio::test]
+    async fn no_available_backend() {
+        let mut config = ProxyConfig::new(vec![closed_port()]);
+        config.max_failures = 1;
+        let (proxy, _) = spawn_proxy(config).await;
+
+        assert_eq!(get(proxy, "/").await.0, 503);
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_backend() {
+        let (a, _) = spawn_backend("a").await;
+        let mut config = ProxyConfig::new(vec![a]);
+        config.response_timeout = Duration::from_millis(100);
+        let (proxy, _) = spawn_proxy(config).await;
+
+        assert_eq!(get(proxy, "/sleep").await.0, 504);
+    }
+
+    #[tokio::test]
+    async fn health_check_marks_backends() {
+        let (a, _) = spawn_backend("a").await;
+        let mut config = ProxyConfig::new(vec![a, closed_port()]);
+        config.health_check_path = Some("/".to_string());
+        let (proxy_addr, proxy) = spawn_proxy(config).await;
+
+        proxy.check_backends().await;
+        assert!(proxy.backends[0].healthy.load(Ordering::SeqCst));
+        assert!(!proxy.backends[1].healthy.load(Ordering::SeqCst));
+        for _ in 0..2 {
+            assert!(get(proxy_addr, "/").await.1.starts_with("a "));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_large_bodies() {
+        let (a, connections) = spawn_backend("a").await;
+        let mut config = ProxyConfig::new(vec![a]);
+        config.max_body = 16;
+        let (proxy, _) = spawn_proxy(config).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "POST / HTTP/1.1\r\nContent-Length: 100000000000\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut BufReader::new(stream), false)
+            .await
+            .unwrap();
+        assert_eq!(response.status_line, "HTTP/1.1 413 Payload Too Large");
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       10\r\n0123456789abcdef\r\n1\r\n!\r\n0\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut BufReader::new(stream), false)
+            .await
+            .unwrap();
+        assert_eq!(response.status_line, "HTTP/1.1 413 Payload Too Large");
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "POST / HTTP/1.1\r\nContent-Length: 16\r\nConnection: close\r\n\r\n\
+                       0123456789abcdef";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut BufReader::new(stream), false)
+            .await
+            .unwrap();
+        assert_eq!(response.body, b"a 127.0.0.1");
+    }
+
+    // answers one request per connection without saying it will close it,
+    // so the proxy pools a connection that is gone by the next request
+    async fn spawn_closing_backend() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut reader = BufReader::new(stream);
+                if let Ok(Some(_)) = read_request(&mut reader, usize::MAX).await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                    let _ = reader.get_mut().write_all(response.as_bytes()).await;
+                }
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn retries_only_safe_requests_on_stale_connections() {
+        let (a, requests) = spawn_closing_backend().await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        assert_eq!(get(proxy, "/").await, (200, "ok".to_string()));
+        // the pooled connection is closed, the GET goes out again
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(get(proxy, "/").await, (200, "ok".to_string()));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // a POST is not sent twice
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "POST / HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut BufReader::new(stream), false)
+            .await
+            .unwrap();
+        assert_eq!(response.status_line, "HTTP/1.1 502 Bad Gateway");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_length_of_bodiless_responses() {
+        let (a, _) = spawn_backend("a").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        for (method, path, length) in [("HEAD", "/", "11"), ("GET", "/cached", "42")] {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            let request = format!("{method} {path} HTTP/1.1\r\nConnection: close\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut BufReader::new(stream), true)
+                .await
+                .unwrap();
+            assert_eq!(header(&response.headers, "content-length"), Some(length));
+            assert!(response.body.is_empty());
+        }
+    }
+    #[tokio::test]
+    async fn skips_interim_responses() {
+        let (a, connections) = spawn_backend("a").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        // the pooled connection must not still hold the final response
+        for path in ["/hints", "/"] {
+            assert_eq!(get(proxy, path).await, (200, "a 127.0.0.1".to_string()));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET /hints HTTP/1.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("Link"));
+    }
+
+    // sends `request` on its own connection, returns the status line
+    async fn status_of(proxy: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        read_response(&mut BufReader::new(stream), false)
+            .await
+            .unwrap()
+            .status_line
+    }
+
+    #[tokio::test]
+    async fn limits_the_head_and_chunk_lines() {
+        let (a, _) = spawn_backend("a").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        let long = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "x".repeat(MAX_LINE));
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-Many: 1\r\n".repeat(MAX_HEADERS + 1)
+        );
+        for request in [long, many] {
+            assert_eq!(
+                status_of(proxy, request.as_bytes()).await,
+                "HTTP/1.1 431 Request Header Fields Too Large"
+            );
+        }
+
+        let request = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{}\r\n!\r\n0\r\n\r\n",
+            "x".repeat(MAX_LINE)
+        );
+        assert_eq!(
+            status_of(proxy, request.as_bytes()).await,
+            "HTTP/1.1 400 Bad Request"
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_bodies_by_the_last_transfer_coding() {
+        let (a, _) = spawn_backend("a").await;
+        let (proxy, _) = spawn_proxy(ProxyConfig::new(vec![a])).await;
+
+        // the length is ignored, so the chunks can't pass for another request
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "POST / HTTP/1.1\r\nContent-Length: 4\r\n\
+                       Transfer-Encoding: gzip, chunked\r\n\r\n\
+                       1\r\n!\r\n0\r\n\r\n\
+                       GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut reader = BufReader::new(stream);
+        for _ in 0..2 {
+            let response = read_response(&mut reader, false).await.unwrap();
+            assert_eq!(response.body, b"a 127.0.0.1");
+        }
+
+        let request = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+        assert_eq!(status_of(proxy, request).await, "HTTP/1.1 400 Bad Request");
+    }
+}
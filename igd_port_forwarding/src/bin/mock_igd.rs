@@ -0,0 +1,72 @@
+// A fake gateway to point `igd_port_forwarding` at when there is no router
+// around:
+//
+//     cargo run --bin mock_igd -- --ssdp-port 1901
+//     cargo run -- --ssdp 239.255.255.250:1901 --local 127.0.0.1 add 6902:7878
+//
+// It serves until stopped, and shows its mappings whenever they change.
+use igd_port_forwarding::mock::MockGateway;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use std::{env, process, thread};
+
+const USAGE: &str = "\
+Usage: mock_igd [OPTIONS]
+
+Options:
+  --ssdp-port PORT     answer SSDP searches on PORT [default: 1900]
+  --external-ip IP     the address to report as external [default: 203.0.113.1]";
+
+fn parse(args: impl IntoIterator<Item = String>) -> Result<(u16, Ipv4Addr), String> {
+    let (mut port, mut external_ip) = (1900, Ipv4Addr::new(203, 0, 113, 1));
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+        let invalid = || format!("invalid value '{}' for {}", value, arg);
+        match arg.as_str() {
+            "--ssdp-port" => port = value.parse().map_err(|_| invalid())?,
+            "--external-ip" => external_ip = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+    Ok((port, external_ip))
+}
+
+fn main() {
+    let (port, external_ip) = parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+    let gateway = MockGateway::start(port, external_ip).unwrap_or_else(|err| {
+        eprintln!("Failed to start the gateway: {}", err);
+        process::exit(1);
+    });
+    println!(
+        "Answering SSDP searches on {}, serving http://{}",
+        gateway.ssdp_addr(),
+        gateway.http_addr()
+    );
+
+    let mut shown = None;
+    loop {
+        let mappings = gateway.mappings();
+        if shown.as_ref() != Some(&mappings) {
+            println!("{} mapping(s):", mappings.len());
+            for mapping in &mappings {
+                println!(
+                    "  {} port {} -> {}:{} lease={}s {:?}",
+                    mapping.protocol,
+                    mapping.external_port,
+                    mapping.internal_client,
+                    mapping.internal_port,
+                    mapping.lease,
+                    mapping.description
+                );
+            }
+            shown = Some(mappings);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
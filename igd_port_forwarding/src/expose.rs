@@ -0,0 +1,243 @@
+// Exposing a server's listener on the gateway.
+//
+// A server that wants to be reachable from outside the local network maps
+// its listening port with `expose` and holds on to the `Exposed` it gets:
+// while it lives, a thread renews the lease, and dropping it removes the
+// mapping. A server that is killed doesn't get to drop it, so the lease is
+// kept short enough for such mappings to go away on their own soon; one that
+// waits for `wait_for_stop` gets to drop it on Ctrl-C or SIGTERM.
+use crate::local;
+use crate::manager::Manager;
+use crate::mapper::{Fallback, MapError, Mapping, PortMapper, Request};
+use igd::{PortMappingProtocol, SearchOptions};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    sync::{mpsc, Arc},
+    thread,
+};
+use tokio::signal;
+
+/// How long the mappings of `expose` last, in seconds.
+pub const LEASE: u32 = 600;
+
+/// Forward the port of the TCP listener at `listener` from the gateway,
+/// found over UPnP, NAT-PMP or PCP, until the `Exposed` is dropped.
+pub fn expose(listener: SocketAddr, description: &str) -> Result<Exposed, MapError> {
+    // without a default route only UPnP can find a gateway
+    let gateway = local::default_gateway().ok();
+    let mapper = Fallback::all(SearchOptions::default(), gateway);
+    Exposed::with_mapper(Box::new(mapper), listener, description, LEASE)
+}
+
+/// A port forwarded to a listener, for as long as this lives.
+pub struct Exposed {
+    mapping: Mapping,
+    manager: Arc<Manager>,
+    // dropped to stop the renewals
+    stop: Option<mpsc::Sender<()>>,
+    renewing: Option<thread::JoinHandle<()>>,
+}
+
+impl Exposed {
+    /// Like `expose`, with `mapper` and leases of `lease` seconds. The
+    /// listener's own port is asked for first, then any port. A listener
+    /// bound to one address needs it to be the one the gateway is reached
+    /// from.
+    pub fn with_mapper(
+        mapper: Box<dyn PortMapper>,
+        listener: SocketAddr,
+        description: &str,
+        lease: u32,
+    ) -> Result<Exposed, MapError> {
+        let manager = Arc::new(Manager::new(mapper));
+        let local_ip = match listener.ip() {
+            // listening everywhere, so on the way to the gateway too
+            ip if ip.is_unspecified() => manager.local_ip()?,
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip,
+                // gateways forward to IPv4 addresses only
+                None => return Err(MapError::Unsupported),
+            },
+        };
+        // any other address, loopback included, is one the gateway can't
+        // forward to
+        if !listener.ip().is_unspecified() {
+            let reached = manager.local_ip()?;
+            if local_ip != reached {
+                return Err(MapError::Unavailable(format!(
+                    "the gateway is reached from {}, not {}",
+                    reached, local_ip
+                )));
+            }
+        }
+        let request = |external_port| Request {
+            protocol: PortMappingProtocol::TCP,
+            external_port,
+            local_addr: SocketAddrV4::new(local_ip, listener.port()),
+            lease,
+            description: description.to_string(),
+        };
+        // `Fallback` wraps the error, so whichever mapper refused it
+        let mapping = match manager.add(request(listener.port())) {
+            Err(e) if e.is_refused() => manager.add(request(0))?,
+            mapped => mapped?,
+        };
+
+        let (stop, stopped) = mpsc::channel();
+        let renewing = Arc::clone(&manager);
+        let renewing = thread::spawn(move || keep_alive(&renewing, stopped));
+        Ok(Exposed {
+            mapping,
+            manager,
+            stop: Some(stop),
+            renewing: Some(renewing),
+        })
+    }
+
+    /// The mapping as the gateway granted it.
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    /// Where clients outside the network connect to; the IP is unspecified
+    /// if the gateway didn't tell.
+    pub fn external(&self) -> SocketAddrV4 {
+        self.mapping.external
+    }
+}
+
+impl Drop for Exposed {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(renewing) = self.renewing.take() {
+            renewing.join().unwrap();
+        }
+        for (mapping, removed) in self.manager.remove_all() {
+            if let Err(e) = removed {
+                eprintln!("Failed to remove {}: {}", mapping, e);
+            }
+        }
+    }
+}
+
+/// Wait for Ctrl-C, or for a request to terminate.
+pub async fn stopped() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.unwrap();
+}
+
+/// Block until [`stopped`], for servers without a runtime of their own.
+/// From then on those signals no longer end the process by themselves.
+pub fn wait_for_stop() -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(stopped());
+    Ok(())
+}
+
+// renews the leases as they come due, until the `Exposed` hangs up
+fn keep_alive(manager: &Manager, stopped: mpsc::Receiver<()>) {
+    // leases that don't run out need nothing until then
+    while let Some(wait) = manager.next_renewal() {
+        if stopped.recv_timeout(wait) != Err(mpsc::RecvTimeoutError::Timeout) {
+            return;
+        }
+        for (mapping, renewed) in manager.renew_due() {
+            if let Err(e) = renewed {
+                eprintln!("Failed to renew {}: {}", mapping, e);
+            }
+        }
+    }
+    let _ = stopped.recv();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockGateway, Upnp};
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        time::Duration,
+    };
+
+    fn mock() -> (MockGateway, Box<dyn PortMapper>) {
+        let mock = MockGateway::start(0, Ipv4Addr::new(203, 0, 113, 7)).unwrap();
+        let mapper = Box::new(Upnp::new(mock.search_options()));
+        (mock, mapper)
+    }
+
+    #[test]
+    fn keeps_the_port_mapped_until_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mock, mapper) = mock();
+        let exposed = Exposed::with_mapper(mapper, addr, "test", 1).unwrap();
+        assert_eq!(
+            exposed.external(),
+            SocketAddrV4::new(mock.external_ip(), addr.port())
+        );
+        assert_eq!(exposed.mapping().via, "UPnP");
+
+        // outlives its lease
+        thread::sleep(Duration::from_millis(1500));
+        let mappings = mock.mappings();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].internal_port, addr.port());
+        assert_eq!(mappings[0].internal_client, Ipv4Addr::LOCALHOST);
+
+        drop(exposed);
+        assert!(mock.mappings().is_empty());
+    }
+
+    #[test]
+    fn takes_any_port_when_its_own_is_taken() {
+        let (mock, mapper) = mock();
+        let others = Manager::new(mapper);
+        let taken = others
+            .add(Request {
+                protocol: PortMappingProtocol::TCP,
+                external_port: 8080,
+                local_addr: "127.0.0.2:8080".parse().unwrap(),
+                lease: 0,
+                description: "others".to_string(),
+            })
+            .unwrap();
+
+        // as `expose` does it, the refusal comes wrapped in `AllFailed`
+        let mapper = Box::new(Fallback::all(mock.search_options(), None));
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        let exposed = Exposed::with_mapper(mapper, addr, "test", LEASE).unwrap();
+        assert_ne!(exposed.external().port(), taken.external.port());
+        assert_eq!(mock.mappings().len(), 2);
+        drop(exposed);
+        assert_eq!(mock.mappings().len(), 1);
+    }
+
+    #[test]
+    fn needs_an_ipv4_listener() {
+        let (_mock, mapper) = mock();
+        let exposed = Exposed::with_mapper(mapper, "[::1]:8080".parse().unwrap(), "test", LEASE);
+        assert!(matches!(exposed, Err(MapError::Unsupported)));
+    }
+
+    #[test]
+    fn needs_a_listener_on_the_way_to_the_gateway() {
+        // the mock gateway is reached from 127.0.0.1
+        let (mock, mapper) = mock();
+        let exposed =
+            Exposed::with_mapper(mapper, "127.0.0.2:8080".parse().unwrap(), "test", LEASE);
+        assert!(matches!(exposed, Err(MapError::Unavailable(_))));
+        assert!(mock.mappings().is_empty());
+    }
+}
@@ -0,0 +1,21 @@
+//! Port forwarding on the local network's gateway, over UPnP IGD, NAT-PMP
+//! or PCP: map ports, keep their leases alive and take the mappings down
+//! again, or expose a server's listener for as long as it runs.
+pub mod expose;
+pub mod local;
+pub mod manager;
+pub mod mapper;
+pub mod mock;
+pub mod natpmp;
+pub mod options;
+pub mod pcp;
+pub mod upnp;
+
+pub use expose::{expose, stopped, wait_for_stop, Exposed};
+pub use manager::{keep_alive, Manager};
+pub use mapper::{Fallback, MapError, Mapping, PortMapper, Request, Retries};
+pub use mock::MockGateway;
+pub use natpmp::NatPmp;
+pub use options::{Command, Options};
+pub use pcp::Pcp;
+pub use upnp::Upnp;
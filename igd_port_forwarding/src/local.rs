@@ -0,0 +1,81 @@
+// The address the gateway should forward to.
+//
+// A host can have several addresses, the one that matters is the one it
+// reaches the gateway from. Connecting a UDP socket makes the system pick
+// it, without sending anything.
+//
+// NAT-PMP and PCP have no discovery: their server is the default gateway,
+// which on Linux is in the routing table under /proc.
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+};
+
+/// This host's address on the way to `gateway`.
+pub fn local_ip(gateway: SocketAddrV4) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(gateway)?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => unreachable!("the socket is bound to an IPv4 address"),
+    }
+}
+
+/// The default route's gateway.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    default_route(&routes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no default route"))
+}
+
+/// The default route's gateway.
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "can't find the default gateway here, give it with --gateway",
+    ))
+}
+
+// the gateway of the route to 0.0.0.0 in a /proc/net/route table, whose
+// addresses are hex numbers in the host's byte order
+fn default_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|route| {
+        let fields: Vec<_> = route.split_whitespace().collect();
+        match fields[..] {
+            [_, "00000000", gateway, ..] => {
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_default_route() {
+        let hex = |ip: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(ip));
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+             eth0\t{}\t00000000\t0001\t0\t0\t0\t{}\n\
+             eth0\t00000000\t{}\t0003\t0\t0\t0\t00000000\n",
+            hex([192, 0, 2, 0]),
+            hex([255, 255, 255, 0]),
+            hex([192, 0, 2, 1]),
+        );
+        assert_eq!(default_route(&routes), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        let no_default: Vec<_> = routes.lines().take(2).collect();
+        assert_eq!(default_route(&no_default.join("\n")), None);
+    }
+
+    #[test]
+    fn finds_the_address_towards_the_gateway() {
+        let gateway = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1900);
+        assert_eq!(local_ip(gateway).unwrap(), Ipv4Addr::LOCALHOST);
+    }
+}
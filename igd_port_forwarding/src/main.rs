@@ -1,52 +1,193 @@
-#![allow(
-    dead_code,
-    unused_imports,
-    unused_variables,
-    unused_mut,
-    unreachable_code,
-    clippy::vec_init_then_push,
-    clippy::unnecessary_sort_by,
-    clippy::match_like_matches_macro,
-    clippy::mutable_key_type,
-    clippy::single_component_path_imports
-)]
-use igd;
+use igd_port_forwarding::{
+    keep_alive, local, options, stopped, Command, Fallback, Manager, Options, Request,
+};
 use std::net::SocketAddrV4;
-use std::str::FromStr;
+use std::sync::Arc;
+use std::{env, process};
+use tokio::task;
+
+// the mappers' calls block, so they run where that's allowed
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    task::spawn_blocking(f).await.unwrap()
+}
+
+// UPnP first, then NAT-PMP and PCP if there's a gateway to ask
+fn mapper(options: &Options) -> Fallback {
+    let gateway = options.gateway.map_or_else(local::default_gateway, Ok);
+    if let Err(e) = &gateway {
+        println!("Only trying UPnP, no gateway for NAT-PMP and PCP: {}", e);
+    }
+    Fallback::all(options.search_options(), gateway.ok())
+}
+
+// maps the ports, keeps them mapped until the program is stopped and then
+// removes them again
+async fn forward(manager: Arc<Manager>, options: Options) -> Result<(), String> {
+    let adding = Arc::clone(&manager);
+    let mapped = blocking(move || {
+        let local_ip = match options.local {
+            Some(ip) => ip,
+            None => adding
+                .local_ip()
+                .map_err(|e| format!("Failed to find the local address: {}", e))?,
+        };
+        let request = |external_port, local_port| Request {
+            protocol: options.protocol,
+            external_port,
+            local_addr: SocketAddrV4::new(local_ip, local_port),
+            lease: options.lease,
+            description: options.description.clone(),
+        };
+        let requests: Vec<_> = match options.command {
+            Command::Add(ports) => ports
+                .into_iter()
+                .map(|(external_port, local_port)| {
+                    request(external_port, local_port.unwrap_or(external_port))
+                })
+                .collect(),
+            // port 0 is any port
+            Command::AddAny(ports) => ports.into_iter().map(|port| request(0, port)).collect(),
+            _ => unreachable!("not a command that adds mappings"),
+        };
+        requests.into_iter().try_for_each(|request| {
+            let failed = match request.external_port {
+                0 => format!("Failed to add a port for {}", request.local_addr),
+                port => format!(
+                    "Failed to add {} port {} -> {}",
+                    request.protocol, port, request.local_addr
+                ),
+            };
+            adding
+                .add(request)
+                .map(|_| ())
+                .map_err(|e| format!("{}: {}", failed, e))
+        })
+    })
+    .await;
+
+    if mapped.is_ok() {
+        let asking = Arc::clone(&manager);
+        match blocking(move || asking.external_ip()).await {
+            Ok(ip) => println!("External address: {}", ip),
+            Err(e) => println!("Failed to get the external address: {}", e),
+        }
+        for mapping in manager.mappings() {
+            println!("Forwarding {}", mapping);
+        }
+        println!("Press Ctrl-C to remove the mappings and exit");
+        let renewing = tokio::spawn(keep_alive(Arc::clone(&manager)));
+        stopped().await;
+        renewing.abort();
+    }
+
+    // whatever was mapped before something failed goes too
+    let removing = Arc::clone(&manager);
+    for (mapping, removed) in blocking(move || removing.remove_all()).await {
+        match removed {
+            Ok(()) => println!("Removed {}", mapping),
+            Err(e) => println!("Failed to remove {}: {}", mapping, e),
+        }
+    }
+    mapped
+}
+
+async fn run(manager: Arc<Manager>, options: Options) -> Result<(), String> {
+    let protocol = options.protocol;
+    match options.command.clone() {
+        Command::Add(_) | Command::AddAny(_) => forward(manager, options).await,
+        Command::Remove(ports) => {
+            blocking(move || {
+                ports.into_iter().try_for_each(|port| {
+                    manager
+                        .remove(protocol, port)
+                        .map(|mapping| println!("Removed {}", mapping))
+                        .map_err(|e| format!("Failed to remove {} port {}: {}", protocol, port, e))
+                })
+            })
+            .await
+        }
+        Command::List => {
+            let mappings = blocking(move || manager.list())
+                .await
+                .map_err(|e| format!("Failed to list the mappings: {}", e))?;
+            if mappings.is_empty() {
+                println!("No mappings");
+            }
+            for mapping in mappings {
+                println!("{} {:?}", mapping, mapping.description);
+            }
+            Ok(())
+        }
+        Command::ExternalIp => {
+            let ip = blocking(move || manager.external_ip())
+                .await
+                .map_err(|e| format!("Failed to get the external address: {}", e))?;
+            println!("{}", ip);
+            Ok(())
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() {}
+async fn main() {
+    let options = Options::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, options::USAGE);
+        process::exit(2);
+    });
 
-// using test function to remove the port
-#[tokio::test]
-async fn test() {
-    let gateway = match igd::search_gateway(Default::default()) {
+    let manager = Manager::new(Box::new(mapper(&options)));
+    if let Err(e) = run(Arc::new(manager), options).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+// the tests below talk to a fake gateway on the loopback interface
+#[cfg(test)]
+fn mock_gateway() -> (igd_port_forwarding::MockGateway, igd::Gateway) {
+    let mock = igd_port_forwarding::MockGateway::start(0, "203.0.113.7".parse().unwrap()).unwrap();
+    let gateway = match igd::search_gateway(mock.search_options()) {
         Ok(g) => g,
         Err(e) => panic!("Failed to find gateway: {}", e),
     };
+    (mock, gateway)
+}
+
+// using test function to remove the port
+#[tokio::test]
+async fn test() {
+    let (mock, gateway) = mock_gateway();
     println!("Gateway = {}", gateway);
+    let local_addr = "192.168.1.46:7878".parse::<SocketAddrV4>().unwrap();
+    gateway
+        .add_port(
+            igd::PortMappingProtocol::TCP,
+            44019,
+            local_addr,
+            3600,
+            "remove_port example",
+        )
+        .unwrap();
 
     // remove port forwarding
     match gateway.remove_port(igd::PortMappingProtocol::TCP, 44019) {
-        Err(ref err) => {
-            println!("There was an error! {}", err);
-        }
-        Ok(_) => {
-            println!("It worked. port forwarding removed");
-        }
+        Err(ref err) => panic!("There was an error! {}", err),
+        Ok(_) => println!("It worked. port forwarding removed"),
     }
+    assert!(mock.mappings().is_empty());
+    assert!(matches!(
+        gateway.remove_port(igd::PortMappingProtocol::TCP, 44019),
+        Err(igd::RemovePortError::NoSuchPortMapping)
+    ));
 }
 
 // using test function to add port
 #[tokio::test]
 async fn add_port() {
-    let gateway = match igd::search_gateway(Default::default()) {
-        Ok(g) => g,
-        Err(e) => panic!("Failed to find gateway: {}", e),
-    };
+    let (mock, gateway) = mock_gateway();
     println!("Gateway = {}", gateway);
 
-    let local_addr = SocketAddrV4::from_str("192.168.1.46:7878").unwrap();
+    let local_addr = "192.168.1.46:7878".parse::<SocketAddrV4>().unwrap();
 
     // forward port 6902 on router to port 7878 on local machine
     match gateway.add_port(
@@ -56,43 +197,57 @@ async fn add_port() {
         3600,
         "add_port example",
     ) {
-        Err(ref err) => {
-            println!("There was an error! {}", err);
-        }
-        Ok(_) => {
-            println!(
-                "It worked. port forwarded: router's port :{} -> 192.168.1.46:7878",
-                6902
-            );
-        }
+        Err(ref err) => panic!("There was an error! {}", err),
+        Ok(_) => println!(
+            "It worked. port forwarded: router's port :{} -> 192.168.1.46:7878",
+            6902
+        ),
     }
+    let mappings = mock.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].external_port, 6902);
+    assert_eq!(mappings[0].internal_client, *local_addr.ip());
+    assert_eq!(mappings[0].description, "add_port example");
+
+    // another host can't have the same port: ConflictInMappingEntry
+    let other = "192.168.1.47:7878".parse::<SocketAddrV4>().unwrap();
+    assert!(matches!(
+        gateway.add_port(
+            igd::PortMappingProtocol::TCP,
+            6902,
+            other,
+            3600,
+            "add_port example"
+        ),
+        Err(igd::AddPortError::PortInUse)
+    ));
 }
 
 #[tokio::test]
 async fn add_any_port() {
-    let gateway = match igd::search_gateway(Default::default()) {
-        Ok(g) => g,
-        Err(e) => panic!("Failed to find gateway: {}", e),
-    };
+    let (mock, gateway) = mock_gateway();
     println!("Gateway = {}", gateway);
 
-    let local_addr = SocketAddrV4::from_str("192.168.1.46:7878").unwrap();
+    let local_addr = "192.168.1.46:7878".parse::<SocketAddrV4>().unwrap();
 
-    // forward port 6902 on router to port 7878 on local machine
+    // forward any port on router to port 7878 on local machine
     match gateway.add_any_port(
         igd::PortMappingProtocol::TCP,
         local_addr,
         3600,
         "add_port example",
     ) {
-        Err(ref err) => {
-            println!("There was an error! {}", err);
-        }
+        Err(ref err) => panic!("There was an error! {}", err),
         Ok(port) => {
             println!(
                 "It worked. port forward: router's port {} -> {}",
                 port, local_addr
             );
+            assert_eq!(mock.mappings()[0].external_port, port);
         }
     }
+    assert_eq!(
+        gateway.get_external_ip().unwrap().to_string(),
+        "203.0.113.7"
+    );
 }
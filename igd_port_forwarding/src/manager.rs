@@ -0,0 +1,311 @@
+// The mappings this program made on the gateway.
+//
+// Leases run out, so every mapping made through the manager is renewed once
+// half of its lease is over, and all of them are removed when the program
+// is done with them. Mappings others made are listed, but only removed when
+// asked to by their port.
+use crate::mapper::{MapError, Mapping, PortMapper, Request};
+use igd::PortMappingProtocol;
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Renewals that failed are tried again after this long.
+pub const RETRY: Duration = Duration::from_secs(10);
+
+// how often `keep_alive` looks for work when no lease runs out
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+// one of ours: what we asked for, what we got and when to renew it
+struct Entry {
+    request: Request,
+    mapping: Mapping,
+    due: Option<Instant>,
+}
+
+// when a lease granted at `now` is to be renewed
+fn due(mapping: &Mapping, now: Instant) -> Option<Instant> {
+    (mapping.lease > 0).then(|| now + Duration::from_secs(u64::from(mapping.lease)) / 2)
+}
+
+fn same_port(mapping: &Mapping, protocol: PortMappingProtocol, external_port: u16) -> bool {
+    mapping.protocol == protocol && mapping.external.port() == external_port
+}
+
+pub struct Manager {
+    mapper: Box<dyn PortMapper>,
+    mappings: Mutex<Vec<Entry>>,
+}
+
+impl Manager {
+    pub fn new(mapper: Box<dyn PortMapper>) -> Manager {
+        Manager {
+            mapper,
+            mappings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// This host's address on the way to the gateway.
+    pub fn local_ip(&self) -> Result<Ipv4Addr, MapError> {
+        self.mapper.local_ip()
+    }
+
+    /// Forward a port of the gateway to `request.local_addr`, and keep it
+    /// forwarded.
+    pub fn add(&self, request: Request) -> Result<Mapping, MapError> {
+        let mapping = self.mapper.map(&request)?;
+        let entry = Entry {
+            request,
+            due: due(&mapping, Instant::now()),
+            mapping: mapping.clone(),
+        };
+        let mut mappings = self.mappings.lock().unwrap();
+        // mapping a port again replaces what it was mapped to
+        mappings
+            .retain(|ours| !same_port(&ours.mapping, mapping.protocol, mapping.external.port()));
+        mappings.push(entry);
+        Ok(mapping)
+    }
+
+    /// Remove the mapping of `external_port`: ours, or one the gateway
+    /// lists.
+    pub fn remove(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<Mapping, MapError> {
+        let ours = {
+            let mut mappings = self.mappings.lock().unwrap();
+            let index = mappings
+                .iter()
+                .position(|ours| same_port(&ours.mapping, protocol, external_port));
+            // even if removing fails, there is nothing to renew any more
+            index.map(|index| mappings.remove(index).mapping)
+        };
+        let mapping = match ours {
+            Some(mapping) => mapping,
+            None => self
+                .list()?
+                .into_iter()
+                .find(|mapping| same_port(mapping, protocol, external_port))
+                .ok_or_else(|| {
+                    MapError::Refused(format!("{} port {} is not mapped", protocol, external_port))
+                })?,
+        };
+        self.mapper.unmap(&mapping)?;
+        Ok(mapping)
+    }
+
+    /// Remove every mapping made through this manager.
+    pub fn remove_all(&self) -> Vec<(Mapping, Result<(), MapError>)> {
+        let ours = std::mem::take(&mut *self.mappings.lock().unwrap());
+        ours.into_iter()
+            .map(|entry| {
+                let removed = self.mapper.unmap(&entry.mapping);
+                (entry.mapping, removed)
+            })
+            .collect()
+    }
+
+    /// The mappings made through this manager that are still in place.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        let mappings = self.mappings.lock().unwrap();
+        mappings.iter().map(|ours| ours.mapping.clone()).collect()
+    }
+
+    /// Every mapping the gateway lets us see, ours or not.
+    pub fn list(&self) -> Result<Vec<Mapping>, MapError> {
+        self.mapper.list()
+    }
+
+    pub fn external_ip(&self) -> Result<Ipv4Addr, MapError> {
+        self.mapper.external_ip()
+    }
+
+    /// How long until the next lease is to be renewed, `None` when no lease
+    /// runs out.
+    pub fn next_renewal(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let mappings = self.mappings.lock().unwrap();
+        mappings
+            .iter()
+            .filter_map(|ours| ours.due)
+            .min()
+            .map(|due| due.saturating_duration_since(now))
+    }
+
+    /// Renew the leases that are due, giving back the renewed mappings. The
+    /// ones that fail are tried again after [`RETRY`].
+    pub fn renew_due(&self) -> Vec<(Mapping, Result<(), MapError>)> {
+        let now = Instant::now();
+        let due_now: Vec<(Request, Mapping)> = {
+            let mappings = self.mappings.lock().unwrap();
+            mappings
+                .iter()
+                .filter(|ours| ours.due.is_some_and(|due| due <= now))
+                .map(|ours| {
+                    // the port we got, even if we asked for any
+                    let request = Request {
+                        external_port: ours.mapping.external.port(),
+                        ..ours.request.clone()
+                    };
+                    (request, ours.mapping.clone())
+                })
+                .collect()
+        };
+        // no lock while talking to the gateway, it may take a while
+        let renewed: Vec<_> = due_now
+            .into_iter()
+            // through the mapper that made it, not whichever answers first
+            .map(|(request, old)| {
+                let renewed = self.mapper.renew(&request, &old);
+                (old, renewed)
+            })
+            .collect();
+
+        let now = Instant::now();
+        let mut mappings = self.mappings.lock().unwrap();
+        renewed
+            .into_iter()
+            .map(|(old, result)| {
+                // unless it was removed meanwhile
+                let found = mappings
+                    .iter_mut()
+                    .find(|ours| same_port(&ours.mapping, old.protocol, old.external.port()));
+                match (result, found) {
+                    (Ok(mapping), Some(ours)) => {
+                        ours.due = due(&mapping, now);
+                        ours.mapping = mapping.clone();
+                        (mapping, Ok(()))
+                    }
+                    (Ok(mapping), None) => (mapping, Ok(())),
+                    (Err(e), found) => {
+                        if let Some(ours) = found {
+                            ours.due = Some(now + RETRY);
+                        }
+                        (old, Err(e))
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Renew `manager`'s leases as they come due, until the task is aborted.
+pub async fn keep_alive(manager: Arc<Manager>) {
+    loop {
+        tokio::time::sleep(manager.next_renewal().unwrap_or(IDLE_CHECK)).await;
+        let renewing = Arc::clone(&manager);
+        // the mappers' calls block
+        let renewed = tokio::task::spawn_blocking(move || renewing.renew_due())
+            .await
+            .unwrap();
+        for (mapping, result) in renewed {
+            match result {
+                Ok(()) => println!("Renewed {}", mapping),
+                Err(e) => println!(
+                    "Failed to renew {}, trying again in {:?}: {}",
+                    mapping, RETRY, e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockGateway, Upnp};
+
+    fn request(external_port: u16, lease: u32) -> Request {
+        Request {
+            protocol: PortMappingProtocol::TCP,
+            external_port,
+            local_addr: "127.0.0.1:7878".parse().unwrap(),
+            lease,
+            description: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn renews_halfway_through_the_lease() {
+        let mut mapping = Mapping {
+            protocol: PortMappingProtocol::TCP,
+            external: "203.0.113.7:6902".parse().unwrap(),
+            local_addr: "192.168.1.46:7878".parse().unwrap(),
+            lease: 3600,
+            description: "test".to_string(),
+            via: "UPnP",
+        };
+        let now = Instant::now();
+        assert_eq!(due(&mapping, now), Some(now + Duration::from_secs(1800)));
+        assert_eq!(
+            mapping.to_string(),
+            "TCP 203.0.113.7:6902 -> 192.168.1.46:7878 via UPnP (3600s)"
+        );
+        mapping.lease = 0;
+        assert_eq!(due(&mapping, now), None);
+    }
+
+    #[test]
+    fn maps_renews_and_removes_ports() {
+        let mock = MockGateway::start(0, Ipv4Addr::new(203, 0, 113, 7)).unwrap();
+        let manager = Manager::new(Box::new(Upnp::new(mock.search_options())));
+        assert_eq!(manager.external_ip().unwrap(), mock.external_ip());
+        assert_eq!(manager.local_ip().unwrap(), Ipv4Addr::LOCALHOST);
+
+        let mapping = manager.add(request(6902, 1)).unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:6902".parse().unwrap());
+        let any = manager
+            .add(Request {
+                protocol: PortMappingProtocol::UDP,
+                ..request(0, 0)
+            })
+            .unwrap();
+        let listed = manager.list().unwrap();
+        let ports: Vec<_> = listed.iter().map(|entry| entry.external.port()).collect();
+        assert_eq!(ports, [6902, any.external.port()]);
+        assert_eq!(manager.mappings(), [mapping.clone(), any]);
+
+        // somebody else's port
+        let taken = Request {
+            local_addr: "127.0.0.2:7878".parse().unwrap(),
+            ..request(6902, 1)
+        };
+        assert!(matches!(manager.add(taken), Err(MapError::Refused(_))));
+
+        // renewed halfway, it outlives its first lease
+        assert!(manager.next_renewal().unwrap() <= Duration::from_millis(500));
+        assert!(manager.renew_due().is_empty());
+        std::thread::sleep(Duration::from_millis(600));
+        let renewed = manager.renew_due();
+        assert_eq!(renewed.len(), 1);
+        assert_eq!(renewed[0].0, mapping);
+        assert!(renewed[0].1.is_ok());
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(mock.mappings().len(), 2);
+
+        let removed = manager.remove_all();
+        assert_eq!(removed.len(), 2);
+        assert!(removed.iter().all(|(_, result)| result.is_ok()));
+        assert!(manager.list().unwrap().is_empty());
+        assert!(matches!(
+            manager.remove(PortMappingProtocol::TCP, 6902),
+            Err(MapError::Refused(_))
+        ));
+    }
+
+    #[test]
+    fn removes_mappings_of_others_by_port() {
+        let mock = MockGateway::start(0, Ipv4Addr::new(203, 0, 113, 7)).unwrap();
+        let others = Manager::new(Box::new(Upnp::new(mock.search_options())));
+        others.add(request(6902, 0)).unwrap();
+
+        let manager = Manager::new(Box::new(Upnp::new(mock.search_options())));
+        let removed = manager.remove(PortMappingProtocol::TCP, 6902).unwrap();
+        assert_eq!(removed.local_addr, "127.0.0.1:7878".parse().unwrap());
+        assert!(mock.mappings().is_empty());
+    }
+}
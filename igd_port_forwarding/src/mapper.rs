@@ -0,0 +1,460 @@
+// Port mapping, whatever the gateway speaks.
+//
+// Gateways forward ports over one of three protocols: UPnP IGD, NAT-PMP
+// (RFC 6886) or its successor PCP (RFC 6887). Each is a `PortMapper`, and
+// `Fallback` tries them in order until one works, starting with the one
+// that worked last time. Whichever does, the
+// result is a `Mapping` and failures are `MapError`s, so callers don't need
+// to care which protocol it was.
+use crate::{natpmp::NatPmp, pcp::Pcp, upnp::Upnp};
+use igd::{PortMappingProtocol, SearchOptions};
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// What to ask a gateway for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub protocol: PortMappingProtocol,
+    /// the port wanted on the gateway, 0 for any
+    pub external_port: u16,
+    pub local_addr: SocketAddrV4,
+    /// in seconds, 0 asks for a mapping that doesn't expire
+    pub lease: u32,
+    pub description: String,
+}
+
+/// A port forwarded from the gateway to this host, as the gateway granted
+/// it: the port and lease may differ from what was asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub protocol: PortMappingProtocol,
+    /// the gateway's address and port, the IP is unspecified if the
+    /// protocol didn't tell
+    pub external: SocketAddrV4,
+    pub local_addr: SocketAddrV4,
+    /// in seconds, 0 if the mapping doesn't expire
+    pub lease: u32,
+    pub description: String,
+    /// the name of the mapper that made it
+    pub via: &'static str,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} via {}",
+            self.protocol, self.external, self.local_addr, self.via
+        )?;
+        match self.lease {
+            0 => Ok(()),
+            lease => write!(f, " ({}s)", lease),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    /// no gateway answered in this protocol
+    Unavailable(String),
+    /// the gateway answered, but wouldn't do it
+    Refused(String),
+    /// the protocol can't do this
+    Unsupported,
+    /// what every mapper said, in the order they were tried
+    AllFailed(Vec<(&'static str, MapError)>),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Unavailable(why) => write!(f, "no gateway: {}", why),
+            MapError::Refused(why) => write!(f, "refused: {}", why),
+            MapError::Unsupported => write!(f, "not supported"),
+            MapError::AllFailed(errors) => {
+                for (i, (name, error)) in errors.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "; " };
+                    write!(f, "{}{}: {}", separator, name, error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl MapError {
+    /// Whether a gateway answered and said no, rather than nobody being
+    /// there: asking differently may help.
+    pub fn is_refused(&self) -> bool {
+        match self {
+            MapError::Refused(_) => true,
+            MapError::AllFailed(errors) => errors.iter().any(|(_, e)| e.is_refused()),
+            MapError::Unavailable(_) | MapError::Unsupported => false,
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl From<io::Error> for MapError {
+    fn from(e: io::Error) -> MapError {
+        MapError::Unavailable(e.to_string())
+    }
+}
+
+pub trait PortMapper: Send + Sync {
+    /// The protocol, for messages.
+    fn name(&self) -> &'static str;
+
+    /// This host's address on the way to the gateway.
+    fn local_ip(&self) -> Result<Ipv4Addr, MapError>;
+
+    fn external_ip(&self) -> Result<Ipv4Addr, MapError>;
+
+    /// Forward a port of the gateway to `request.local_addr`. Asking again
+    /// for a port we have renews it.
+    fn map(&self, request: &Request) -> Result<Mapping, MapError>;
+
+    fn unmap(&self, mapping: &Mapping) -> Result<(), MapError>;
+
+    /// Renew `mapping`, which `request` asked for; mapping it again unless
+    /// the mapper knows better.
+    fn renew(&self, request: &Request, mapping: &Mapping) -> Result<Mapping, MapError> {
+        let _ = mapping;
+        self.map(request)
+    }
+
+    /// Every mapping the gateway shows, ours or not.
+    fn list(&self) -> Result<Vec<Mapping>, MapError> {
+        Err(MapError::Unsupported)
+    }
+}
+
+/// Tries its mappers in order; the first that succeeds wins, and is tried
+/// first from then on.
+pub struct Fallback {
+    mappers: Vec<Box<dyn PortMapper>>,
+    // the index of the mapper that worked last
+    worked: Mutex<Option<usize>>,
+}
+
+impl Fallback {
+    pub fn new(mappers: Vec<Box<dyn PortMapper>>) -> Fallback {
+        Fallback {
+            mappers,
+            worked: Mutex::new(None),
+        }
+    }
+
+    /// UPnP, searched for with `search`, then NAT-PMP and PCP if there's a
+    /// `gateway` to ask them.
+    pub fn all(search: SearchOptions, gateway: Option<Ipv4Addr>) -> Fallback {
+        let mut mappers: Vec<Box<dyn PortMapper>> = vec![Box::new(Upnp::new(search))];
+        if let Some(gateway) = gateway {
+            mappers.push(Box::new(NatPmp::new(gateway)));
+            mappers.push(Box::new(Pcp::new(gateway)));
+        }
+        Fallback::new(mappers)
+    }
+
+    // `remember` whether it worked, for calls that tell whether a gateway
+    // speaks the protocol
+    fn first<T>(
+        &self,
+        remember: bool,
+        f: impl Fn(&dyn PortMapper) -> Result<T, MapError>,
+    ) -> Result<T, MapError> {
+        let worked = *self.worked.lock().unwrap();
+        let others = (0..self.mappers.len()).filter(|&i| Some(i) != worked);
+        let mut errors = Vec::new();
+        for i in worked.into_iter().chain(others) {
+            let mapper = &self.mappers[i];
+            match f(mapper.as_ref()) {
+                Ok(done) => {
+                    if remember {
+                        *self.worked.lock().unwrap() = Some(i);
+                    }
+                    return Ok(done);
+                }
+                Err(e) => errors.push((mapper.name(), e)),
+            }
+        }
+        Err(MapError::AllFailed(errors))
+    }
+
+    // whichever mapper made `mapping`
+    fn made(&self, mapping: &Mapping) -> Result<&dyn PortMapper, MapError> {
+        self.mappers
+            .iter()
+            .find(|mapper| mapper.name() == mapping.via)
+            .map(|mapper| mapper.as_ref())
+            .ok_or(MapError::Unsupported)
+    }
+}
+
+impl PortMapper for Fallback {
+    fn name(&self) -> &'static str {
+        "any"
+    }
+
+    fn local_ip(&self) -> Result<Ipv4Addr, MapError> {
+        // NAT-PMP and PCP know it without asking the gateway
+        self.first(false, |mapper| mapper.local_ip())
+    }
+
+    fn external_ip(&self) -> Result<Ipv4Addr, MapError> {
+        self.first(true, |mapper| mapper.external_ip())
+    }
+
+    fn map(&self, request: &Request) -> Result<Mapping, MapError> {
+        self.first(true, |mapper| mapper.map(request))
+    }
+
+    // by whichever mapper made it
+    fn unmap(&self, mapping: &Mapping) -> Result<(), MapError> {
+        self.made(mapping)?.unmap(mapping)
+    }
+
+    // the same, as another mapper would make a second mapping
+    fn renew(&self, request: &Request, mapping: &Mapping) -> Result<Mapping, MapError> {
+        self.made(mapping)?.renew(request, mapping)
+    }
+
+    fn list(&self) -> Result<Vec<Mapping>, MapError> {
+        self.first(true, |mapper| mapper.list())
+    }
+}
+
+/// How NAT-PMP and PCP clients retry: the first answer is awaited for
+/// `first_wait`, and every retry waits twice as long as the one before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retries {
+    pub first_wait: Duration,
+    pub tries: u32,
+}
+
+impl Default for Retries {
+    // RFC 6886 asks for 250ms and up to 9 tries, but that takes minutes
+    // when nobody answers, too long before moving on to the next mapper
+    fn default() -> Retries {
+        Retries {
+            first_wait: Duration::from_millis(250),
+            tries: 4,
+        }
+    }
+}
+
+/// Send `request` from `socket`, connected to the server, until an answer
+/// that `answers` accepts comes back.
+pub(crate) fn exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    retries: Retries,
+    answers: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, MapError> {
+    let mut buf = [0u8; 1100];
+    let mut wait = retries.first_wait;
+    for _ in 0..retries.tries {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(wait))?;
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) if answers(&buf[..len]) => return Ok(buf[..len].to_vec()),
+                // stray or late answers to an earlier request
+                Ok(_) => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                // nothing listens on the server's port
+                Err(e) => return Err(e.into()),
+            }
+        }
+        wait *= 2;
+    }
+    Err(MapError::Unavailable(format!(
+        "no answer after {} tries",
+        retries.tries
+    )))
+}
+
+/// A socket from `local_ip` to `server`.
+pub(crate) fn connect(local_ip: Ipv4Addr, server: SocketAddrV4) -> Result<UdpSocket, MapError> {
+    let socket = UdpSocket::bind((local_ip, 0))?;
+    socket.connect(server)?;
+    Ok(socket)
+}
+
+/// A NAT-PMP or PCP server on 127.0.0.1 for tests, answering with what
+/// `answer` makes of each request, if anything.
+#[cfg(test)]
+pub(crate) fn stand_in(answer: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static) -> SocketAddrV4 {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let std::net::SocketAddr::V4(addr) = socket.local_addr().unwrap() else {
+        unreachable!("the socket is bound to an IPv4 address");
+    };
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1100];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            if let Some(response) = answer(&buf[..len]) {
+                socket.send_to(&response, from).unwrap();
+            }
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    // maps only if it `works`, counting how often it was asked
+    struct Stub {
+        name: &'static str,
+        works: bool,
+        knows_local_ip: bool,
+        asked: Arc<AtomicUsize>,
+    }
+
+    impl PortMapper for Stub {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn local_ip(&self) -> Result<Ipv4Addr, MapError> {
+            match self.knows_local_ip {
+                true => Ok(Ipv4Addr::LOCALHOST),
+                false => Err(MapError::Unavailable("nobody there".to_string())),
+            }
+        }
+
+        fn external_ip(&self) -> Result<Ipv4Addr, MapError> {
+            Err(MapError::Unsupported)
+        }
+
+        fn map(&self, request: &Request) -> Result<Mapping, MapError> {
+            self.asked.fetch_add(1, Ordering::SeqCst);
+            if !self.works {
+                return Err(MapError::Unavailable("nobody there".to_string()));
+            }
+            Ok(Mapping {
+                protocol: request.protocol,
+                external: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, request.external_port),
+                local_addr: request.local_addr,
+                lease: request.lease,
+                description: request.description.clone(),
+                via: self.name,
+            })
+        }
+
+        fn unmap(&self, _: &Mapping) -> Result<(), MapError> {
+            Ok(())
+        }
+    }
+
+    fn stub(name: &'static str, works: bool) -> Box<dyn PortMapper> {
+        counted(name, works).0
+    }
+
+    fn counted(name: &'static str, works: bool) -> (Box<dyn PortMapper>, Arc<AtomicUsize>) {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let stub = Stub {
+            name,
+            works,
+            knows_local_ip: true,
+            asked: Arc::clone(&asked),
+        };
+        (Box::new(stub), asked)
+    }
+
+    fn request() -> Request {
+        Request {
+            protocol: PortMappingProtocol::TCP,
+            external_port: 6902,
+            local_addr: "127.0.0.1:7878".parse().unwrap(),
+            lease: 60,
+            description: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn falls_back_in_order() {
+        let request = request();
+        let fallback = Fallback::new(vec![stub("A", false), stub("B", true), stub("C", true)]);
+        let mapping = fallback.map(&request).unwrap();
+        assert_eq!(mapping.via, "B");
+        assert_eq!(
+            mapping.to_string(),
+            "TCP 0.0.0.0:6902 -> 127.0.0.1:7878 via B (60s)"
+        );
+        fallback.unmap(&mapping).unwrap();
+
+        let nobody = Fallback::new(vec![stub("A", false), stub("B", false)]);
+        let error = nobody.map(&request).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "A: no gateway: nobody there; B: no gateway: nobody there"
+        );
+        assert!(matches!(nobody.list(), Err(MapError::AllFailed(errors)) if errors.len() == 2));
+        assert!(!error.is_refused());
+        let refused = MapError::AllFailed(vec![
+            ("A", MapError::Unsupported),
+            ("B", MapError::Refused("port in use".to_string())),
+        ]);
+        assert!(refused.is_refused());
+    }
+
+    #[test]
+    fn starts_with_the_mapper_that_worked() {
+        let (a, a_asked) = counted("A", false);
+        let fallback = Fallback::new(vec![a, stub("B", true)]);
+        assert_eq!(fallback.map(&request()).unwrap().via, "B");
+        assert_eq!(fallback.map(&request()).unwrap().via, "B");
+        assert_eq!(a_asked.load(Ordering::SeqCst), 1);
+
+        // knowing the local address says nothing about the gateway
+        let a = Stub {
+            name: "A",
+            works: false,
+            knows_local_ip: false,
+            asked: Arc::default(),
+        };
+        let fallback = Fallback::new(vec![Box::new(a), stub("B", false)]);
+        fallback.local_ip().unwrap();
+        let error = fallback.map(&request()).unwrap_err().to_string();
+        assert!(error.starts_with("A: "), "{error}");
+    }
+
+    #[test]
+    fn renews_through_the_mapper_that_made_it() {
+        let (b, b_asked) = counted("B", true);
+        let fallback = Fallback::new(vec![stub("A", true), b]);
+        let mapping = Mapping {
+            via: "B",
+            ..fallback.map(&request()).unwrap()
+        };
+        assert_eq!(fallback.renew(&request(), &mapping).unwrap().via, "B");
+        assert_eq!(b_asked.load(Ordering::SeqCst), 1);
+
+        let gone = Mapping {
+            via: "C",
+            ..mapping
+        };
+        assert!(matches!(
+            fallback.renew(&request(), &gone),
+            Err(MapError::Unsupported)
+        ));
+    }
+}
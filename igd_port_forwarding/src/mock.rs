@@ -0,0 +1,546 @@
+// A fake UPnP Internet Gateway Device, for trying things out without a
+// router.
+//
+// It answers SSDP searches sent to the SSDP multicast group on the loopback
+// interface (or to its port directly) with the address of its HTTP server,
+// which serves the device and service descriptions igd reads and takes SOAP
+// requests for the WANIPConnection actions this crate uses:
+//
+//     AddPortMapping, DeletePortMapping, GetExternalIPAddress and
+//     GetGenericPortMappingEntry
+//
+// Mappings live in memory and expire when their lease runs out. Errors are
+// answered with the UPnP error codes a router would use, e.g. 718
+// ConflictInMappingEntry for a port that is mapped to another host already.
+use igd::{PortMappingProtocol, SearchOptions};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Where SSDP searches go, on port 1900 usually.
+pub const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const DESCRIPTION_PATH: &str = "/rootDesc.xml";
+const SCPD_PATH: &str = "/WANIPCn.xml";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+
+// the actions, with their arguments in the order the service description
+// lists them, `true` for input arguments
+const ACTIONS: &[(&str, &[(&str, bool)])] = &[
+    (
+        "AddPortMapping",
+        &[
+            ("NewRemoteHost", true),
+            ("NewExternalPort", true),
+            ("NewProtocol", true),
+            ("NewInternalPort", true),
+            ("NewInternalClient", true),
+            ("NewEnabled", true),
+            ("NewPortMappingDescription", true),
+            ("NewLeaseDuration", true),
+        ],
+    ),
+    (
+        "DeletePortMapping",
+        &[
+            ("NewRemoteHost", true),
+            ("NewExternalPort", true),
+            ("NewProtocol", true),
+        ],
+    ),
+    ("GetExternalIPAddress", &[("NewExternalIPAddress", false)]),
+    (
+        "GetGenericPortMappingEntry",
+        &[
+            ("NewPortMappingIndex", true),
+            ("NewRemoteHost", false),
+            ("NewExternalPort", false),
+            ("NewProtocol", false),
+            ("NewInternalPort", false),
+            ("NewInternalClient", false),
+            ("NewEnabled", false),
+            ("NewPortMappingDescription", false),
+            ("NewLeaseDuration", false),
+        ],
+    ),
+];
+
+/// A port mapping on the fake gateway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockMapping {
+    pub protocol: PortMappingProtocol,
+    pub external_port: u16,
+    pub internal_client: Ipv4Addr,
+    pub internal_port: u16,
+    pub description: String,
+    /// in seconds, as asked for, 0 for a mapping that doesn't expire
+    pub lease: u32,
+    expires: Option<Instant>,
+}
+
+impl MockMapping {
+    // seconds left, as GetGenericPortMappingEntry reports them
+    fn lease_left(&self, now: Instant) -> u64 {
+        match self.expires {
+            Some(expires) => expires.saturating_duration_since(now).as_secs(),
+            None => 0,
+        }
+    }
+}
+
+// what went wrong with a request, as UPnP tells it
+struct Fault(u16, &'static str);
+
+#[derive(Default)]
+struct State {
+    mappings: Vec<MockMapping>,
+}
+
+impl State {
+    // forgets mappings whose lease ran out
+    fn expire(&mut self, now: Instant) {
+        self.mappings
+            .retain(|mapping| mapping.expires.is_none_or(|expires| expires > now));
+    }
+}
+
+pub struct MockGateway {
+    ssdp: SocketAddr,
+    http: SocketAddrV4,
+    external_ip: Ipv4Addr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockGateway {
+    /// Answer SSDP searches on `ssdp_port` (0 picks a free one) and serve
+    /// the gateway on a free port of 127.0.0.1, until the program ends.
+    pub fn start(ssdp_port: u16, external_ip: Ipv4Addr) -> io::Result<MockGateway> {
+        let ssdp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, ssdp_port))?;
+        ssdp.join_multicast_v4(&SSDP_GROUP, &Ipv4Addr::LOCALHOST)?;
+        let http = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let SocketAddr::V4(http_addr) = http.local_addr()? else {
+            unreachable!("the listener is bound to an IPv4 address");
+        };
+        let gateway = MockGateway {
+            ssdp: (SSDP_GROUP, ssdp.local_addr()?.port()).into(),
+            http: http_addr,
+            external_ip,
+            state: Arc::default(),
+        };
+
+        let location = format!("http://{}{}", http_addr, DESCRIPTION_PATH);
+        thread::spawn(move || answer_searches(ssdp, &location));
+        let state = Arc::clone(&gateway.state);
+        thread::spawn(move || {
+            for stream in http.incoming().flatten() {
+                let state = Arc::clone(&state);
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &state, external_ip) {
+                        println!("Mock gateway request failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(gateway)
+    }
+
+    /// Where to send SSDP searches: the group, on the gateway's port.
+    pub fn ssdp_addr(&self) -> SocketAddr {
+        self.ssdp
+    }
+
+    /// Where the HTTP server listens.
+    pub fn http_addr(&self) -> SocketAddrV4 {
+        self.http
+    }
+
+    pub fn external_ip(&self) -> Ipv4Addr {
+        self.external_ip
+    }
+
+    /// Options for `igd::search_gateway` that find this gateway: the search
+    /// goes to the group from 127.0.0.1, so it stays on the loopback
+    /// interface.
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            broadcast_address: self.ssdp,
+            timeout: Some(Duration::from_secs(2)),
+        }
+    }
+
+    /// The mappings whose lease hasn't run out.
+    pub fn mappings(&self) -> Vec<MockMapping> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(Instant::now());
+        state.mappings.clone()
+    }
+}
+
+// answers every search for a gateway with where its description is
+fn answer_searches(socket: UdpSocket, location: &str) {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("Mock gateway stopped answering searches: {}", e);
+                return;
+            }
+        };
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let wanted = header(&request, "st");
+        let for_us = request.starts_with("M-SEARCH ")
+            && matches!(wanted, Some(st) if st == DEVICE_TYPE || st == "ssdp:all");
+        if !for_us {
+            continue;
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             CACHE-CONTROL: max-age=120\r\n\
+             EXT:\r\n\
+             LOCATION: {}\r\n\
+             SERVER: mock/1.0 UPnP/1.0 igd_port_forwarding/0.1\r\n\
+             ST: {}\r\n\
+             USN: uuid:00000000-0000-0000-0000-000000000001::{}\r\n\r\n",
+            location, DEVICE_TYPE, DEVICE_TYPE
+        );
+        let _ = socket.send_to(response.as_bytes(), from);
+    }
+}
+
+// the value of header `name` in an HTTP-like message
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+// serves one HTTP request and closes the connection
+fn serve(stream: TcpStream, state: &Mutex<State>, external_ip: Ipv4Addr) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut head = String::new();
+    loop {
+        let len = reader.read_line(&mut head)?;
+        if len == 0 || head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            break;
+        }
+    }
+    let length = header(&head, "content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let body = String::from_utf8_lossy(&body);
+
+    let mut request_line = head.split_whitespace();
+    let (status, xml) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(DESCRIPTION_PATH)) => ("200 OK", device_description()),
+        (Some("GET"), Some(SCPD_PATH)) => ("200 OK", service_description()),
+        (Some("POST"), Some(CONTROL_PATH)) => {
+            // "urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping"
+            let action = header(&head, "soapaction")
+                .map(|action| action.trim_matches('"'))
+                .and_then(|action| action.strip_prefix(SERVICE_TYPE))
+                .and_then(|action| action.strip_prefix('#'))
+                .unwrap_or("");
+            let mut state = state.lock().unwrap();
+            match control(&mut state, external_ip, action, &body) {
+                Ok(arguments) => ("200 OK", response(action, &arguments)),
+                Err(Fault(code, description)) => {
+                    ("500 Internal Server Error", fault(code, description))
+                }
+            }
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/xml; charset=\"utf-8\"\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        xml.len(),
+        xml
+    )
+}
+
+// runs an action, giving back its output arguments
+fn control(
+    state: &mut State,
+    external_ip: Ipv4Addr,
+    action: &str,
+    body: &str,
+) -> Result<Vec<(&'static str, String)>, Fault> {
+    let now = Instant::now();
+    state.expire(now);
+    match action {
+        "GetExternalIPAddress" => Ok(vec![("NewExternalIPAddress", external_ip.to_string())]),
+        "AddPortMapping" => {
+            let mapping = MockMapping {
+                protocol: protocol(body)?,
+                external_port: argument(body, "NewExternalPort")?,
+                internal_client: argument(body, "NewInternalClient")?,
+                internal_port: argument(body, "NewInternalPort")?,
+                description: argument(body, "NewPortMappingDescription")?,
+                lease: argument(body, "NewLeaseDuration")?,
+                expires: None,
+            };
+            if mapping.external_port == 0 {
+                return Err(Fault(716, "WildCardNotPermittedInExtPort"));
+            }
+            if mapping.internal_port == 0 {
+                return Err(Fault(402, "Invalid Args"));
+            }
+            let mapping = MockMapping {
+                expires: (mapping.lease > 0)
+                    .then(|| now + Duration::from_secs(u64::from(mapping.lease))),
+                ..mapping
+            };
+            let existing = state.mappings.iter_mut().find(|existing| {
+                (existing.protocol, existing.external_port)
+                    == (mapping.protocol, mapping.external_port)
+            });
+            match existing {
+                // the same host may map the port again, to renew it
+                Some(existing) if existing.internal_client == mapping.internal_client => {
+                    *existing = mapping
+                }
+                Some(_) => return Err(Fault(718, "ConflictInMappingEntry")),
+                None => state.mappings.push(mapping),
+            }
+            Ok(Vec::new())
+        }
+        "DeletePortMapping" => {
+            let protocol = protocol(body)?;
+            let external_port: u16 = argument(body, "NewExternalPort")?;
+            let before = state.mappings.len();
+            state.mappings.retain(|mapping| {
+                (mapping.protocol, mapping.external_port) != (protocol, external_port)
+            });
+            if state.mappings.len() == before {
+                return Err(Fault(714, "NoSuchEntryInArray"));
+            }
+            Ok(Vec::new())
+        }
+        "GetGenericPortMappingEntry" => {
+            let index: usize = argument(body, "NewPortMappingIndex")?;
+            let Some(mapping) = state.mappings.get(index) else {
+                return Err(Fault(713, "SpecifiedArrayIndexInvalid"));
+            };
+            Ok(vec![
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", mapping.external_port.to_string()),
+                ("NewProtocol", mapping.protocol.to_string()),
+                ("NewInternalPort", mapping.internal_port.to_string()),
+                ("NewInternalClient", mapping.internal_client.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", mapping.description.clone()),
+                ("NewLeaseDuration", mapping.lease_left(now).to_string()),
+            ])
+        }
+        _ => Err(Fault(401, "Invalid Action")),
+    }
+}
+
+fn protocol(body: &str) -> Result<PortMappingProtocol, Fault> {
+    match argument::<String>(body, "NewProtocol")?.as_str() {
+        "TCP" => Ok(PortMappingProtocol::TCP),
+        "UDP" => Ok(PortMappingProtocol::UDP),
+        _ => Err(Fault(402, "Invalid Args")),
+    }
+}
+
+// the text of the element `name` in a SOAP body; the arguments are plain
+// elements without a namespace prefix
+fn argument<T: std::str::FromStr>(body: &str, name: &str) -> Result<T, Fault> {
+    let invalid = || Fault(402, "Invalid Args");
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let text = if body.contains(&format!("<{}/>", name)) {
+        ""
+    } else {
+        let start = body.find(&open).ok_or_else(invalid)? + open.len();
+        let len = body[start..].find(&close).ok_or_else(invalid)?;
+        &body[start..start + len]
+    };
+    unescape(text.trim()).parse().map_err(|_| invalid())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body>{}</s:Body></s:Envelope>",
+        body
+    )
+}
+
+fn response(action: &str, arguments: &[(&str, String)]) -> String {
+    let mut body = format!("<u:{}Response xmlns:u=\"{}\">", action, SERVICE_TYPE);
+    for (name, value) in arguments {
+        write!(body, "<{}>{}</{}>", name, escape(value), name).unwrap();
+    }
+    write!(body, "</u:{}Response>", action).unwrap();
+    envelope(&body)
+}
+
+fn fault(code: u16, description: &str) -> String {
+    envelope(&format!(
+        "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+         <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+         <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+         </UPnPError></detail></s:Fault>",
+        code, description
+    ))
+}
+
+// the gateway, with the WANIPConnection service where routers have it
+fn device_description() -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+         <root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion>\
+         <device><deviceType>{}</deviceType><friendlyName>Mock gateway</friendlyName>\
+         <deviceList><device>\
+         <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>\
+         <deviceList><device>\
+         <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>\
+         <serviceList><service>\
+         <serviceType>{}</serviceType>\
+         <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>\
+         <controlURL>{}</controlURL>\
+         <eventSubURL>/evt/IPConn</eventSubURL>\
+         <SCPDURL>{}</SCPDURL>\
+         </service></serviceList>\
+         </device></deviceList>\
+         </device></deviceList>\
+         </device></root>",
+        DEVICE_TYPE, SERVICE_TYPE, CONTROL_PATH, SCPD_PATH
+    )
+}
+
+fn service_description() -> String {
+    let mut actions = String::new();
+    for (name, arguments) in ACTIONS {
+        write!(actions, "<action><name>{}</name><argumentList>", name).unwrap();
+        for (argument, input) in arguments.iter() {
+            write!(
+                actions,
+                "<argument><name>{}</name><direction>{}</direction>\
+                 <relatedStateVariable>{}</relatedStateVariable></argument>",
+                argument,
+                if *input { "in" } else { "out" },
+                argument.trim_start_matches("New")
+            )
+            .unwrap();
+        }
+        actions.push_str("</argumentList></action>");
+    }
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+         <scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion>\
+         <actionList>{}</actionList></scpd>",
+        actions
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_searches_for_gateways() {
+        let gateway = MockGateway::start(0, Ipv4Addr::new(203, 0, 113, 7)).unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+             MAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+            DEVICE_TYPE
+        );
+        socket
+            .send_to(search.as_bytes(), gateway.ssdp_addr())
+            .unwrap();
+        let mut buf = [0u8; 1500];
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        let answer = String::from_utf8_lossy(&buf[..len]);
+        let location = format!("http://{}{}", gateway.http_addr(), DESCRIPTION_PATH);
+        assert_eq!(header(&answer, "location"), Some(location.as_str()));
+    }
+
+    #[test]
+    fn answers_soap_requests() {
+        let mut state = State::default();
+        let ip = Ipv4Addr::new(203, 0, 113, 7);
+        let add = "<NewRemoteHost></NewRemoteHost><NewExternalPort>6902</NewExternalPort>\
+                   <NewProtocol>TCP</NewProtocol><NewInternalPort>7878</NewInternalPort>\
+                   <NewInternalClient>192.168.1.46</NewInternalClient><NewEnabled>1</NewEnabled>\
+                   <NewPortMappingDescription>a &amp; b</NewPortMappingDescription>\
+                   <NewLeaseDuration>0</NewLeaseDuration>";
+        assert!(control(&mut state, ip, "AddPortMapping", add).is_ok());
+        assert_eq!(state.mappings[0].description, "a & b");
+        let other_host = add.replace("192.168.1.46", "192.168.1.47");
+        let conflict = control(&mut state, ip, "AddPortMapping", &other_host);
+        assert!(matches!(
+            conflict,
+            Err(Fault(718, "ConflictInMappingEntry"))
+        ));
+
+        let entry = control(
+            &mut state,
+            ip,
+            "GetGenericPortMappingEntry",
+            "<NewPortMappingIndex>0</NewPortMappingIndex>",
+        );
+        let xml = response("GetGenericPortMappingEntry", &entry.ok().unwrap());
+        assert!(xml.contains("<NewPortMappingDescription>a &amp; b</NewPortMappingDescription>"));
+        let past_the_end = control(
+            &mut state,
+            ip,
+            "GetGenericPortMappingEntry",
+            "<NewPortMappingIndex>1</NewPortMappingIndex>",
+        );
+        assert!(matches!(past_the_end, Err(Fault(713, _))));
+
+        let delete = "<NewRemoteHost/><NewExternalPort>6902</NewExternalPort>\
+                      <NewProtocol>TCP</NewProtocol>";
+        assert!(control(&mut state, ip, "DeletePortMapping", delete).is_ok());
+        let again = control(&mut state, ip, "DeletePortMapping", delete);
+        assert!(matches!(again, Err(Fault(714, _))));
+        assert!(matches!(
+            control(&mut state, ip, "ForceTermination", ""),
+            Err(Fault(401, _))
+        ));
+    }
+}
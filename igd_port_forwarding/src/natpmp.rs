@@ -0,0 +1,242 @@
+// The NAT-PMP mapper (RFC 6886).
+//
+// Requests go over UDP to port 5351 of the gateway, which maps ports for
+// whoever sent the request, so requests are sent from the local address of
+// the mapping. Every request is a version byte (0), an opcode and its
+// fields, all big-endian; responses echo the opcode plus 128, with a result
+// code and the seconds since the gateway started.
+use crate::local;
+use crate::mapper::{self, MapError, Mapping, PortMapper, Request, Retries};
+use igd::PortMappingProtocol;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Where NAT-PMP and PCP servers listen.
+pub const SERVER_PORT: u16 = 5351;
+
+/// What mappings without an end get: NAT-PMP has none, and this is the
+/// lease RFC 6886 recommends.
+pub const DEFAULT_LEASE: u32 = 7200;
+
+const VERSION: u8 = 0;
+const EXTERNAL_ADDRESS: u8 = 0;
+const RESPONSE: u8 = 128;
+
+pub struct NatPmp {
+    pub server: SocketAddrV4,
+    pub retries: Retries,
+}
+
+impl NatPmp {
+    /// Talk to the gateway at `gateway`, on the NAT-PMP port.
+    pub fn new(gateway: Ipv4Addr) -> NatPmp {
+        NatPmp {
+            server: SocketAddrV4::new(gateway, SERVER_PORT),
+            retries: Retries::default(),
+        }
+    }
+
+    // sends `request` from `local_ip` and gives back the successful answer
+    fn request(&self, local_ip: Ipv4Addr, request: &[u8]) -> Result<Vec<u8>, MapError> {
+        let socket = mapper::connect(local_ip, self.server)?;
+        let opcode = request[1];
+        let response = mapper::exchange(&socket, request, self.retries, |response| {
+            response.len() >= 4 && (response[0] != VERSION || response[1] == RESPONSE + opcode)
+        })?;
+        if response[0] != VERSION {
+            return Err(MapError::Unavailable(format!(
+                "the gateway speaks version {}, not NAT-PMP",
+                response[0]
+            )));
+        }
+        let expected = if opcode == EXTERNAL_ADDRESS { 12 } else { 16 };
+        match u16::from_be_bytes([response[2], response[3]]) {
+            0 if response.len() >= expected => Ok(response),
+            0 => Err(MapError::Refused(
+                "a response that is too short".to_string(),
+            )),
+            1 => Err(MapError::Unavailable(
+                "the gateway doesn't speak this version of NAT-PMP".to_string(),
+            )),
+            code => Err(MapError::Refused(reason(code).to_string())),
+        }
+    }
+
+    // the mapping request for `local_port`, which deletes it when `lease` is
+    // 0
+    fn map_request(
+        protocol: PortMappingProtocol,
+        local_port: u16,
+        external_port: u16,
+        lease: u32,
+    ) -> Vec<u8> {
+        let opcode = match protocol {
+            PortMappingProtocol::UDP => 1,
+            PortMappingProtocol::TCP => 2,
+        };
+        let mut request = vec![VERSION, opcode, 0, 0];
+        request.extend_from_slice(&local_port.to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&lease.to_be_bytes());
+        request
+    }
+
+    // what to send from; the gateway has to see the local address
+    fn source(&self, local_addr: SocketAddrV4) -> Result<Ipv4Addr, MapError> {
+        match *local_addr.ip() {
+            ip if ip.is_unspecified() => self.local_ip(),
+            ip => Ok(ip),
+        }
+    }
+}
+
+impl PortMapper for NatPmp {
+    fn name(&self) -> &'static str {
+        "NAT-PMP"
+    }
+
+    fn local_ip(&self) -> Result<Ipv4Addr, MapError> {
+        Ok(local::local_ip(self.server)?)
+    }
+
+    fn external_ip(&self) -> Result<Ipv4Addr, MapError> {
+        let response = self.request(Ipv4Addr::UNSPECIFIED, &[VERSION, EXTERNAL_ADDRESS])?;
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    fn map(&self, request: &Request) -> Result<Mapping, MapError> {
+        let lease = match request.lease {
+            0 => DEFAULT_LEASE,
+            lease => lease,
+        };
+        let packet = NatPmp::map_request(
+            request.protocol,
+            request.local_addr.port(),
+            request.external_port,
+            lease,
+        );
+        let response = self.request(self.source(request.local_addr)?, &packet)?;
+        let external_port = u16::from_be_bytes([response[10], response[11]]);
+        let lease = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+        // not worth failing the mapping for
+        let external_ip = self.external_ip().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        Ok(Mapping {
+            protocol: request.protocol,
+            external: SocketAddrV4::new(external_ip, external_port),
+            local_addr: request.local_addr,
+            lease,
+            description: request.description.clone(),
+            via: self.name(),
+        })
+    }
+
+    fn unmap(&self, mapping: &Mapping) -> Result<(), MapError> {
+        let packet = NatPmp::map_request(mapping.protocol, mapping.local_addr.port(), 0, 0);
+        self.request(self.source(mapping.local_addr)?, &packet)?;
+        Ok(())
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::stand_in;
+    use std::time::Duration;
+
+    // grants what is asked for, up to two minutes, with 40000 for any port
+    fn gateway(request: &[u8]) -> Option<Vec<u8>> {
+        let mut response = vec![VERSION, RESPONSE + request[1], 0, 0];
+        response.extend_from_slice(&1000u32.to_be_bytes());
+        match request[1] {
+            EXTERNAL_ADDRESS => response.extend_from_slice(&[203, 0, 113, 9]),
+            1 | 2 => {
+                let external = match u16::from_be_bytes([request[6], request[7]]) {
+                    0 => 40000,
+                    port => port,
+                };
+                let lease = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+                response.extend_from_slice(&request[4..6]);
+                response.extend_from_slice(&external.to_be_bytes());
+                response.extend_from_slice(&lease.min(120).to_be_bytes());
+            }
+            _ => response[3] = 5,
+        }
+        Some(response)
+    }
+
+    fn client(server: SocketAddrV4) -> NatPmp {
+        NatPmp {
+            server,
+            retries: Retries {
+                first_wait: Duration::from_millis(50),
+                tries: 2,
+            },
+        }
+    }
+
+    fn request(external_port: u16) -> Request {
+        Request {
+            protocol: PortMappingProtocol::TCP,
+            external_port,
+            local_addr: "127.0.0.1:7878".parse().unwrap(),
+            lease: 3600,
+            description: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn maps_ports() {
+        let natpmp = client(stand_in(gateway));
+        assert_eq!(natpmp.external_ip().unwrap(), Ipv4Addr::new(203, 0, 113, 9));
+
+        let mapping = natpmp.map(&request(6902)).unwrap();
+        assert_eq!(mapping.external, "203.0.113.9:6902".parse().unwrap());
+        // the gateway's lease, not ours
+        assert_eq!(mapping.lease, 120);
+        assert_eq!(mapping.via, "NAT-PMP");
+        natpmp.unmap(&mapping).unwrap();
+
+        let any = natpmp.map(&request(0)).unwrap();
+        assert_eq!(any.external.port(), 40000);
+    }
+
+    #[test]
+    fn reports_failures() {
+        // a gateway that says no
+        let natpmp = client(stand_in(|request| {
+            Some(vec![VERSION, RESPONSE + request[1], 0, 2, 0, 0, 0, 0])
+        }));
+        let refused = natpmp.map(&request(6902));
+        assert!(matches!(refused, Err(MapError::Refused(reason)) if reason == "not authorized"));
+
+        // a PCP-only gateway
+        let pcp = client(stand_in(|request| {
+            Some(vec![2, RESPONSE + request[1], 0, 1])
+        }));
+        assert!(matches!(
+            pcp.map(&request(6902)),
+            Err(MapError::Unavailable(_))
+        ));
+
+        // nobody at all
+        let silent = client(stand_in(|_| None));
+        assert!(matches!(
+            silent.external_ip(),
+            Err(MapError::Unavailable(_))
+        ));
+    }
+}
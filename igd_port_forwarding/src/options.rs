@@ -0,0 +1,241 @@
+// Command line options of `igd_port_forwarding`.
+//
+// Options can go anywhere; the first other argument is the command and the
+// rest are its ports.
+use igd::{PortMappingProtocol, SearchOptions};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+pub const USAGE: &str = "\
+Usage: igd_port_forwarding [OPTIONS] COMMAND
+
+Commands:
+  add PORT[:LOCAL_PORT]...  forward PORT of the gateway to LOCAL_PORT of this
+                            host (the same port unless given), until stopped
+  add-any LOCAL_PORT...     forward a free port of the gateway to LOCAL_PORT,
+                            until stopped
+  remove PORT...            remove the mappings of PORT
+  list                      list the gateway's mappings
+  external-ip               print the gateway's external address
+
+ The gateway is asked over UPnP IGD, then NAT-PMP, then PCP, until one
+ of them answers.
+
+Options:
+  --udp                 map UDP ports instead of TCP
+  --local IP            the address to forward to, and to search for the
+                        gateway from [default: this host's address on the
+                        way to the gateway]
+  --lease SECS          how long mappings last, they are renewed when half
+                        of it is over; 0 asks for mappings that don't expire
+                        [default: 3600]
+  --description TEXT    description of the mappings [default: igd_port_forwarding]
+  --ssdp ADDR           where to search for the gateway [default: 239.255.255.250:1900]
+  --timeout SECS        how long to search for the gateway [default: 10]
+  --gateway IP          the gateway to ask over NAT-PMP and PCP [default:
+                        the default route's gateway]";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// external ports, with the local port if it is a different one
+    Add(Vec<(u16, Option<u16>)>),
+    /// local ports
+    AddAny(Vec<u16>),
+    /// external ports
+    Remove(Vec<u16>),
+    List,
+    ExternalIp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub protocol: PortMappingProtocol,
+    /// `None` finds the address on the way to the gateway, and searches
+    /// from every interface
+    pub local: Option<Ipv4Addr>,
+    /// in seconds
+    pub lease: u32,
+    pub description: String,
+    /// where SSDP searches are sent
+    pub ssdp: SocketAddr,
+    pub timeout: Duration,
+    /// `None` asks the default gateway over NAT-PMP and PCP
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+        let defaults = SearchOptions::default();
+        let mut protocol = PortMappingProtocol::TCP;
+        let mut local = None;
+        let mut lease = 3600;
+        let mut description = "igd_port_forwarding".to_string();
+        let mut ssdp = defaults.broadcast_address;
+        let mut timeout = defaults.timeout.unwrap_or(Duration::from_secs(10));
+        let mut gateway = None;
+        let mut words = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--udp" => protocol = PortMappingProtocol::UDP,
+                "--local" => local = Some(parse(&arg, &value(&arg, &mut args)?)?),
+                "--lease" => lease = parse(&arg, &value(&arg, &mut args)?)?,
+                "--description" => description = value(&arg, &mut args)?,
+                "--ssdp" => ssdp = parse(&arg, &value(&arg, &mut args)?)?,
+                "--timeout" => {
+                    let secs: u64 = parse(&arg, &value(&arg, &mut args)?)?;
+                    if secs == 0 {
+                        return Err("--timeout must be at least 1".to_string());
+                    }
+                    timeout = Duration::from_secs(secs);
+                }
+                "--gateway" => gateway = Some(parse(&arg, &value(&arg, &mut args)?)?),
+                _ if arg.starts_with("--") => return Err(format!("unknown option '{arg}'")),
+                _ => words.push(arg),
+            }
+        }
+
+        let Some((command, ports)) = words.split_first() else {
+            return Err("no command given".to_string());
+        };
+        let command = match command.as_str() {
+            "add" => Command::Add(each(ports, forward)?),
+            "add-any" => Command::AddAny(each(ports, port)?),
+            "remove" => Command::Remove(each(ports, port)?),
+            "list" | "external-ip" if !ports.is_empty() => {
+                return Err(format!("{command} takes no arguments"))
+            }
+            "list" => Command::List,
+            "external-ip" => Command::ExternalIp,
+            _ => return Err(format!("unknown command '{command}'")),
+        };
+        Ok(Options {
+            command,
+            protocol,
+            local,
+            lease,
+            description,
+            ssdp,
+            timeout,
+            gateway,
+        })
+    }
+
+    /// How to search for the gateway.
+    pub fn search_options(&self) -> SearchOptions {
+        let defaults = SearchOptions::default();
+        SearchOptions {
+            // multicast leaves by the interface of the address it is sent from
+            bind_addr: match self.local {
+                Some(ip) => (ip, 0).into(),
+                None => defaults.bind_addr,
+            },
+            broadcast_address: self.ssdp,
+            timeout: Some(self.timeout),
+        }
+    }
+}
+
+fn each<T>(ports: &[String], parse: fn(&str) -> Result<T, String>) -> Result<Vec<T>, String> {
+    if ports.is_empty() {
+        return Err("which ports?".to_string());
+    }
+    ports.iter().map(|arg| parse(arg)).collect()
+}
+
+// `PORT` or `PORT:LOCAL_PORT`
+fn forward(arg: &str) -> Result<(u16, Option<u16>), String> {
+    match arg.split_once(':') {
+        Some((external, local)) => Ok((port(external)?, Some(port(local)?))),
+        None => Ok((port(arg)?, None)),
+    }
+}
+
+fn port(arg: &str) -> Result<u16, String> {
+    match arg.parse() {
+        Ok(0) | Err(_) => Err(format!("invalid port '{arg}'")),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn value(option: &str, args: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{option} needs a value"))
+}
+
+fn parse<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{value}' for {option}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        let options = Options::parse(args("add 6902:7878 8080")).unwrap();
+        assert_eq!(
+            options.command,
+            Command::Add(vec![(6902, Some(7878)), (8080, None)])
+        );
+        assert_eq!(options.protocol, PortMappingProtocol::TCP);
+        assert_eq!(options.local, None);
+        assert_eq!(options.lease, 3600);
+        assert_eq!(options.ssdp, "239.255.255.250:1900".parse().unwrap());
+        assert_eq!(options.gateway, None);
+
+        let options =
+            Options::parse(args("--udp add-any 7878 --lease 0 --local 192.168.1.46")).unwrap();
+        assert_eq!(options.command, Command::AddAny(vec![7878]));
+        assert_eq!(options.protocol, PortMappingProtocol::UDP);
+        assert_eq!(options.local, Some(Ipv4Addr::new(192, 168, 1, 46)));
+        assert_eq!(options.lease, 0);
+
+        let options = Options::parse(args("--ssdp 127.0.0.1:1900 --timeout 2 list")).unwrap();
+        assert_eq!(options.command, Command::List);
+        let search = options.search_options();
+        assert_eq!(search.bind_addr, "0.0.0.0:0".parse().unwrap());
+        assert_eq!(search.broadcast_address, "127.0.0.1:1900".parse().unwrap());
+        assert_eq!(search.timeout, Some(Duration::from_secs(2)));
+        let options = Options::parse(args("--local 127.0.0.1 list")).unwrap();
+        assert_eq!(
+            options.search_options().bind_addr,
+            "127.0.0.1:0".parse().unwrap()
+        );
+
+        let options = Options::parse(args("remove 6902 44019")).unwrap();
+        assert_eq!(options.command, Command::Remove(vec![6902, 44019]));
+        let options = Options::parse(args("external-ip --gateway 192.168.1.1")).unwrap();
+        assert_eq!(options.command, Command::ExternalIp);
+        assert_eq!(options.gateway, Some(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        for line in [
+            "",
+            "forward 80",
+            "add",
+            "add 0",
+            "add 80:http",
+            "remove 70000",
+            "list 80",
+            "--lease",
+            "--lease forever add 80",
+            "--timeout 0 list",
+            "--tcp list",
+            "--gateway router list",
+        ] {
+            assert!(Options::parse(args(line)).is_err(), "{line}");
+        }
+    }
+}
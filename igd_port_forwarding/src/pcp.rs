@@ -0,0 +1,308 @@
+// The PCP mapper (RFC 6887), NAT-PMP's successor on the same port.
+//
+// A MAP request is a 24 byte header (version 2, the opcode, the lifetime
+// and the client's address as an IPv6 address) followed by the mapping: a
+// nonce, the protocol, the internal port and the external port and address
+// suggested. The response has the same layout with the opcode's high bit
+// set, a result code, and what the server assigned. Only whoever knows the
+// nonce can renew or delete a mapping, so a `Pcp` uses one nonce for all
+// the mappings it makes.
+use crate::local;
+use crate::mapper::{self, MapError, Mapping, PortMapper, Request, Retries};
+use crate::natpmp::{DEFAULT_LEASE, SERVER_PORT};
+use igd::PortMappingProtocol;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4},
+};
+
+const VERSION: u8 = 2;
+const MAP: u8 = 1;
+const RESPONSE: u8 = 0x80;
+const LEN: usize = 60;
+
+pub struct Pcp {
+    pub server: SocketAddrV4,
+    pub retries: Retries,
+    nonce: [u8; 12],
+}
+
+impl Pcp {
+    /// Talk to the gateway at `gateway`, on the PCP port.
+    pub fn new(gateway: Ipv4Addr) -> Pcp {
+        Pcp::at(SocketAddrV4::new(gateway, SERVER_PORT))
+    }
+
+    pub fn at(server: SocketAddrV4) -> Pcp {
+        // std's hasher keys are random, which is all a nonce needs to be
+        let mut nonce = [0; 12];
+        let random = || RandomState::new().build_hasher().finish().to_be_bytes();
+        nonce[..8].copy_from_slice(&random());
+        nonce[8..].copy_from_slice(&random()[..4]);
+        Pcp {
+            server,
+            retries: Retries::default(),
+            nonce,
+        }
+    }
+
+    // sends a MAP request, which deletes the mapping when `lease` is 0, and
+    // gives back the successful answer
+    fn request(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        external_port: u16,
+        lease: u32,
+    ) -> Result<Vec<u8>, MapError> {
+        // the server compares the client's address to where the request
+        // came from
+        let local_ip = match *local_addr.ip() {
+            ip if ip.is_unspecified() => self.local_ip()?,
+            ip => ip,
+        };
+        let mut request = vec![VERSION, MAP, 0, 0];
+        request.extend_from_slice(&lease.to_be_bytes());
+        request.extend_from_slice(&local_ip.to_ipv6_mapped().octets());
+        request.extend_from_slice(&self.nonce);
+        request.push(match protocol {
+            PortMappingProtocol::TCP => 6,
+            PortMappingProtocol::UDP => 17,
+        });
+        request.extend_from_slice(&[0; 3]);
+        request.extend_from_slice(&local_addr.port().to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        // any external address
+        request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+        let socket = mapper::connect(local_ip, self.server)?;
+        let response = mapper::exchange(&socket, &request, self.retries, |response| {
+            response.len() >= 4
+                && (response[0] != VERSION
+                    || response[1] == RESPONSE | MAP
+                        && (response.len() < LEN || response[24..36] == self.nonce))
+        })?;
+        if response[0] != VERSION {
+            return Err(MapError::Unavailable(format!(
+                "the gateway speaks version {}, not PCP",
+                response[0]
+            )));
+        }
+        match response[3] {
+            0 if response.len() >= LEN => Ok(response),
+            0 => Err(MapError::Refused(
+                "a response that is too short".to_string(),
+            )),
+            1 => Err(MapError::Unavailable(
+                "the gateway doesn't speak this version of PCP".to_string(),
+            )),
+            code => Err(MapError::Refused(reason(code).to_string())),
+        }
+    }
+}
+
+impl PortMapper for Pcp {
+    fn name(&self) -> &'static str {
+        "PCP"
+    }
+
+    fn local_ip(&self) -> Result<Ipv4Addr, MapError> {
+        Ok(local::local_ip(self.server)?)
+    }
+
+    // PCP tells the external address with each mapping, and only then
+    fn external_ip(&self) -> Result<Ipv4Addr, MapError> {
+        Err(MapError::Unsupported)
+    }
+
+    fn map(&self, request: &Request) -> Result<Mapping, MapError> {
+        // PCP has no mappings without an end
+        let lease = match request.lease {
+            0 => DEFAULT_LEASE,
+            lease => lease,
+        };
+        let response = self.request(
+            request.protocol,
+            request.local_addr,
+            request.external_port,
+            lease,
+        )?;
+        let lease = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+        let external_port = u16::from_be_bytes([response[42], response[43]]);
+        let external_ip: [u8; 16] = response[44..60].try_into().unwrap();
+        let external_ip = Ipv6Addr::from(external_ip)
+            .to_ipv4_mapped()
+            .unwrap_or(Ipv4Addr::UNSPECIFIED);
+        Ok(Mapping {
+            protocol: request.protocol,
+            external: SocketAddrV4::new(external_ip, external_port),
+            local_addr: request.local_addr,
+            lease,
+            description: request.description.clone(),
+            via: self.name(),
+        })
+    }
+
+    fn unmap(&self, mapping: &Mapping) -> Result<(), MapError> {
+        self.request(mapping.protocol, mapping.local_addr, 0, 0)?;
+        Ok(())
+    }
+}
+
+fn reason(code: u8) -> &'static str {
+    match code {
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        5 => "unsupported option",
+        6 => "malformed option",
+        7 => "network failure",
+        8 => "out of resources",
+        9 => "unsupported protocol",
+        10 => "user exceeded quota",
+        11 => "cannot provide the external port",
+        12 => "address mismatch",
+        13 => "excessive remote peers",
+        _ => "unknown result code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::stand_in;
+    use crate::{Fallback, NatPmp, Upnp};
+    use igd::SearchOptions;
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+    // grants what is asked for, up to two minutes, with 40000 for any
+    // port, and remembers whose nonce a mapping is
+    fn gateway() -> impl Fn(&[u8]) -> Option<Vec<u8>> {
+        let nonces = Mutex::new(HashMap::<(u8, u16), Vec<u8>>::new());
+        move |request| {
+            if request[0] != VERSION {
+                return Some(vec![0, request[1] | RESPONSE, 0, 1]);
+            }
+            let mut response = vec![VERSION, request[1] | RESPONSE, 0, 0];
+            let lease = u32::from_be_bytes(request[4..8].try_into().unwrap());
+            response.extend_from_slice(&lease.min(120).to_be_bytes());
+            response.extend_from_slice(&1000u32.to_be_bytes());
+            response.extend_from_slice(&[0; 12]);
+            response.extend_from_slice(&request[24..LEN]);
+            let (nonce, key) = (&request[24..36], (request[36], request[40]));
+            let key = (key.0, u16::from_be_bytes([key.1, request[41]]));
+            let mut nonces = nonces.lock().unwrap();
+            match nonces.get(&key) {
+                Some(owner) if owner != nonce => response[3] = 2,
+                _ if lease == 0 => {
+                    nonces.remove(&key);
+                }
+                _ => {
+                    nonces.insert(key, nonce.to_vec());
+                    let external = match u16::from_be_bytes([request[42], request[43]]) {
+                        0 => 40000,
+                        port => port,
+                    };
+                    response[42..44].copy_from_slice(&external.to_be_bytes());
+                    let ip = Ipv4Addr::new(203, 0, 113, 9).to_ipv6_mapped().octets();
+                    response[44..60].copy_from_slice(&ip);
+                }
+            }
+            Some(response)
+        }
+    }
+
+    fn client(server: SocketAddrV4) -> Pcp {
+        Pcp {
+            retries: Retries {
+                first_wait: Duration::from_millis(50),
+                tries: 2,
+            },
+            ..Pcp::at(server)
+        }
+    }
+
+    fn request(external_port: u16) -> Request {
+        Request {
+            protocol: PortMappingProtocol::UDP,
+            external_port,
+            local_addr: "127.0.0.1:7878".parse().unwrap(),
+            lease: 3600,
+            description: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn maps_ports() {
+        let server = stand_in(gateway());
+        let pcp = client(server);
+        let mapping = pcp.map(&request(6902)).unwrap();
+        assert_eq!(mapping.external, "203.0.113.9:6902".parse().unwrap());
+        assert_eq!(mapping.lease, 120);
+        assert_eq!(mapping.via, "PCP");
+
+        // somebody else's nonce can't touch it
+        let other = client(server);
+        let taken = other.unmap(&mapping);
+        assert!(matches!(taken, Err(MapError::Refused(reason)) if reason == "not authorized"));
+        pcp.unmap(&mapping).unwrap();
+        other.map(&request(6902)).unwrap();
+
+        let any = pcp.map(&Request {
+            local_addr: "127.0.0.1:7879".parse().unwrap(),
+            ..request(0)
+        });
+        assert_eq!(any.unwrap().external.port(), 40000);
+    }
+
+    #[test]
+    fn reports_failures() {
+        // a NAT-PMP-only gateway
+        let natpmp = client(stand_in(|request| {
+            Some(vec![0, request[1] | RESPONSE, 0, 1])
+        }));
+        assert!(matches!(
+            natpmp.map(&request(6902)),
+            Err(MapError::Unavailable(_))
+        ));
+
+        let silent = client(stand_in(|_| None));
+        assert!(matches!(
+            silent.map(&request(6902)),
+            Err(MapError::Unavailable(_))
+        ));
+    }
+
+    #[test]
+    fn falls_back_from_upnp_and_natpmp() {
+        // no UPnP gateway answers the search, and the PCP server tells
+        // NAT-PMP clients it doesn't speak their version
+        let search = SearchOptions {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            broadcast_address: stand_in(|_| None).into(),
+            timeout: Some(Duration::from_millis(200)),
+        };
+        let server = stand_in(gateway());
+        let natpmp = NatPmp {
+            server,
+            retries: client(server).retries,
+        };
+        let fallback = Fallback::new(vec![
+            Box::new(Upnp::new(search)),
+            Box::new(natpmp),
+            Box::new(client(server)),
+        ]);
+        let mapping = fallback.map(&request(6902)).unwrap();
+        assert_eq!(mapping.via, "PCP");
+        fallback.unmap(&mapping).unwrap();
+
+        // PCP worked, so it is asked first from now on
+        let error = fallback.external_ip().unwrap_err().to_string();
+        assert!(
+            error.starts_with("PCP: not supported; UPnP: no gateway: "),
+            "{error}"
+        );
+        assert!(error.contains("; NAT-PMP: "), "{error}");
+    }
+}
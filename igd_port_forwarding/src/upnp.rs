@@ -0,0 +1,213 @@
+// The UPnP IGD mapper, on top of the igd crate.
+//
+// The gateway is searched for the first time it is needed, and remembered.
+// A search that found nothing is remembered for a while too: every search
+// waits out its whole timeout, which on a network without UPnP would
+// otherwise be paid by every call.
+use crate::local;
+use crate::mapper::{MapError, Mapping, PortMapper, Request};
+use igd::{
+    AddAnyPortError, AddPortError, Gateway, GetExternalIpError, GetGenericPortMappingEntryError,
+    RemovePortError, RequestError, SearchOptions,
+};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a search that found no gateway is taken as the answer.
+pub const SEARCH_AGAIN: Duration = Duration::from_secs(60);
+
+enum Searched {
+    Not,
+    Found(Gateway),
+    // why, and when to search again
+    Failed(String, Instant),
+}
+
+pub struct Upnp {
+    search: SearchOptions,
+    gateway: Mutex<Searched>,
+}
+
+impl Upnp {
+    pub fn new(search: SearchOptions) -> Upnp {
+        Upnp {
+            search,
+            gateway: Mutex::new(Searched::Not),
+        }
+    }
+
+    /// Use `gateway` instead of searching.
+    pub fn with_gateway(gateway: Gateway) -> Upnp {
+        Upnp {
+            search: SearchOptions::default(),
+            gateway: Mutex::new(Searched::Found(gateway)),
+        }
+    }
+
+    /// The gateway, searched for unless found before, or not found less
+    /// than [`SEARCH_AGAIN`] ago.
+    pub fn gateway(&self) -> Result<Gateway, MapError> {
+        let mut gateway = self.gateway.lock().unwrap();
+        match &*gateway {
+            Searched::Found(gateway) => return Ok(gateway.clone()),
+            Searched::Failed(why, again) if Instant::now() < *again => {
+                return Err(MapError::Unavailable(why.clone()))
+            }
+            _ => {}
+        }
+        let search = SearchOptions {
+            bind_addr: self.search.bind_addr,
+            broadcast_address: self.search.broadcast_address,
+            timeout: self.search.timeout,
+        };
+        match igd::search_gateway(search) {
+            Ok(found) => {
+                *gateway = Searched::Found(found.clone());
+                Ok(found)
+            }
+            Err(e) => {
+                *gateway = Searched::Failed(e.to_string(), Instant::now() + SEARCH_AGAIN);
+                Err(MapError::Unavailable(e.to_string()))
+            }
+        }
+    }
+}
+
+impl PortMapper for Upnp {
+    fn name(&self) -> &'static str {
+        "UPnP"
+    }
+
+    fn local_ip(&self) -> Result<Ipv4Addr, MapError> {
+        Ok(local::local_ip(self.gateway()?.addr)?)
+    }
+
+    fn external_ip(&self) -> Result<Ipv4Addr, MapError> {
+        self.gateway()?.get_external_ip().map_err(|e| match e {
+            GetExternalIpError::RequestError(e) => request_error(e),
+            e => MapError::Refused(e.to_string()),
+        })
+    }
+
+    fn map(&self, request: &Request) -> Result<Mapping, MapError> {
+        let gateway = self.gateway()?;
+        let external_port = match request.external_port {
+            0 => gateway
+                .add_any_port(
+                    request.protocol,
+                    request.local_addr,
+                    request.lease,
+                    &request.description,
+                )
+                .map_err(|e| match e {
+                    AddAnyPortError::RequestError(e) => request_error(e),
+                    e => MapError::Refused(e.to_string()),
+                })?,
+            port => {
+                gateway
+                    .add_port(
+                        request.protocol,
+                        port,
+                        request.local_addr,
+                        request.lease,
+                        &request.description,
+                    )
+                    .map_err(|e| match e {
+                        AddPortError::RequestError(e) => request_error(e),
+                        e => MapError::Refused(e.to_string()),
+                    })?;
+                port
+            }
+        };
+        // not worth failing the mapping for
+        let external_ip = gateway.get_external_ip().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        Ok(Mapping {
+            protocol: request.protocol,
+            external: SocketAddrV4::new(external_ip, external_port),
+            local_addr: request.local_addr,
+            lease: request.lease,
+            description: request.description.clone(),
+            via: self.name(),
+        })
+    }
+
+    fn unmap(&self, mapping: &Mapping) -> Result<(), MapError> {
+        self.gateway()?
+            .remove_port(mapping.protocol, mapping.external.port())
+            .map_err(|e| match e {
+                RemovePortError::RequestError(e) => request_error(e),
+                e => MapError::Refused(e.to_string()),
+            })
+    }
+
+    fn list(&self) -> Result<Vec<Mapping>, MapError> {
+        let gateway = self.gateway()?;
+        let external_ip = gateway.get_external_ip().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let mut mappings = Vec::new();
+        let mut index = 0;
+        loop {
+            let entry = match gateway.get_generic_port_mapping_entry(index) {
+                Ok(entry) => entry,
+                // past the last one
+                Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => {
+                    return Ok(mappings)
+                }
+                Err(GetGenericPortMappingEntryError::RequestError(e)) => {
+                    return Err(request_error(e))
+                }
+                Err(e) => return Err(MapError::Refused(e.to_string())),
+            };
+            // a host name, rarely
+            let client = entry
+                .internal_client
+                .parse()
+                .unwrap_or(Ipv4Addr::UNSPECIFIED);
+            mappings.push(Mapping {
+                protocol: entry.protocol,
+                external: SocketAddrV4::new(external_ip, entry.external_port),
+                local_addr: SocketAddrV4::new(client, entry.internal_port),
+                lease: entry.lease_duration,
+                description: entry.port_mapping_description,
+                via: self.name(),
+            });
+            index += 1;
+        }
+    }
+}
+
+// the gateway couldn't be reached, or said no
+fn request_error(e: RequestError) -> MapError {
+    match e {
+        RequestError::AttoHttpError(_) | RequestError::IoError(_) => {
+            MapError::Unavailable(e.to_string())
+        }
+        e => MapError::Refused(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn remembers_a_search_that_found_nothing() {
+        // nobody answers on this socket
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upnp = Upnp::new(SearchOptions {
+            bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            broadcast_address: silent.local_addr().unwrap(),
+            timeout: Some(Duration::from_millis(200)),
+        });
+        let started = Instant::now();
+        assert!(matches!(upnp.gateway(), Err(MapError::Unavailable(_))));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let started = Instant::now();
+        assert!(matches!(upnp.external_ip(), Err(MapError::Unavailable(_))));
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
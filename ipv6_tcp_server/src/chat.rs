@@ -0,0 +1,442 @@
+// Chat mode: clients pick a nickname, join rooms and talk to everyone in
+// the same room.
+//
+// One hub thread owns all the chat state (who is called what, who is in
+// which room), so nothing needs a lock. Every connection has two threads:
+// the reader (a worker of the pool) sends whatever the client says to the
+// hub as an `Event`, and a writer sends the client what the hub put in its
+// outbox. The writers are threads of their own, outside the pool, so a chat
+// server runs a thread per connection on top of its workers; as the reader
+// holds its worker until the client leaves, the workers are how many can
+// chat at once, which is why chat mode defaults to more of them. An outbox
+// holds a bounded number of lines; a client that doesn't read fast enough to
+// keep it from overflowing is disconnected, so one slow client can't hold up
+// a room. Should the hub be gone, connections are closed.
+//
+// Lines starting with "/" are commands, anything else is said in the room:
+//
+//     /nick NAME    pick a nickname (and join #lobby the first time)
+//     /join ROOM    move to another room
+//     /leave        leave the room
+//     /who          list who is in the room
+//     /rooms        list the rooms
+//     /quit         say goodbye and disconnect
+//
+// The server's own lines start with "* ", errors with "ERR ".
+use crate::connections::{Connection, Counted, Registration, Stats};
+use crate::protocol::{self, Line, MAX_LINE};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Lines an outbox holds before its client counts as too slow.
+pub const QUEUE_LEN: usize = 256;
+
+/// Workers by default in chat mode, and so clients at once.
+pub const WORKERS: usize = 64;
+
+const LOBBY: &str = "lobby";
+const MAX_NAME: usize = 32;
+
+/// What connections tell the hub.
+enum Event {
+    Connect {
+        id: u64,
+        outbox: SyncSender<String>,
+        // to hang up on slow clients
+        stream: TcpStream,
+    },
+    Line {
+        id: u64,
+        line: String,
+    },
+    /// a line for this client only
+    Tell {
+        id: u64,
+        line: String,
+    },
+    Disconnect {
+        id: u64,
+    },
+}
+
+/// The way to the hub thread.
+#[derive(Clone)]
+pub struct Hub {
+    events: Sender<Event>,
+    queue_len: usize,
+}
+
+impl Hub {
+    /// Start a hub whose clients may fall `queue_len` lines behind.
+    pub fn spawn(queue_len: usize) -> Hub {
+        let (events, receiver) = mpsc::channel();
+        thread::spawn(move || run(receiver));
+        Hub { events, queue_len }
+    }
+
+    /// A hub whose thread is gone, as if it had panicked.
+    #[cfg(test)]
+    pub fn gone(queue_len: usize) -> Hub {
+        let (events, _) = mpsc::channel();
+        Hub { events, queue_len }
+    }
+
+    // fails with `NotConnected` when the hub thread is gone
+    fn send(&self, event: Event) -> io::Result<()> {
+        self.events
+            .send(event)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "the chat hub is gone"))
+    }
+}
+
+struct Client {
+    nick: Option<String>,
+    room: Option<String>,
+    outbox: SyncSender<String>,
+    stream: TcpStream,
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<u64, Client>,
+    rooms: BTreeMap<String, BTreeSet<u64>>,
+    // clients whose outbox overflowed, disconnected after the current event
+    slow: Vec<u64>,
+}
+
+fn run(events: Receiver<Event>) {
+    let mut state = State::default();
+    for event in events {
+        match event {
+            Event::Connect { id, outbox, stream } => {
+                let client = Client {
+                    nick: None,
+                    room: None,
+                    outbox,
+                    stream,
+                };
+                state.clients.insert(id, client);
+                state.tell(id, "* Welcome! Pick a nickname with /nick NAME".to_string());
+            }
+            Event::Line { id, line } => state.line(id, &line),
+            Event::Tell { id, line } => state.tell(id, line),
+            Event::Disconnect { id } => state.remove(id, "disconnected"),
+        }
+        // dropping a slow client tells its room, which may find more
+        while let Some(id) = state.slow.pop() {
+            if let Some(client) = state.clients.get(&id) {
+                println!("Disconnecting slow client {:?}", client.stream.peer_addr());
+                let _ = client.stream.shutdown(Shutdown::Both);
+                state.remove(id, "too slow");
+            }
+        }
+    }
+}
+
+impl State {
+    fn tell(&mut self, id: u64, line: String) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+        match client.outbox.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.slow.push(id),
+            // the writer is gone, the reader will say so soon
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    // to everyone in `room` but `except`
+    fn broadcast(&mut self, room: &str, except: Option<u64>, line: &str) {
+        let members: Vec<u64> = self
+            .rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        for member in members {
+            if Some(member) != except {
+                self.tell(member, line.to_string());
+            }
+        }
+    }
+
+    fn line(&mut self, id: u64, line: &str) {
+        // what a client said after quitting, or being dropped, goes nowhere
+        if !self.clients.contains_key(&id) {
+            return;
+        }
+        let Some(command) = line.strip_prefix('/') else {
+            return self.say(id, line);
+        };
+        let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+        let arg = arg.trim();
+        match (command.to_ascii_lowercase().as_str(), arg.is_empty()) {
+            ("nick", false) => self.nick(id, arg),
+            ("join", false) => self.join(id, arg.trim_start_matches('#')),
+            ("leave", true) => self.leave(id),
+            ("who", true) => self.who(id),
+            ("rooms", true) => self.list_rooms(id),
+            ("quit", true) => {
+                self.tell(id, "* Bye".to_string());
+                self.remove(id, "quit");
+            }
+            _ => self.tell(
+                id,
+                "ERR try /nick NAME, /join ROOM, /leave, /who, /rooms or /quit".to_string(),
+            ),
+        }
+    }
+
+    fn say(&mut self, id: u64, text: &str) {
+        let client = &self.clients[&id];
+        match (&client.nick, &client.room) {
+            (Some(nick), Some(room)) => {
+                let (line, room) = (format!("<{}> {}", nick, text), room.clone());
+                self.broadcast(&room, Some(id), &line);
+            }
+            (None, _) => self.tell(id, "ERR pick a nickname first: /nick NAME".to_string()),
+            (Some(_), None) => self.tell(id, "ERR join a room first: /join ROOM".to_string()),
+        }
+    }
+
+    fn nick(&mut self, id: u64, nick: &str) {
+        if !valid_name(nick) {
+            let error = format!(
+                "ERR a nickname is 1 to {} letters, digits, '-' or '_'",
+                MAX_NAME
+            );
+            return self.tell(id, error);
+        }
+        let taken = self
+            .clients
+            .iter()
+            .any(|(&other, client)| other != id && client.nick.as_deref() == Some(nick));
+        if taken {
+            return self.tell(id, format!("ERR {} is taken", nick));
+        }
+
+        let client = self.clients.get_mut(&id).unwrap();
+        let old = client.nick.replace(nick.to_string());
+        let room = client.room.clone();
+        self.tell(id, format!("* You are {}", nick));
+        match (old, room) {
+            (None, _) => self.join(id, LOBBY),
+            (Some(old), Some(room)) if old != nick => {
+                let notice = format!("* {} is now known as {}", old, nick);
+                self.broadcast(&room, Some(id), &notice);
+            }
+            _ => {}
+        }
+    }
+
+    fn join(&mut self, id: u64, room: &str) {
+        if !valid_name(room) {
+            let error = format!(
+                "ERR a room name is 1 to {} letters, digits, '-' or '_'",
+                MAX_NAME
+            );
+            return self.tell(id, error);
+        }
+        let client = &self.clients[&id];
+        let Some(nick) = client.nick.clone() else {
+            return self.tell(id, "ERR pick a nickname first: /nick NAME".to_string());
+        };
+        if client.room.as_deref() == Some(room) {
+            return self.tell(id, format!("* You are in #{} already", room));
+        }
+        self.leave(id);
+        self.rooms.entry(room.to_string()).or_default().insert(id);
+        self.clients.get_mut(&id).unwrap().room = Some(room.to_string());
+        // the one joining sees it too, as confirmation
+        self.broadcast(room, None, &format!("* {} joined #{}", nick, room));
+    }
+
+    // takes `id` out of its room, if it is in one
+    fn leave(&mut self, id: u64) {
+        let client = self.clients.get_mut(&id).unwrap();
+        let Some(room) = client.room.take() else {
+            return;
+        };
+        let notice = format!("* {} left #{}", client.nick.as_deref().unwrap_or(""), room);
+        self.tell(id, notice.clone());
+        self.part(&room, id, &notice);
+    }
+
+    // takes `id` out of the members of `room` and tells the others
+    fn part(&mut self, room: &str, id: u64, notice: &str) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+        self.broadcast(room, None, notice);
+    }
+
+    fn who(&mut self, id: u64) {
+        let Some(room) = self.clients[&id].room.clone() else {
+            return self.tell(id, "ERR join a room first: /join ROOM".to_string());
+        };
+        let nicks: Vec<&str> = self.rooms[&room]
+            .iter()
+            .filter_map(|member| self.clients[member].nick.as_deref())
+            .collect();
+        let line = format!("* In #{}: {}", room, nicks.join(", "));
+        self.tell(id, line);
+    }
+
+    fn list_rooms(&mut self, id: u64) {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .map(|(room, members)| format!("#{} ({})", room, members.len()))
+            .collect();
+        let line = match rooms.is_empty() {
+            true => "* No rooms yet, /join ROOM opens one".to_string(),
+            false => format!("* Rooms: {}", rooms.join(", ")),
+        };
+        self.tell(id, line);
+    }
+
+    // forgets the client; dropping its outbox lets the writer send what is
+    // queued and hang up
+    fn remove(&mut self, id: u64, why: &str) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        if let (Some(nick), Some(room)) = (client.nick, client.room) {
+            self.part(&room, id, &format!("* {} left #{} ({})", nick, room, why));
+        }
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Serve a chat client until it quits, disconnects, idles too long or is
+/// too slow.
+pub fn handle_client(
+    stream: TcpStream,
+    registration: Registration,
+    stats: &Stats,
+    idle: Duration,
+    hub: &Hub,
+) {
+    let connection = &registration.connection;
+    let peer = connection.peer;
+    let id = registration.id();
+    let clones = stream
+        .try_clone()
+        .and_then(|for_writer| Ok((for_writer, stream.try_clone()?)));
+    let (for_writer, for_hub) = match clones {
+        Ok(clones) => clones,
+        Err(e) => {
+            println!("Failed to set up chat with {}: {}", peer, e);
+            return;
+        }
+    };
+    let (outbox, lines) = mpsc::sync_channel(hub.queue_len);
+    let counted = Arc::clone(connection);
+    let writing = thread::spawn(move || write_lines(&for_writer, &counted, lines));
+    let connect = Event::Connect {
+        id,
+        outbox,
+        stream: for_hub,
+    };
+    // the outbox goes with the event, so the writer stops when it fails
+    let read = hub.send(connect).and_then(|()| {
+        stream
+            .set_read_timeout(Some(idle))
+            .and_then(|()| read_lines(&stream, &registration, stats, hub))
+    });
+    match read {
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            stats.timed_out.fetch_add(1, Ordering::SeqCst);
+            let line = format!("ERR idle for {:?}, closing", idle);
+            let _ = hub.send(Event::Tell { id, line });
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+            println!("Chat hub is gone, closing {}", peer);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        _ => {}
+    }
+    // a hub that is gone dropped the outbox already
+    let _ = hub.send(Event::Disconnect { id });
+    if writing.join().is_err() {
+        println!("Chat writer for {} panicked", peer);
+    }
+    println!(
+        "Chat connection closed: {} ({} bytes in, {} bytes out)",
+        peer,
+        connection.bytes_in.load(Ordering::SeqCst),
+        connection.bytes_out.load(Ordering::SeqCst)
+    );
+}
+
+// sends the client what the hub has for it
+fn write_lines(stream: &TcpStream, connection: &Connection, lines: Receiver<String>) {
+    let mut writer = Counted {
+        inner: stream,
+        count: &connection.bytes_out,
+    };
+    for mut line in lines {
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).is_err() {
+            break;
+        }
+    }
+    // the hub let go of the client, or the client stopped listening
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// passes lines on to the hub until the client goes away
+fn read_lines(
+    stream: &TcpStream,
+    registration: &Registration,
+    stats: &Stats,
+    hub: &Hub,
+) -> io::Result<()> {
+    let id = registration.id();
+    let mut reader = BufReader::new(Counted {
+        inner: stream,
+        count: &registration.connection.bytes_in,
+    });
+    let mut line = Vec::new();
+    loop {
+        let event = match protocol::read_line(&mut reader, &mut line, MAX_LINE)? {
+            Line::Eof => return Ok(()),
+            Line::TooLong => Event::Tell {
+                id,
+                line: format!("ERR line too long, at most {} bytes", MAX_LINE),
+            },
+            Line::Complete => {
+                stats.lines.fetch_add(1, Ordering::SeqCst);
+                let line = String::from_utf8_lossy(&line).into_owned();
+                Event::Line { id, line }
+            }
+        };
+        hub.send(event)?;
+    }
+}
@@ -0,0 +1,198 @@
+// Bookkeeping of the connections the server has.
+//
+// Every accepted connection is registered until it closes, with what it
+// read and wrote so far, so STATS can list them. The registry also decides
+// whether there is room for another connection: connections waiting for a
+// free worker count, so clients are turned away instead of queueing forever.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How much the server takes on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// threads serving connections
+    pub workers: usize,
+    /// connections beyond this are refused, waiting ones included
+    pub max_connections: usize,
+    /// connections that send nothing for this long are closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            workers: 8,
+            max_connections: 8,
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What one connection did so far.
+pub struct Connection {
+    pub peer: SocketAddr,
+    pub started: Instant,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+/// Counters shared by all connections, reported by STATS.
+#[derive(Default)]
+pub struct Stats {
+    pub connections: AtomicU64,
+    pub lines: AtomicU64,
+    pub refused: AtomicU64,
+    pub timed_out: AtomicU64,
+    next_id: AtomicU64,
+    // by id, so they are listed in the order they came in
+    open: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+impl Stats {
+    /// Register a new connection from `peer`, unless there are `max` already.
+    pub fn open(self: &Arc<Stats>, peer: SocketAddr, max: usize) -> Option<Registration> {
+        let mut open = self.open.lock().unwrap();
+        if open.len() >= max {
+            self.refused.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let connection = Arc::new(Connection {
+            peer,
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        });
+        open.insert(id, Arc::clone(&connection));
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Some(Registration {
+            stats: Arc::clone(self),
+            id,
+            connection,
+        })
+    }
+
+    /// Connections open right now.
+    pub fn active(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    /// The counters on one line, then a line per open connection, then
+    /// `END`.
+    pub fn report(&self) -> String {
+        let open = self.open.lock().unwrap();
+        let mut report = format!(
+            "connections={} active={} lines={} refused={} timed_out={}\n",
+            self.connections.load(Ordering::SeqCst),
+            open.len(),
+            self.lines.load(Ordering::SeqCst),
+            self.refused.load(Ordering::SeqCst),
+            self.timed_out.load(Ordering::SeqCst)
+        );
+        for connection in open.values() {
+            writeln!(
+                report,
+                "connection {} in={} out={} secs={:.3}",
+                connection.peer,
+                connection.bytes_in.load(Ordering::SeqCst),
+                connection.bytes_out.load(Ordering::SeqCst),
+                connection.started.elapsed().as_secs_f64()
+            )
+            .unwrap();
+        }
+        report.push_str("END");
+        report
+    }
+}
+
+/// Keeps a connection registered until it is dropped.
+pub struct Registration {
+    stats: Arc<Stats>,
+    id: u64,
+    pub connection: Arc<Connection>,
+}
+
+impl Registration {
+    /// Tells connections apart, in the order they came in.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.stats.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Adds the bytes that go through `inner` to `count`.
+pub struct Counted<'a, T> {
+    pub inner: T,
+    pub count: &'a AtomicU64,
+}
+
+impl<T: Read> Read for Counted<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count.fetch_add(len as u64, Ordering::SeqCst);
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Counted<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count.fetch_add(len as u64, Ordering::SeqCst);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_up_to_the_limit() {
+        let stats = Arc::new(Stats::default());
+        let peer = "[::1]:1000".parse().unwrap();
+        let first = stats.open(peer, 2).unwrap();
+        let second = stats.open(peer, 2).unwrap();
+        assert!(stats.open(peer, 2).is_none());
+        assert_eq!(stats.active(), 2);
+
+        drop(first);
+        let third = stats.open(peer, 2).unwrap();
+        assert_eq!(stats.connections.load(Ordering::SeqCst), 3);
+        assert_eq!(stats.refused.load(Ordering::SeqCst), 1);
+
+        Counted {
+            inner: Vec::new(),
+            count: &third.connection.bytes_out,
+        }
+        .write_all(b"hello")
+        .unwrap();
+        let report = stats.report();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "connections=3 active=2 lines=0 refused=1 timed_out=0"
+        );
+        assert!(lines[1].starts_with("connection [::1]:1000 in=0 out=0 secs="));
+        assert!(lines[2].starts_with("connection [::1]:1000 in=0 out=5 secs="));
+        assert_eq!(lines[3], "END");
+        drop(second);
+    }
+}
@@ -0,0 +1,145 @@
+// Where the server listens.
+//
+// The server can listen on several addresses at once, given as `--listen`
+// options. Link-local IPv6 addresses need the interface they belong to, as
+// a scope ID after a `%`: `[fe80::1%eth0]:3333` or `[fe80::1%2]:3333`.
+//
+// Whether a socket bound to an IPv6 address also accepts IPv4 clients (as
+// IPv4-mapped addresses like `::ffff:127.0.0.1`) depends on the system
+// unless `--v6-only` or `--dual-stack` says so. The option has to be set
+// before binding, which std can't do, so the listeners are made with
+// socket2.
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenOptions {
+    pub addrs: Vec<SocketAddr>,
+    /// `None` keeps the system's default
+    pub v6_only: Option<bool>,
+}
+
+impl ListenOptions {
+    /// A listener for every address, in the same order.
+    pub fn bind(&self) -> io::Result<Vec<TcpListener>> {
+        self.addrs
+            .iter()
+            .map(|&addr| bind(addr, self.v6_only))
+            .collect()
+    }
+}
+
+/// Parse `addr` like [`SocketAddr`] does, but also take interface names as
+/// scope IDs: `[fe80::1%eth0]:3333`.
+pub fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+    let invalid = || format!("invalid address '{}'", addr);
+    let (ip, port) = addr
+        .strip_prefix('[')
+        .and_then(|addr| addr.split_once("]:"))
+        .ok_or_else(invalid)?;
+    let (ip, interface) = ip.split_once('%').ok_or_else(invalid)?;
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid())?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let scope_id = interface_index(interface)
+        .ok_or_else(|| format!("no network interface named '{}'", interface))?;
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id).into())
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid NUL-terminated string for the whole call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Listen on `addr`. For IPv6 addresses `v6_only` decides whether IPv4
+/// clients are accepted too, `None` leaves that to the system.
+pub fn bind(addr: SocketAddr, v6_only: Option<bool>) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let (SocketAddr::V6(_), Some(v6_only)) = (addr, v6_only) {
+        socket.set_only_v6(v6_only)?;
+    }
+    // a restarted server shouldn't wait for old connections in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Whether IPv4 clients can reach `listener`: IPv4 listeners, IPv4-mapped
+/// addresses and `[::]` unless it is IPv6 only.
+pub fn accepts_ipv4(listener: &TcpListener) -> io::Result<bool> {
+    match listener.local_addr()? {
+        SocketAddr::V4(_) => Ok(true),
+        SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_some() => Ok(true),
+        SocketAddr::V6(addr) if addr.ip().is_unspecified() => {
+            Ok(!SockRef::from(listener).only_v6()?)
+        }
+        SocketAddr::V6(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn parses_scope_ids() {
+        let SocketAddr::V6(addr) = parse_addr("[fe80::1%3]:3333").unwrap() else {
+            panic!("not IPv6");
+        };
+        assert_eq!(addr.scope_id(), 3);
+        assert_eq!(addr.port(), 3333);
+
+        #[cfg(target_os = "linux")]
+        {
+            let SocketAddr::V6(addr) = parse_addr("[fe80::1%lo]:3333").unwrap() else {
+                panic!("not IPv6");
+            };
+            assert_eq!(addr.scope_id(), 1);
+        }
+        assert!(parse_addr("[fe80::1%no-such-interface]:3333").is_err());
+    }
+
+    #[test]
+    fn port_zero_picks_a_port() {
+        let listener = bind("[::1]:0".parse().unwrap(), None).unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), 0);
+    }
+
+    #[test]
+    fn v6_only_decides_about_ipv4_clients() {
+        let dual = bind("[::]:0".parse().unwrap(), Some(false)).unwrap();
+        let port = dual.local_addr().unwrap().port();
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (_, peer) = dual.accept().unwrap();
+        // the IPv4 client shows up with an IPv4-mapped address
+        let SocketAddr::V6(peer) = peer else {
+            panic!("not IPv6");
+        };
+        assert_eq!(peer.ip().to_ipv4_mapped(), Some([127, 0, 0, 1].into()));
+        assert!(accepts_ipv4(&dual).unwrap());
+
+        let v6_only = bind("[::]:0".parse().unwrap(), Some(true)).unwrap();
+        let port = v6_only.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        TcpStream::connect(("::1", port)).unwrap();
+        assert!(!accepts_ipv4(&v6_only).unwrap());
+        assert!(!accepts_ipv4(&bind("[::1]:0".parse().unwrap(), None).unwrap()).unwrap());
+        assert!(accepts_ipv4(&bind("127.0.0.1:0".parse().unwrap(), None).unwrap()).unwrap());
+    }
+}
@@ -10,50 +10,167 @@
     clippy::mutable_key_type,
     clippy::single_component_path_imports
 )]
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
-
-fn handle_client(mut stream: TcpStream) {
-    let mut data: [u8; 50] = [0; 50]; // using 50 byte buffer
-    while match stream.read(&mut data) {
-        Ok(size) => {
-            if size == 0 {
-                // sleep for 1 second
-                std::thread::sleep(std::time::Duration::from_millis(100));
-
-                true
-            } else {
-                println!("data: {:?}", &data[0..size]);
-                stream.write_all(&data[0..size]).unwrap();
-                true
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use std::{env, process};
+
+use connections::{Connection, Counted, Limits, Registration, Stats};
+use multithreaded_web_server::ThreadPool;
+use options::Options;
+use protocol::{Command, Line, MAX_LINE};
+
+mod chat;
+mod connections;
+mod listen;
+mod options;
+mod protocol;
+
+fn handle_client(stream: TcpStream, registration: Registration, stats: &Stats, idle: Duration) {
+    let connection = &registration.connection;
+    let peer = connection.peer;
+    let served = stream
+        .set_read_timeout(Some(idle))
+        .and_then(|()| serve_lines(&stream, connection, stats));
+    match served {
+        Ok(()) => {}
+        // a read timeout, which one depends on the platform
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            println!("Closing idle connection {}", peer);
+            stats.timed_out.fetch_add(1, Ordering::SeqCst);
+            let mut writer = Counted {
+                inner: &stream,
+                count: &connection.bytes_out,
+            };
+            let _ = writeln!(writer, "ERR idle for {:?}, closing", idle);
+        }
+        Err(e) => {
+            println!("error :{:?}", e);
+            println!("An error occurred, terminating connection with {}", peer);
+        }
+    }
+    // the client may be gone already, then there is nothing left to shut down
+    let _ = stream.shutdown(Shutdown::Both);
+    println!(
+        "Connection closed: {} ({} bytes in, {} bytes out, {:.3}s)",
+        peer,
+        connection.bytes_in.load(Ordering::SeqCst),
+        connection.bytes_out.load(Ordering::SeqCst),
+        connection.started.elapsed().as_secs_f64()
+    );
+}
+
+// answers lines until the client quits or closes the connection
+fn serve_lines(stream: &TcpStream, connection: &Connection, stats: &Stats) -> io::Result<()> {
+    let mut reader = BufReader::new(Counted {
+        inner: stream,
+        count: &connection.bytes_in,
+    });
+    let mut writer = Counted {
+        inner: stream,
+        count: &connection.bytes_out,
+    };
+    let mut line = Vec::new();
+    // every answer goes out in one write, so it arrives in one piece
+    let mut answer = Vec::new();
+    loop {
+        answer.clear();
+        match protocol::read_line(&mut reader, &mut line, MAX_LINE)? {
+            Line::Eof => return Ok(()),
+            Line::TooLong => {
+                writeln!(answer, "ERR line too long, at most {} bytes", MAX_LINE)?;
+                writer.write_all(&answer)?;
+                continue;
             }
+            Line::Complete => {}
+        }
+        stats.lines.fetch_add(1, Ordering::SeqCst);
+        println!("data: {:?}", String::from_utf8_lossy(&line));
+        let command = Command::parse(&line);
+        match command {
             // echo everything!
+            Command::Echo(text) | Command::Other(text) => {
+                answer.extend_from_slice(text);
+                answer.push(b'\n');
+            }
+            Command::Time => writeln!(answer, "{}", protocol::format_time(SystemTime::now()))?,
+            Command::Stats => writeln!(answer, "{}", stats.report())?,
+            Command::Quit => writeln!(answer, "BYE")?,
         }
-        Err(x) => {
-            println!("error :{:?}", x);
-            println!(
-                "An error occurred, terminating connection with {}",
-                stream.peer_addr().unwrap()
-            );
-            stream.shutdown(std::net::Shutdown::Both).unwrap();
-            false
+        writer.write_all(&answer)?;
+        if command == Command::Quit {
+            return Ok(());
         }
-    } {}
+    }
 }
 
-fn main() {
-    let listener = TcpListener::bind("[::1]:3333").unwrap();
-    // accept connections and process them, spawning a new thread for each one
-    println!("Server listening on port 3333");
+// what connections get
+#[derive(Clone)]
+enum Service {
+    Echo,
+    Chat(chat::Hub),
+}
+
+// accepts connections on every listener, each on its own thread, and serves
+// them on a pool of `limits.workers` threads
+fn serve(
+    listeners: Vec<TcpListener>,
+    stats: Arc<Stats>,
+    limits: Limits,
+    service: Service,
+) -> Vec<JoinHandle<()>> {
+    let pool = Arc::new(ThreadPool::new(limits.workers));
+    listeners
+        .into_iter()
+        .map(|listener| {
+            let stats = Arc::clone(&stats);
+            let pool = Arc::clone(&pool);
+            let service = service.clone();
+            thread::spawn(move || accept(listener, stats, pool, limits, service))
+        })
+        .collect()
+}
 
+fn accept(
+    listener: TcpListener,
+    stats: Arc<Stats>,
+    pool: Arc<ThreadPool>,
+    limits: Limits,
+    service: Service,
+) {
+    // accept connections and hand them to the pool, as long as there is room
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                println!("New connection: {}", stream.peer_addr().unwrap());
-                thread::spawn(move || {
+                // the client may have given up already
+                let Ok(peer) = stream.peer_addr() else {
+                    continue;
+                };
+                let Some(registration) = stats.open(peer, limits.max_connections) else {
+                    println!("Refusing connection from {}: server is full", peer);
+                    refuse(stream, limits.max_connections);
+                    continue;
+                };
+                println!("New connection: {}", peer);
+                let stats = Arc::clone(&stats);
+                let service = service.clone();
+                pool.execute(move || {
                     // connection succeeded
-                    handle_client(stream)
+                    let idle = limits.idle_timeout;
+                    match service {
+                        Service::Echo => handle_client(stream, registration, &stats, idle),
+                        Service::Chat(hub) => {
+                            chat::handle_client(stream, registration, &stats, idle, &hub)
+                        }
+                    }
                 });
             }
             Err(e) => {
@@ -66,15 +183,414 @@ fn main() {
     // close the socket server
     drop(listener);
 }
+
+// tells a client there is no room for it and hangs up
+fn refuse(mut stream: TcpStream, max_connections: usize) {
+    // a client that doesn't read mustn't hold up accepting others
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    let _ = writeln!(
+        stream,
+        "ERR too many connections (at most {}), please try again later",
+        max_connections
+    );
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// maps the ports of the listeners a gateway can forward to; the server
+// runs on without the others
+#[cfg(feature = "expose")]
+fn expose(listeners: &[TcpListener]) -> Vec<igd_port_forwarding::Exposed> {
+    let mut exposed = Vec::new();
+    for listener in listeners {
+        let addr = listener.local_addr().unwrap();
+        // gateways forward to IPv4 addresses only
+        if !listen::accepts_ipv4(listener).unwrap_or(false) {
+            println!("Not exposing {}, it doesn't accept IPv4 clients", addr);
+            continue;
+        }
+        match igd_port_forwarding::expose(addr, "ipv6_tcp_server") {
+            Ok(mapped) => {
+                println!("Exposed {} as {}", addr, mapped.mapping());
+                exposed.push(mapped);
+            }
+            Err(e) => eprintln!("Failed to expose {}: {}", addr, e),
+        }
+    }
+    exposed
+}
+
+fn main() {
+    let options = Options::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, options::USAGE);
+        process::exit(2);
+    });
+    if options.expose && !cfg!(feature = "expose") {
+        eprintln!("--expose needs a build with the `expose` feature");
+        process::exit(2);
+    }
+    let listeners = options.listen.bind().unwrap_or_else(|err| {
+        eprintln!("Failed to listen: {}", err);
+        process::exit(1);
+    });
+    for listener in &listeners {
+        // with port 0 this is where the port the system picked shows up
+        println!("Server listening on {}", listener.local_addr().unwrap());
+    }
+    // the mappings go when these do
+    #[cfg(feature = "expose")]
+    let exposed = if options.expose {
+        expose(&listeners)
+    } else {
+        Vec::new()
+    };
+
+    let service = match options.chat {
+        Some(queue_len) => Service::Chat(chat::Hub::spawn(queue_len)),
+        None => Service::Echo,
+    };
+    let stats = Arc::new(Stats::default());
+    let accepting = serve(listeners, stats, options.limits, service);
+    // the accept loops never end, so a signal ends the server to take down
+    // the mappings on the way out
+    #[cfg(feature = "expose")]
+    if !exposed.is_empty() {
+        match igd_port_forwarding::wait_for_stop() {
+            Ok(()) => {
+                println!("Stopping, removing the port mappings");
+                drop(exposed);
+                return;
+            }
+            Err(e) => eprintln!(
+                "Can't wait for signals, mappings stay until they expire: {}",
+                e
+            ),
+        }
+    }
+    for accepting in accepting {
+        accepting.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // serves connections on an ephemeral port of [::1]
+    fn spawn_server(limits: Limits) -> (std::net::SocketAddr, Arc<Stats>) {
+        let listener = listen::bind("[::1]:0".parse().unwrap(), None).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(Stats::default());
+        serve(vec![listener], Arc::clone(&stats), limits, Service::Echo);
+        (addr, stats)
+    }
+
+    // a chat server whose clients may fall `queue_len` lines behind
+    fn spawn_chat(queue_len: usize) -> std::net::SocketAddr {
+        let listener = listen::bind("[::1]:0".parse().unwrap(), None).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hub = chat::Hub::spawn(queue_len);
+        let stats = Arc::new(Stats::default());
+        serve(vec![listener], stats, Limits::default(), Service::Chat(hub));
+        addr
+    }
+
+    #[test]
+    fn closes_chats_when_the_hub_is_gone() {
+        let listener = listen::bind("[::1]:0".parse().unwrap(), None).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = Limits {
+            workers: 1,
+            max_connections: 2,
+            ..Limits::default()
+        };
+        let service = Service::Chat(chat::Hub::gone(4));
+        serve(vec![listener], Arc::new(Stats::default()), limits, service);
+        // the one worker survives the first to hang up on the second
+        for _ in 0..2 {
+            let (_stream, mut reader) = connect(addr);
+            assert_eq!(read(&mut reader), "");
+        }
+    }
+
+    fn read(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    // connects and picks a nickname
+    fn chat_as(addr: std::net::SocketAddr, nick: &str) -> (TcpStream, BufReader<TcpStream>) {
+        let (mut stream, mut reader) = connect(addr);
+        assert_eq!(
+            read(&mut reader),
+            "* Welcome! Pick a nickname with /nick NAME"
+        );
+        assert_eq!(
+            ask(&mut stream, &mut reader, &format!("/nick {}", nick)),
+            format!("* You are {}", nick)
+        );
+        assert_eq!(read(&mut reader), format!("* {} joined #lobby", nick));
+        (stream, reader)
+    }
+
+    fn connect(addr: std::net::SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        (stream, reader)
+    }
+
+    fn ask(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, line: &str) -> String {
+        writeln!(stream, "{}", line).unwrap();
+        let mut answer = String::new();
+        reader.read_line(&mut answer).unwrap();
+        answer.trim_end().to_string()
+    }
+
+    // the lines of a STATS answer, up to END
+    fn stats_lines(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>) -> Vec<String> {
+        let mut lines = vec![ask(stream, reader, "STATS")];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim_end() == "END" {
+                return lines;
+            }
+            lines.push(line.trim_end().to_string());
+        }
+    }
+
+    // waits for the server to notice closed connections
+    fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn commands() {
+        let (addr, _) = spawn_server(Limits::default());
+        let (mut stream, mut reader) = connect(addr);
+        assert_eq!(ask(&mut stream, &mut reader, "hello"), "hello");
+        assert_eq!(ask(&mut stream, &mut reader, "ECHO one two"), "one two");
+        let time = ask(&mut stream, &mut reader, "time");
+        assert!(time.ends_with('Z') && time.contains('T'), "{}", time);
+        let stats = stats_lines(&mut stream, &mut reader);
+        assert_eq!(
+            stats[0],
+            "connections=1 active=1 lines=4 refused=0 timed_out=0"
+        );
+        // hello, ECHO one two, time, STATS in and the answers to the first three out
+        let counters = format!(
+            "connection {} in=30 out=35 secs=",
+            stream.local_addr().unwrap()
+        );
+        assert!(stats[1].starts_with(&counters), "{}", stats[1]);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(ask(&mut stream, &mut reader, "QUIT"), "BYE");
+
+        // the server hung up
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn skips_lines_that_are_too_long() {
+        let (addr, _) = spawn_server(Limits::default());
+        let (mut stream, mut reader) = connect(addr);
+        let long = "x".repeat(MAX_LINE + 1);
+        assert!(ask(&mut stream, &mut reader, &long).starts_with("ERR line too long"));
+        assert_eq!(ask(&mut stream, &mut reader, "still here"), "still here");
+        let longest = "y".repeat(MAX_LINE);
+        assert_eq!(ask(&mut stream, &mut reader, &longest), longest);
+    }
+
+    #[test]
+    fn serves_every_listen_address() {
+        let options =
+            Options::parse(["--listen", "[::1]:0", "--listen", "127.0.0.1:0"].map(String::from))
+                .unwrap();
+        let listeners = options.listen.bind().unwrap();
+        let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        serve(
+            listeners,
+            Arc::new(Stats::default()),
+            options.limits,
+            Service::Echo,
+        );
+
+        for addr in addrs {
+            assert_ne!(addr.port(), 0);
+            let (mut stream, mut reader) = connect(addr);
+            assert_eq!(ask(&mut stream, &mut reader, "ECHO hi"), "hi");
+        }
+    }
+
+    #[test]
+    fn closes_on_eof() {
+        let (addr, stats) = spawn_server(Limits::default());
+        let (mut stream, mut reader) = connect(addr);
+        assert_eq!(ask(&mut stream, &mut reader, "ping"), "ping");
+        assert_eq!(stats.active(), 1);
+
+        // an unfinished last line is still answered before the server closes
+        stream.write_all(b"bye").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "bye\n");
+        wait_until(|| stats.active() == 0);
+    }
+
+    #[test]
+    fn refuses_clients_beyond_the_limit() {
+        let limits = Limits {
+            workers: 1,
+            max_connections: 2,
+            ..Limits::default()
+        };
+        let (addr, stats) = spawn_server(limits);
+        let (mut first, mut first_reader) = connect(addr);
+        assert_eq!(ask(&mut first, &mut first_reader, "first"), "first");
+        // waits for the only worker, but has a place
+        let (mut second, mut second_reader) = connect(addr);
+        writeln!(second, "second").unwrap();
+
+        let (_third, mut third_reader) = connect(addr);
+        let mut refusal = String::new();
+        third_reader.read_to_string(&mut refusal).unwrap();
+        assert_eq!(
+            refusal,
+            "ERR too many connections (at most 2), please try again later\n"
+        );
+        assert_eq!(stats.refused.load(Ordering::SeqCst), 1);
+
+        // once the first leaves, the second gets the worker
+        assert_eq!(ask(&mut first, &mut first_reader, "QUIT"), "BYE");
+        let mut answer = String::new();
+        second_reader.read_line(&mut answer).unwrap();
+        assert_eq!(answer, "second\n");
+        assert_eq!(ask(&mut second, &mut second_reader, "QUIT"), "BYE");
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let limits = Limits {
+            idle_timeout: Duration::from_millis(200),
+            ..Limits::default()
+        };
+        let (addr, stats) = spawn_server(limits);
+        let (mut stream, mut reader) = connect(addr);
+        assert_eq!(ask(&mut stream, &mut reader, "awake"), "awake");
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "ERR idle for 200ms, closing\n");
+        assert_eq!(stats.timed_out.load(Ordering::SeqCst), 1);
+        wait_until(|| stats.active() == 0);
+    }
+
+    #[test]
+    fn chats_in_rooms() {
+        let addr = spawn_chat(chat::QUEUE_LEN);
+        let (mut alice, mut alice_reader) = chat_as(addr, "alice");
+        let (mut bob, mut bob_reader) = chat_as(addr, "bob");
+        assert_eq!(read(&mut alice_reader), "* bob joined #lobby");
+
+        // what one says goes to the others, not back
+        writeln!(alice, "hi bob").unwrap();
+        assert_eq!(read(&mut bob_reader), "<alice> hi bob");
+        let who = ask(&mut alice, &mut alice_reader, "/who");
+        assert_eq!(who, "* In #lobby: alice, bob");
+
+        assert_eq!(
+            ask(&mut bob, &mut bob_reader, "/nick alice"),
+            "ERR alice is taken"
+        );
+        assert_eq!(
+            ask(&mut bob, &mut bob_reader, "/join #rust"),
+            "* bob left #lobby"
+        );
+        assert_eq!(read(&mut bob_reader), "* bob joined #rust");
+        assert_eq!(read(&mut alice_reader), "* bob left #lobby");
+        let rooms = ask(&mut alice, &mut alice_reader, "/rooms");
+        assert_eq!(rooms, "* Rooms: #lobby (1), #rust (1)");
+
+        // alice is not in #rust any more, so this doesn't reach her
+        writeln!(bob, "anyone here?").unwrap();
+        assert_eq!(ask(&mut bob, &mut bob_reader, "/who"), "* In #rust: bob");
+        assert_eq!(
+            ask(&mut bob, &mut bob_reader, "/frobnicate").get(..4),
+            Some("ERR ")
+        );
+
+        assert_eq!(
+            ask(&mut alice, &mut alice_reader, "/join rust"),
+            "* alice left #lobby"
+        );
+        assert_eq!(read(&mut alice_reader), "* alice joined #rust");
+        assert_eq!(read(&mut bob_reader), "* alice joined #rust");
+        assert_eq!(ask(&mut bob, &mut bob_reader, "/quit"), "* Bye");
+        let mut rest = String::new();
+        bob_reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(read(&mut alice_reader), "* bob left #rust (quit)");
+    }
+
+    #[test]
+    fn disconnects_slow_clients() {
+        let addr = spawn_chat(4);
+        let (mut slow, mut slow_reader) = chat_as(addr, "slow");
+        let (flooder, mut flooder_reader) = chat_as(addr, "flooder");
+        assert_eq!(read(&mut slow_reader), "* flooder joined #lobby");
+        writeln!(slow, "I won't read another line").unwrap();
+        assert_eq!(
+            read(&mut flooder_reader),
+            "<slow> I won't read another line"
+        );
+
+        // fill the socket buffers towards `slow`, then its queue
+        let mut writer = flooder.try_clone().unwrap();
+        thread::spawn(move || {
+            let line = "x".repeat(MAX_LINE);
+            for _ in 0..100_000 {
+                if writeln!(writer, "{}", line).is_err() {
+                    return;
+                }
+            }
+        });
+        assert_eq!(read(&mut flooder_reader), "* slow left #lobby (too slow)");
+        drop(flooder);
+    }
+}
+
 // test client
 #[test]
 fn test_client() {
     use std::str::from_utf8;
-    match TcpStream::connect("[::1]:3333") {
+    // the server runs in-process, on a port the system picks
+    let listener = listen::bind("[::1]:0".parse().unwrap(), None).unwrap();
+    let addr = listener.local_addr().unwrap();
+    serve(
+        vec![listener],
+        Arc::new(Stats::default()),
+        Limits::default(),
+        Service::Echo,
+    );
+
+    match TcpStream::connect(addr) {
         Ok(mut stream) => {
-            println!("Successfully connected to server in port 3333");
+            println!("Successfully connected to server in port {}", addr.port());
 
-            let msg = b"Hello!";
+            let msg = b"Hello!\n";
 
             stream.write_all(msg).unwrap();
             println!("Sent Hello, awaiting reply...");
@@ -84,7 +600,7 @@ fn test_client() {
                 Ok(s) => {
                     if let Ok(text) = from_utf8(&data[..s]) {
                         println!("Received reply: {}", text);
-                        assert_eq!(text, "Hello!"); // Replace "Expected reply" with the actual expected reply
+                        assert_eq!(text, "Hello!\n"); // Replace "Expected reply" with the actual expected reply
                     }
                 }
                 Err(e) => {
@@ -0,0 +1,173 @@
+// Command line options.
+use crate::chat;
+use crate::connections::Limits;
+use crate::listen::{self, ListenOptions};
+use std::{net::Ipv6Addr, net::SocketAddr, time::Duration};
+
+pub const USAGE: &str = "\
+Usage: ipv6_tcp_server [OPTIONS]
+
+Options:
+  --listen ADDR          address to listen on, may be given more than once;
+                         port 0 picks a free port [default: [::1]:3333]
+  --v6-only              IPv6 listeners accept IPv6 clients only
+  --dual-stack           IPv6 listeners accept IPv4 clients too
+  --workers N            threads serving connections, each one client at a
+                         time [default: 8, with --chat 64]
+  --max-connections N    refuse clients beyond N [default: --workers]
+  --idle-timeout SECS    close connections quiet for SECS [default: 300]
+  --chat                 be a chat server instead of an echo server
+  --chat-queue N         lines a chat client may fall behind before it is
+                         disconnected [default: 256]
+  --expose               forward the ports of the IPv4 and dual-stack
+                         listeners from the gateway while the server runs
+                         (needs the `expose` feature)";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub listen: ListenOptions,
+    pub limits: Limits,
+    /// `Some(queue length)` in chat mode
+    pub chat: Option<usize>,
+    /// map the listeners' ports on the gateway
+    pub expose: bool,
+}
+
+impl Options {
+    /// Parse the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+        let mut addrs = Vec::new();
+        let mut v6_only = None;
+        let mut limits = Limits::default();
+        let mut workers = None;
+        let mut max_connections = None;
+        let mut chat = false;
+        let mut chat_queue = chat::QUEUE_LEN;
+        let mut expose = false;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let only = match arg.as_str() {
+                "--listen" => {
+                    addrs.push(listen::parse_addr(&value(&arg, args.next())?)?);
+                    continue;
+                }
+                "--workers" => {
+                    workers = Some(parse(&arg, args.next())?);
+                    continue;
+                }
+                "--max-connections" => {
+                    max_connections = Some(parse(&arg, args.next())?);
+                    continue;
+                }
+                "--idle-timeout" => {
+                    limits.idle_timeout = Duration::from_secs(parse(&arg, args.next())?);
+                    continue;
+                }
+                "--chat" => {
+                    chat = true;
+                    continue;
+                }
+                "--chat-queue" => {
+                    chat_queue = parse(&arg, args.next())?;
+                    continue;
+                }
+                "--expose" => {
+                    expose = true;
+                    continue;
+                }
+                "--v6-only" => true,
+                "--dual-stack" => false,
+                _ => return Err(format!("unexpected argument '{}'", arg)),
+            };
+            if v6_only.is_some_and(|v6_only| v6_only != only) {
+                return Err("--v6-only and --dual-stack can't be combined".to_string());
+            }
+            v6_only = Some(only);
+        }
+        if addrs.is_empty() {
+            addrs.push(SocketAddr::from((Ipv6Addr::LOCALHOST, 3333)));
+        }
+        // a chat client keeps its worker for as long as it is connected
+        let default_workers = if chat { chat::WORKERS } else { limits.workers };
+        limits.workers = workers.unwrap_or(default_workers);
+        limits.max_connections = max_connections.unwrap_or(limits.workers);
+        if limits.workers == 0 || limits.max_connections == 0 {
+            return Err("--workers and --max-connections must be at least 1".to_string());
+        }
+        if limits.idle_timeout.is_zero() {
+            // a zero read timeout is an error for `set_read_timeout`
+            return Err("--idle-timeout must be at least 1".to_string());
+        }
+        if chat_queue == 0 {
+            return Err("--chat-queue must be at least 1".to_string());
+        }
+        Ok(Options {
+            listen: ListenOptions { addrs, v6_only },
+            limits,
+            chat: chat.then_some(chat_queue),
+            expose,
+        })
+    }
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} needs a value", option))
+}
+
+fn parse<T: std::str::FromStr>(option: &str, arg: Option<String>) -> Result<T, String> {
+    let arg = value(option, arg)?;
+    arg.parse()
+        .map_err(|_| format!("invalid value '{}' for {}", arg, option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = Options::parse(args("")).unwrap();
+        assert_eq!(options.listen.addrs, vec!["[::1]:3333".parse().unwrap()]);
+        assert_eq!(options.listen.v6_only, None);
+        assert_eq!(options.limits, Limits::default());
+        assert_eq!(options.chat, None);
+        assert!(!options.expose);
+
+        let options = Options::parse(args(
+            "--listen [::]:0 --listen 127.0.0.1:4000 --dual-stack --workers 2 --idle-timeout 9",
+        ))
+        .unwrap();
+        assert_eq!(options.listen.addrs.len(), 2);
+        assert_eq!(options.listen.v6_only, Some(false));
+        assert_eq!(options.limits.workers, 2);
+        assert_eq!(options.limits.max_connections, 2);
+        assert_eq!(options.limits.idle_timeout, Duration::from_secs(9));
+
+        let options = Options::parse(args("--max-connections 100")).unwrap();
+        assert_eq!(options.limits.max_connections, 100);
+        let options = Options::parse(args("--chat-queue 10 --chat")).unwrap();
+        assert_eq!(options.chat, Some(10));
+        assert_eq!(options.limits.workers, chat::WORKERS);
+        assert_eq!(options.limits.max_connections, chat::WORKERS);
+        let options = Options::parse(args("--chat --workers 3")).unwrap();
+        assert_eq!(options.limits.max_connections, 3);
+        let options = Options::parse(args("--listen 0.0.0.0:3333 --expose")).unwrap();
+        assert!(options.expose);
+
+        for bad in [
+            "--v6-only --dual-stack",
+            "--listen",
+            "--listen localhost",
+            "--workers 0",
+            "--idle-timeout 0",
+            "--max-connections many",
+            "--chat --chat-queue 0",
+        ] {
+            assert!(Options::parse(args(bad)).is_err(), "{}", bad);
+        }
+    }
+}
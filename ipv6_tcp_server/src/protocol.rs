@@ -0,0 +1,199 @@
+// The line protocol spoken over each connection.
+//
+// Clients send lines ending in "\n" (a "\r" before it is ignored). A line
+// starting with one of the commands below runs it, anything else is echoed
+// back as it is:
+//
+//     ECHO <text>   answers <text>
+//     TIME          answers the server's time in UTC, e.g. 2024-05-01T12:00:00Z
+//     STATS         answers the server's counters
+//     QUIT          answers BYE and closes the connection
+//
+// Commands are not case sensitive. Lines longer than `MAX_LINE` bytes are
+// answered with an error and skipped.
+use std::{
+    io::{self, BufRead},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The longest line the server accepts, without the line ending.
+pub const MAX_LINE: usize = 1024;
+
+/// What [`read_line`] found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    /// a line is in the buffer, without its line ending
+    Complete,
+    /// the line was longer than allowed and was skipped up to its end
+    TooLong,
+    /// the connection was closed, nothing is left to read
+    Eof,
+}
+
+/// Read the next line into `buf` (which is cleared first), keeping at most
+/// `max` bytes of it in memory.
+///
+/// A last line without a line ending counts as complete.
+pub fn read_line<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>, max: usize) -> io::Result<Line> {
+    buf.clear();
+    let mut too_long = false;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            return Ok(match (too_long, buf.is_empty()) {
+                (true, _) => Line::TooLong,
+                (false, true) => Line::Eof,
+                (false, false) => Line::Complete,
+            });
+        }
+        let (chunk, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(end) => (&available[..end], Some(end + 1)),
+            None => (available, None),
+        };
+        if !too_long {
+            if buf.len() + chunk.len() > max + 1 {
+                // one byte more than `max` could still be a "\r"
+                too_long = true;
+                buf.clear();
+            } else {
+                buf.extend_from_slice(chunk);
+            }
+        }
+        let used = done.unwrap_or(available.len());
+        reader.consume(used);
+        if done.is_some() {
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+            if buf.len() > max {
+                too_long = true;
+                buf.clear();
+            }
+            return Ok(if too_long {
+                Line::TooLong
+            } else {
+                Line::Complete
+            });
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Echo(&'a [u8]),
+    Time,
+    Stats,
+    Quit,
+    /// not a command, echoed as it is
+    Other(&'a [u8]),
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a [u8]) -> Command<'a> {
+        let (word, rest) = match line.iter().position(|&b| b == b' ') {
+            Some(space) => (&line[..space], &line[space + 1..]),
+            None => (line, &line[line.len()..]),
+        };
+        let word = word.to_ascii_uppercase();
+        match (word.as_slice(), rest.is_empty()) {
+            (b"ECHO", _) => Command::Echo(rest),
+            (b"TIME", true) => Command::Time,
+            (b"STATS", true) => Command::Stats,
+            (b"QUIT", true) => Command::Quit,
+            _ => Command::Other(line),
+        }
+    }
+}
+
+/// `time` as an RFC 3339 timestamp in UTC, e.g. `2024-05-01T12:00:00Z`.
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// the date `days` after 1970-01-01, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::BufReader, time::Duration};
+
+    fn lines(input: &[u8], max: usize) -> Vec<(Line, String)> {
+        // a tiny buffer, so lines are split over several reads
+        let mut reader = BufReader::with_capacity(3, input);
+        let mut buf = Vec::new();
+        let mut lines = Vec::new();
+        loop {
+            let line = read_line(&mut reader, &mut buf, max).unwrap();
+            if line == Line::Eof {
+                return lines;
+            }
+            lines.push((line, String::from_utf8(buf.clone()).unwrap()));
+        }
+    }
+
+    #[test]
+    fn reads_lines_up_to_the_limit() {
+        let found = lines(b"short\r\nexactly8\nmuch too long\nlast", 8);
+        let expected = [
+            (Line::Complete, "short"),
+            (Line::Complete, "exactly8"),
+            (Line::TooLong, ""),
+            (Line::Complete, "last"),
+        ];
+        assert_eq!(found.len(), expected.len());
+        for ((line, text), (expected_line, expected_text)) in found.iter().zip(expected) {
+            assert_eq!(*line, expected_line);
+            assert_eq!(text, expected_text);
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse(b"ECHO hi there"), Command::Echo(b"hi there"));
+        assert_eq!(Command::parse(b"echo"), Command::Echo(b""));
+        assert_eq!(Command::parse(b"time"), Command::Time);
+        assert_eq!(Command::parse(b"STATS"), Command::Stats);
+        assert_eq!(Command::parse(b"Quit"), Command::Quit);
+        assert_eq!(Command::parse(b"QUIT now"), Command::Other(b"QUIT now"));
+        assert_eq!(Command::parse(b"hello"), Command::Other(b"hello"));
+    }
+
+    #[test]
+    fn formats_time_in_utc() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(format_time(leap_day), "2024-02-29T12:34:56Z");
+    }
+}
@@ -0,0 +1,93 @@
+// Compares the single-threaded `search` with the parallel file search on a
+// generated corpus. There is no benchmark framework in this workspace, so
+// this is a plain program timed with `Instant`:
+//
+//     cargo bench -p minigrep
+use minigrep::{output::OutputOptions, parallel, search, search_file, Matcher, QueryMode};
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+const FILES: usize = 64;
+const LINES_PER_FILE: usize = 20_000;
+const QUERY: &str = "needle";
+
+// deterministic pseudo-random text with the query sprinkled in
+fn generate_corpus(dir: &std::path::Path) -> Vec<PathBuf> {
+    let words = [
+        "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", QUERY,
+    ];
+    let mut seed: u64 = 42;
+    let mut next = || {
+        // xorshift
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    (0..FILES)
+        .map(|i| {
+            let mut contents = String::new();
+            for _ in 0..LINES_PER_FILE {
+                let line: Vec<&str> = (0..8)
+                    .map(|_| words[(next() % 64) as usize % words.len()])
+                    .collect();
+                contents.push_str(&line.join(" "));
+                contents.push('\n');
+            }
+            let path = dir.join(format!("file{i:03}.txt"));
+            fs::write(&path, contents).unwrap();
+            path
+        })
+        .collect()
+}
+
+fn time<T>(name: &str, f: impl Fn() -> T) -> (Duration, T) {
+    // the first run warms the page cache
+    f();
+    let runs = 5;
+    let start = Instant::now();
+    let mut result = f();
+    for _ in 1..runs {
+        result = f();
+    }
+    let elapsed = start.elapsed() / runs;
+    println!("{name:<28} {elapsed:>10.2?}");
+    (elapsed, result)
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = generate_corpus(dir.path());
+    println!("corpus: {FILES} files x {LINES_PER_FILE} lines");
+
+    let (sequential, expected) = time("search (single thread)", || {
+        files
+            .iter()
+            .map(|file| search(QUERY, &fs::read_to_string(file).unwrap()).len())
+            .sum::<usize>()
+    });
+
+    let matcher = Matcher::new(QUERY, QueryMode::Literal, false).unwrap();
+    let options = OutputOptions::default();
+    let jobs = parallel::default_jobs();
+    let (parallel, found) = time(&format!("search_files (-j {jobs})"), || {
+        parallel::search_files(
+            &files,
+            jobs,
+            |file, mut out| search_file(file, &matcher, &options, false, &mut out),
+            &mut io::sink(),
+            |file, e| panic!("{}: {e}", file.display()),
+        )
+        .unwrap()
+    });
+
+    assert_eq!(expected, found);
+    println!(
+        "speedup: {:.1}x",
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -0,0 +1,542 @@
+// Command line parsing for `Config::build`.
+//
+// Every option is described once in `OPTIONS`; the parser looks options up
+// there and `usage()` generates the `--help` text from the same table, so the
+// two can't drift apart.
+use crate::{
+    fuzzy::FuzzyOptions, output::OutputOptions, parallel, replace::RewriteOptions,
+    walk::WalkOptions, Config, QueryMode, STDIN,
+};
+use std::{error::Error, fmt};
+
+struct Opt {
+    short: Option<char>,
+    long: &'static str,
+    // name of the value shown in the help, `None` for a plain flag
+    value: Option<&'static str>,
+    help: &'static str,
+}
+
+#[rustfmt::skip]
+const OPTIONS: &[Opt] = &[
+    Opt { short: Some('e'), long: "regexp", value: Some("PATTERN"), help: "search for PATTERN, can be given more than once" },
+    Opt { short: Some('i'), long: "ignore-case", value: None, help: "ignore case distinctions (overrides IGNORE_CASE)" },
+    Opt { short: Some('s'), long: "case-sensitive", value: None, help: "match case exactly (overrides IGNORE_CASE)" },
+    Opt { short: Some('S'), long: "smart-case", value: None, help: "ignore case unless a pattern has an uppercase letter" },
+    Opt { short: Some('E'), long: "regex", value: None, help: "patterns are regular expressions" },
+    Opt { short: Some('w'), long: "word", value: None, help: "patterns must match whole words" },
+    Opt { short: Some('F'), long: "fixed-strings", value: None, help: "patterns are newline separated lists of strings" },
+    Opt { short: None, long: "fuzzy", value: None, help: "find lines within a few typos of the pattern, closest first" },
+    Opt { short: Some('k'), long: "max-errors", value: Some("NUM"), help: "with --fuzzy, allow NUM edits (default: 1), implies --fuzzy" },
+    Opt { short: None, long: "best", value: Some("NUM"), help: "with --fuzzy, only print the NUM closest lines" },
+    Opt { short: Some('n'), long: "line-number", value: None, help: "print the line number of each line" },
+    Opt { short: Some('A'), long: "after-context", value: Some("NUM"), help: "print NUM lines after each match" },
+    Opt { short: Some('B'), long: "before-context", value: Some("NUM"), help: "print NUM lines before each match" },
+    Opt { short: Some('C'), long: "context", value: Some("NUM"), help: "print NUM lines before and after each match" },
+    Opt { short: Some('c'), long: "count", value: None, help: "only print the number of selected lines per file" },
+    Opt { short: Some('l'), long: "files-with-matches", value: None, help: "only print the names of files with a match" },
+    Opt { short: Some('L'), long: "files-without-match", value: None, help: "only print the names of files without a match" },
+    Opt { short: Some('v'), long: "invert-match", value: None, help: "select the lines that do not match" },
+    Opt { short: Some('m'), long: "max-count", value: Some("NUM"), help: "stop after NUM selected lines per file" },
+    // long only: grep's -r means recursive, which minigrep always is
+    Opt { short: None, long: "replace", value: Some("TEMPLATE"), help: "print lines with matches replaced, $1 or ${name} for groups" },
+    Opt { short: None, long: "in-place", value: None, help: "rewrite the files with --replace instead of printing" },
+    Opt { short: None, long: "backup", value: None, help: "with --in-place, keep the original as FILE.bak" },
+    Opt { short: None, long: "dry-run", value: None, help: "with --replace, print a unified diff of the changes" },
+    Opt { short: None, long: "json", value: None, help: "print results as JSON Lines" },
+    Opt { short: None, long: "include", value: Some("GLOB"), help: "only search files matching GLOB" },
+    Opt { short: None, long: "exclude", value: Some("GLOB"), help: "skip files matching GLOB" },
+    Opt { short: None, long: "no-ignore", value: None, help: "don't respect .gitignore and .ignore files" },
+    Opt { short: None, long: "mmap", value: None, help: "search files through a memory map" },
+    Opt { short: Some('j'), long: "threads", value: Some("NUM"), help: "search NUM files at once (default: one per core)" },
+    Opt { short: Some('h'), long: "help", value: None, help: "print this help" },
+    Opt { short: Some('V'), long: "version", value: None, help: "print the version" },
+];
+
+/// Why the command line could not be turned into a [`Config`].
+///
+/// `Help` and `Version` are not really errors: parsing stops and the caller
+/// should print the error's message and exit successfully.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    MissingPattern,
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidNumber {
+        option: String,
+        value: String,
+    },
+    /// `--in-place`, `--backup` or `--dry-run` without `--replace`
+    MissingReplace(String),
+    /// two options that can't be used together
+    Conflict(String, String),
+    Help,
+    Version,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingPattern => write!(f, "Didn't get a query string"),
+            ConfigError::UnknownOption(option) => write!(f, "unknown option '{option}'"),
+            ConfigError::MissingValue(option) => write!(f, "option '{option}' needs a value"),
+            ConfigError::InvalidNumber { option, value } => {
+                write!(f, "option '{option}' needs a number, got '{value}'")
+            }
+            ConfigError::MissingReplace(option) => write!(f, "option '{option}' needs --replace"),
+            ConfigError::Conflict(a, b) => write!(f, "options '{a}' and '{b}' can't be combined"),
+            ConfigError::Help => write!(f, "{}", usage()),
+            ConfigError::Version => write!(f, "minigrep {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The `--help` text.
+pub fn usage() -> String {
+    let mut text = String::from(
+        "Usage: minigrep [OPTIONS] PATTERN [PATH...]\n       minigrep [OPTIONS] -e PATTERN... [PATH...]\n\nOptions:\n",
+    );
+    for opt in OPTIONS {
+        let short = match opt.short {
+            Some(short) => format!("-{short}, "),
+            None => "    ".to_string(),
+        };
+        let value = opt.value.map(|v| format!(" {v}")).unwrap_or_default();
+        let flag = format!("{short}--{}{value}", opt.long);
+        text.push_str(&format!("  {flag:<30} {}\n", opt.help));
+    }
+    text.push_str("\nWithout a PATH, or with -, standard input is searched.\n");
+    text.push_str("Set IGNORE_CASE to ignore case by default. Use -- to end the options.");
+    text
+}
+
+enum CaseMode {
+    Sensitive,
+    Insensitive,
+    Smart,
+}
+
+// `env_ignore_case` is whether IGNORE_CASE is set, passed in so the parser
+// itself doesn't depend on the environment
+pub(crate) fn parse(
+    mut args: impl Iterator<Item = String>,
+    env_ignore_case: bool,
+) -> Result<Config, ConfigError> {
+    args.next(); // skip the first argument which is the program name
+
+    let mut patterns = Vec::new();
+    let mut positional = Vec::new();
+    let mut case = None;
+    let mut mode = QueryMode::Literal;
+    let mut walk = WalkOptions::default();
+    let mut output = OutputOptions::default();
+    let mut mmap = false;
+    let mut in_place = false;
+    let mut rewrite = RewriteOptions::default();
+    let mut fuzzy: Option<FuzzyOptions> = None;
+    // the last of -A, -B and -C, for the conflicts with --fuzzy
+    let mut context = None;
+    let mut jobs = parallel::default_jobs();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            // everything after `--` is positional, even if it starts with '-'
+            positional.extend(args.by_ref());
+            break;
+        }
+
+        // (option, value attached with `=` or directly after a short flag)
+        let mut found: Vec<(&Opt, Option<String>)> = Vec::new();
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let opt = OPTIONS
+                .iter()
+                .find(|opt| opt.long == name)
+                .ok_or_else(|| ConfigError::UnknownOption(arg.clone()))?;
+            found.push((opt, value));
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            // short flags can be combined (`-nv`) and a value can follow directly (`-A2`)
+            let shorts = &arg[1..];
+            for (i, short) in shorts.char_indices() {
+                let opt = OPTIONS
+                    .iter()
+                    .find(|opt| opt.short == Some(short))
+                    .ok_or_else(|| ConfigError::UnknownOption(format!("-{short}")))?;
+                let rest = &shorts[i + short.len_utf8()..];
+                if opt.value.is_some() && !rest.is_empty() {
+                    found.push((opt, Some(rest.to_string())));
+                    break;
+                }
+                found.push((opt, None));
+            }
+        } else {
+            positional.push(arg);
+            continue;
+        }
+
+        for (opt, value) in found {
+            let value = match (opt.value, value) {
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(format!("--{}", opt.long)))?,
+                (None, Some(_)) => return Err(ConfigError::UnknownOption(arg.clone())),
+                (None, None) => String::new(),
+            };
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| ConfigError::InvalidNumber {
+                        option: format!("--{}", opt.long),
+                        value: value.clone(),
+                    })
+            };
+
+            match opt.long {
+                "regexp" => patterns.push(value.clone()),
+                "ignore-case" => case = Some(CaseMode::Insensitive),
+                "case-sensitive" => case = Some(CaseMode::Sensitive),
+                "smart-case" => case = Some(CaseMode::Smart),
+                "regex" => mode = QueryMode::Regex,
+                "word" => mode = QueryMode::WholeWord,
+                "fixed-strings" => mode = QueryMode::FixedStrings,
+                "fuzzy" => {
+                    fuzzy.get_or_insert_with(FuzzyOptions::default);
+                }
+                "max-errors" => {
+                    fuzzy.get_or_insert_with(FuzzyOptions::default).max_distance = number()?
+                }
+                "best" => fuzzy.get_or_insert_with(FuzzyOptions::default).best = Some(number()?),
+                "line-number" => output.line_number = true,
+                "after-context" => {
+                    output.after_context = number()?;
+                    context = Some("--after-context");
+                }
+                "before-context" => {
+                    output.before_context = number()?;
+                    context = Some("--before-context");
+                }
+                "context" => {
+                    output.before_context = number()?;
+                    output.after_context = output.before_context;
+                    context = Some("--context");
+                }
+                "count" => output.count = true,
+                "files-with-matches" => output.files_with_matches = true,
+                "files-without-match" => output.files_without_match = true,
+                "invert-match" => output.invert_match = true,
+                "max-count" => output.max_count = Some(number()?),
+                "replace" => output.replace = Some(value.clone()),
+                "in-place" => in_place = true,
+                "backup" => rewrite.backup = true,
+                "dry-run" => rewrite.dry_run = true,
+                "json" => output.json = true,
+                "include" => walk.include.push(value.clone()),
+                "exclude" => walk.exclude.push(value.clone()),
+                "no-ignore" => walk.no_ignore = true,
+                "mmap" => mmap = true,
+                "threads" => jobs = number()?.max(1),
+                "help" => return Err(ConfigError::Help),
+                "version" => return Err(ConfigError::Version),
+                _ => unreachable!("option --{} is not handled", opt.long),
+            }
+        }
+    }
+
+    if output.replace.is_none() {
+        let needs_replace = [
+            (in_place, "--in-place"),
+            (rewrite.backup, "--backup"),
+            (rewrite.dry_run, "--dry-run"),
+        ];
+        if let Some((_, option)) = needs_replace.iter().find(|(given, _)| *given) {
+            return Err(ConfigError::MissingReplace(option.to_string()));
+        }
+    }
+
+    if output.json {
+        // JSON events always carry the lines themselves, as they were read
+        check_conflicts(
+            "--json",
+            &[
+                (output.count, "--count"),
+                (output.files_with_matches, "--files-with-matches"),
+                (output.files_without_match, "--files-without-match"),
+                (output.replace.is_some(), "--replace"),
+            ],
+        )?;
+    }
+    if fuzzy.is_some() {
+        // fuzzy results are ranked lines of a single pattern, compared as
+        // plain text, so there is nothing else to print or to match with
+        let mode_option = match mode {
+            QueryMode::Literal => "",
+            QueryMode::Regex => "--regex",
+            QueryMode::WholeWord => "--word",
+            QueryMode::FixedStrings => "--fixed-strings",
+        };
+        check_conflicts(
+            "--fuzzy",
+            &[
+                (mode != QueryMode::Literal, mode_option),
+                (patterns.len() > 1, "--regexp"),
+                (context.is_some(), context.unwrap_or_default()),
+                (output.max_count.is_some(), "--max-count"),
+                (output.json, "--json"),
+                (output.count, "--count"),
+                (output.files_with_matches, "--files-with-matches"),
+                (output.files_without_match, "--files-without-match"),
+                (output.invert_match, "--invert-match"),
+                (output.replace.is_some(), "--replace"),
+            ],
+        )?;
+    }
+
+    // without -e the first positional argument is the pattern
+    let mut positional = positional.into_iter();
+    if patterns.is_empty() {
+        patterns.push(positional.next().ok_or(ConfigError::MissingPattern)?);
+    }
+    let mut paths: Vec<String> = positional.collect();
+    if paths.is_empty() {
+        // like grep, read standard input when there is nothing else to search
+        paths.push(STDIN.to_string());
+    }
+
+    let ignore_case = match case {
+        Some(CaseMode::Sensitive) => false,
+        Some(CaseMode::Insensitive) => true,
+        Some(CaseMode::Smart) => !patterns.iter().any(|p| p.chars().any(char::is_uppercase)),
+        None => env_ignore_case,
+    };
+
+    Ok(Config {
+        patterns,
+        paths,
+        ignore_case,
+        mode,
+        walk,
+        output,
+        mmap,
+        jobs,
+        // a dry run shows what rewriting would do, so it rewrites too, just on paper
+        rewrite: (in_place || rewrite.dry_run).then_some(rewrite),
+        fuzzy,
+    })
+}
+
+// `option` can't be combined with any of `others` that was given
+fn check_conflicts(option: &str, others: &[(bool, &str)]) -> Result<(), ConfigError> {
+    match others.iter().find(|(given, _)| *given) {
+        Some((_, other)) => Err(ConfigError::Conflict(option.to_string(), other.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str], env_ignore_case: bool) -> Result<Config, ConfigError> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse(args.into_iter(), env_ignore_case)
+    }
+
+    #[test]
+    fn ignore_case_flags_override_env() {
+        let config = parse_args(&["minigrep", "to", "poem.txt"], true).unwrap();
+        assert!(config.ignore_case);
+
+        let config = parse_args(&["minigrep", "-s", "to", "poem.txt"], true).unwrap();
+        assert!(!config.ignore_case);
+
+        let config = parse_args(&["minigrep", "--ignore-case", "to", "poem.txt"], false).unwrap();
+        assert!(config.ignore_case);
+    }
+
+    #[test]
+    fn smart_case() {
+        let config = parse_args(&["minigrep", "-S", "to", "poem.txt"], false).unwrap();
+        assert!(config.ignore_case);
+
+        let config = parse_args(&["minigrep", "-S", "To", "poem.txt"], true).unwrap();
+        assert!(!config.ignore_case);
+    }
+
+    #[test]
+    fn repeated_patterns() {
+        let config = parse_args(&["minigrep", "-e", "to", "-eme", "a", "b"], false).unwrap();
+        assert_eq!(config.patterns, ["to", "me"]);
+        assert_eq!(config.paths, ["a", "b"]);
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let config = parse_args(&["minigrep", "-n", "--", "-v", "--help"], false).unwrap();
+        assert_eq!(config.patterns, ["-v"]);
+        assert_eq!(config.paths, ["--help"]);
+        assert!(config.output.line_number);
+        assert!(!config.output.invert_match);
+    }
+
+    #[test]
+    fn combined_short_flags_and_values() {
+        let config = parse_args(
+            &["minigrep", "-nvA2", "-B", "1", "--max-count=3", "to", "p"],
+            false,
+        )
+        .unwrap();
+        assert!(config.output.line_number);
+        assert!(config.output.invert_match);
+        assert_eq!(config.output.after_context, 2);
+        assert_eq!(config.output.before_context, 1);
+        assert_eq!(config.output.max_count, Some(3));
+
+        let config = parse_args(&["minigrep", "-j4", "to", "p"], false).unwrap();
+        assert_eq!(config.jobs, 4);
+    }
+
+    #[test]
+    fn typed_errors() {
+        assert_eq!(
+            parse_args(&["minigrep"], false).err(),
+            Some(ConfigError::MissingPattern)
+        );
+        assert_eq!(
+            parse_args(&["minigrep", "-x", "to", "p"], false).err(),
+            Some(ConfigError::UnknownOption("-x".to_string()))
+        );
+        assert_eq!(
+            parse_args(&["minigrep", "to", "p", "-A"], false).err(),
+            Some(ConfigError::MissingValue("--after-context".to_string()))
+        );
+        assert_eq!(
+            parse_args(&["minigrep", "-m", "many", "to", "p"], false).err(),
+            Some(ConfigError::InvalidNumber {
+                option: "--max-count".to_string(),
+                value: "many".to_string()
+            })
+        );
+        assert_eq!(
+            parse_args(&["minigrep", "-h"], false).err(),
+            Some(ConfigError::Help)
+        );
+        assert_eq!(
+            parse_args(&["minigrep", "--version"], false).err(),
+            Some(ConfigError::Version)
+        );
+    }
+
+    #[test]
+    fn replace_options() {
+        let config = parse_args(&["minigrep", "--replace", "$1", "(a)", "p"], false).unwrap();
+        assert_eq!(config.output.replace.as_deref(), Some("$1"));
+        assert!(config.rewrite.is_none());
+        assert_eq!(
+            parse_args(&["minigrep", "-r", "foo", "."], false).err(),
+            Some(ConfigError::UnknownOption("-r".to_string()))
+        );
+
+        let config = parse_args(
+            &[
+                "minigrep",
+                "--replace=b",
+                "--in-place",
+                "--backup",
+                "a",
+                "p",
+            ],
+            false,
+        )
+        .unwrap();
+        let rewrite = config.rewrite.unwrap();
+        assert!(rewrite.backup && !rewrite.dry_run);
+
+        let config =
+            parse_args(&["minigrep", "--replace=b", "--dry-run", "a", "p"], false).unwrap();
+        assert!(config.rewrite.unwrap().dry_run);
+
+        assert_eq!(
+            parse_args(&["minigrep", "--in-place", "a", "p"], false).err(),
+            Some(ConfigError::MissingReplace("--in-place".to_string()))
+        );
+    }
+
+    #[test]
+    fn json_conflicts_with_summaries() {
+        let config = parse_args(&["minigrep", "--json", "-C1", "a", "p"], false).unwrap();
+        assert!(config.output.json);
+        assert_eq!(
+            parse_args(&["minigrep", "--json", "-l", "a", "p"], false).err(),
+            Some(ConfigError::Conflict(
+                "--json".to_string(),
+                "--files-with-matches".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn fuzzy_options() {
+        let config = parse_args(&["minigrep", "--fuzzy", "a", "p"], false).unwrap();
+        assert_eq!(config.fuzzy, Some(FuzzyOptions::default()));
+
+        let config = parse_args(&["minigrep", "-k2", "--best=5", "a", "p"], false).unwrap();
+        assert_eq!(
+            config.fuzzy,
+            Some(FuzzyOptions {
+                max_distance: 2,
+                best: Some(5)
+            })
+        );
+        assert!(parse_args(&["minigrep", "a", "p"], false)
+            .unwrap()
+            .fuzzy
+            .is_none());
+        assert_eq!(
+            parse_args(&["minigrep", "--fuzzy", "-v", "a", "p"], false).err(),
+            Some(ConfigError::Conflict(
+                "--fuzzy".to_string(),
+                "--invert-match".to_string()
+            ))
+        );
+        let conflict = |args: &[&str]| match parse_args(args, false) {
+            Err(ConfigError::Conflict(fuzzy, other)) if fuzzy == "--fuzzy" => other,
+            other => panic!("{args:?} gave {:?}", other.err()),
+        };
+        assert_eq!(conflict(&["minigrep", "--fuzzy", "-C2", "a"]), "--context");
+        assert_eq!(
+            conflict(&["minigrep", "-B", "1", "-k2", "a"]),
+            "--before-context"
+        );
+        assert_eq!(
+            conflict(&["minigrep", "--fuzzy", "-m1", "a"]),
+            "--max-count"
+        );
+        assert_eq!(conflict(&["minigrep", "--fuzzy", "-w", "a"]), "--word");
+        assert_eq!(
+            conflict(&["minigrep", "-F", "--best=3", "a"]),
+            "--fixed-strings"
+        );
+        assert_eq!(conflict(&["minigrep", "--fuzzy", "-E", "a"]), "--regex");
+        assert_eq!(
+            conflict(&["minigrep", "--fuzzy", "-e", "a", "-e", "b"]),
+            "--regexp"
+        );
+        // one -e is just the pattern
+        assert!(parse_args(&["minigrep", "--fuzzy", "-e", "a", "p"], false).is_ok());
+    }
+
+    #[test]
+    fn usage_lists_every_option() {
+        let usage = usage();
+        for opt in OPTIONS {
+            assert!(usage.contains(&format!("--{}", opt.long)));
+        }
+        assert!(usage.contains("-A, --after-context NUM"));
+    }
+}
@@ -0,0 +1,256 @@
+// Unicode case folding for `search_case_insensitive` and the `-i` searches
+// of literal and fixed-string patterns.
+//
+// Lowercasing isn't enough to compare text without case: "STRASSE" lowercases
+// to "strasse", not "straße", and a lowercased copy of every line costs an
+// allocation per line. Instead both sides go through full case folding
+// (ß and ẞ fold to "ss", final ς to σ) as a stream of chars, and the folded
+// line is searched with KMP while it is being produced, so nothing is copied.
+use crate::matcher::Span;
+use caseless::Caseless;
+use std::iter;
+use unicode_normalization::UnicodeNormalization;
+
+/// Unicode normalization applied before and after folding, so that text that
+/// looks the same but is encoded differently (precomposed "é" versus "e" and
+/// a combining acute accent) still matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// compare the code points as they are
+    #[default]
+    None,
+    /// compose both sides (NFC)
+    Nfc,
+    /// decompose both sides (NFD), i.e. Unicode's canonical caseless matching
+    Nfd,
+}
+
+/// How [`FoldedQuery`] compares text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FoldOptions {
+    pub normalization: Normalization,
+    /// use the Turkish and Azerbaijani mappings: I folds to dotless ı and
+    /// İ to i, instead of I to i and İ to "i̇"
+    pub turkic: bool,
+}
+
+/// A query folded once up front, ready to be searched for in many lines.
+#[derive(Debug, Clone)]
+pub struct FoldedQuery {
+    chars: Vec<char>,
+    // KMP failure function: the length of the longest proper prefix of
+    // `chars[..=i]` that is also a suffix of it
+    failure: Vec<usize>,
+    options: FoldOptions,
+}
+
+impl FoldedQuery {
+    pub fn new(query: &str, options: FoldOptions) -> FoldedQuery {
+        let chars: Vec<char> = match options.normalization {
+            Normalization::None => fold(query.chars(), options.turkic).collect(),
+            Normalization::Nfc => fold(query.nfc(), options.turkic).nfc().collect(),
+            Normalization::Nfd => fold(query.nfd(), options.turkic).nfd().collect(),
+        };
+
+        let mut failure = vec![0; chars.len()];
+        let mut k = 0;
+        for i in 1..chars.len() {
+            while k > 0 && chars[i] != chars[k] {
+                k = failure[k - 1];
+            }
+            if chars[i] == chars[k] {
+                k += 1;
+            }
+            failure[i] = k;
+        }
+
+        FoldedQuery {
+            chars,
+            failure,
+            options,
+        }
+    }
+
+    /// Whether the query occurs in `line`, ignoring case.
+    pub fn is_match(&self, line: &str) -> bool {
+        let turkic = self.options.turkic;
+        // one arm per pipeline keeps the iterators unboxed
+        match self.options.normalization {
+            Normalization::None => self.find_in(fold(line.chars(), turkic)),
+            Normalization::Nfc => self.find_in(fold(line.nfc(), turkic).nfc()),
+            Normalization::Nfd => self.find_in(fold(line.nfd(), turkic).nfd()),
+        }
+    }
+
+    /// Where the query occurs in `line`, leftmost first and without
+    /// overlaps, as byte ranges of `line`. A match that starts or ends
+    /// inside what one char folds to (the first "s" of "ß") covers that
+    /// whole char.
+    ///
+    /// Normalization merges and splits chars, so with it the positions
+    /// aren't known and a matching line is a single span.
+    pub fn find_spans(&self, line: &str) -> Vec<Span> {
+        if self.options.normalization != Normalization::None {
+            return match self.is_match(line) && !line.is_empty() {
+                true => vec![Span {
+                    start: 0,
+                    end: line.len(),
+                }],
+                false => Vec::new(),
+            };
+        }
+        if self.chars.is_empty() {
+            return Vec::new();
+        }
+        // every folded char with the byte range of the char it came from;
+        // folding is char by char, so this is the same stream `is_match` sees
+        let turkic = self.options.turkic;
+        let folded: Vec<(char, usize, usize)> = line
+            .char_indices()
+            .flat_map(|(i, c)| fold(iter::once(c), turkic).map(move |f| (f, i, i + c.len_utf8())))
+            .collect();
+
+        let mut spans = Vec::new();
+        let mut k = 0;
+        for (i, &(c, _, end)) in folded.iter().enumerate() {
+            while k > 0 && c != self.chars[k] {
+                k = self.failure[k - 1];
+            }
+            if c == self.chars[k] {
+                k += 1;
+                if k == self.chars.len() {
+                    let start = folded[i + 1 - k].1;
+                    // two matches inside the same char make one span
+                    if spans.last().is_none_or(|last: &Span| last.end <= start) {
+                        spans.push(Span { start, end });
+                    }
+                    k = 0;
+                }
+            }
+        }
+        spans
+    }
+
+    fn find_in(&self, folded: impl Iterator<Item = char>) -> bool {
+        if self.chars.is_empty() {
+            return true;
+        }
+        let mut k = 0;
+        for c in folded {
+            while k > 0 && c != self.chars[k] {
+                k = self.failure[k - 1];
+            }
+            if c == self.chars[k] {
+                k += 1;
+                if k == self.chars.len() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+// full case folding, also for the approximate matching of `--fuzzy -i`
+pub(crate) fn fold(chars: impl Iterator<Item = char>, turkic: bool) -> impl Iterator<Item = char> {
+    chars
+        .map(move |c| match c {
+            // the two entries CaseFolding.txt marks with status T
+            'I' if turkic => 'ı',
+            'İ' if turkic => 'i',
+            c => c,
+        })
+        .default_case_fold()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(query: &str, line: &str, options: FoldOptions) -> bool {
+        FoldedQuery::new(query, options).is_match(line)
+    }
+
+    fn default_match(query: &str, line: &str) -> bool {
+        matches(query, line, FoldOptions::default())
+    }
+
+    #[test]
+    fn folds_sharp_s_both_ways() {
+        assert!(default_match("STRASSE", "Die Straße ist lang."));
+        assert!(default_match("straße", "DIE STRASSE IST LANG."));
+        // capital sharp s
+        assert!(default_match("straße", "STRAẞE"));
+        assert!(!default_match("strase", "Straße"));
+    }
+
+    #[test]
+    fn turkish_dotted_and_dotless_i() {
+        let turkic = FoldOptions {
+            turkic: true,
+            ..Default::default()
+        };
+        // by default I is the capital of i, and dotless ı stays apart
+        assert!(default_match("istanbul", "ISTANBUL"));
+        assert!(!default_match("kır", "KIR"));
+        // İ folds to i followed by a combining dot above
+        assert!(default_match("i\u{307}stanbul", "İSTANBUL"));
+
+        assert!(matches("kır", "KIR", turkic));
+        assert!(matches("istanbul", "İSTANBUL", turkic));
+        assert!(!matches("istanbul", "ISTANBUL", turkic));
+    }
+
+    #[test]
+    fn greek_and_cyrillic() {
+        // final ς and Σ both fold to σ
+        assert!(default_match("ΤΟΥΣ", "όλους τους φίλους"));
+        assert!(default_match("τουσ", "ΟΛΟΥΣ ΤΟΥΣ"));
+        assert!(default_match("москва", "ГОРОД МОСКВА"));
+        assert!(!default_match("москва", "ГОРОД МОСКВЕ"));
+    }
+
+    #[test]
+    fn normalization_matches_composed_and_decomposed() {
+        let composed = "Café au lait";
+        let decomposed = "Cafe\u{301} au lait";
+        assert!(!default_match("CAFÉ", decomposed));
+
+        for normalization in [Normalization::Nfc, Normalization::Nfd] {
+            let options = FoldOptions {
+                normalization,
+                ..Default::default()
+            };
+            assert!(matches("CAFÉ", decomposed, options));
+            assert!(matches("CAFE\u{301}", composed, options));
+        }
+    }
+
+    #[test]
+    fn finds_spans_in_the_original_line() {
+        let spans = |query: &str, line: &str| -> Vec<(usize, usize)> {
+            FoldedQuery::new(query, FoldOptions::default())
+                .find_spans(line)
+                .iter()
+                .map(|span| (span.start, span.end))
+                .collect()
+        };
+        // "ß" is two bytes that fold to two chars
+        assert_eq!(
+            spans("STRASSE", "Die Straße, die STRASSE"),
+            [(4, 11), (17, 24)]
+        );
+        assert_eq!(spans("s", "ßx"), [(0, 2)]);
+        assert_eq!(spans("aa", "AAAA"), [(0, 2), (2, 4)]);
+        assert_eq!(spans("x", "abc"), []);
+        assert_eq!(spans("", "abc"), []);
+    }
+
+    #[test]
+    fn kmp_restarts_inside_a_partial_match() {
+        assert!(default_match("AAB", "aaaab"));
+        assert!(default_match("abab", "ABAABABA"));
+        assert!(!default_match("abab", "ABAABBA"));
+        assert!(default_match("", "anything"));
+    }
+}
@@ -0,0 +1,173 @@
+// Approximate matching for `--fuzzy`.
+//
+// A line matches if some substring of it is at most `k` edits (insertions,
+// deletions or substitutions) away from the query. The distance is computed
+// with Myers' bit-parallel algorithm: one column of the edit distance table
+// is kept as bit vectors over the query, so each character of the line costs
+// a handful of word operations instead of a pass over the whole query.
+// Queries longer than a machine word fall back to the plain dynamic program.
+use crate::fold;
+use std::collections::HashMap;
+
+/// `--fuzzy` settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuzzyOptions {
+    /// `-k`: the most edits a line may be away from the query
+    pub max_distance: usize,
+    /// `--best`: only print this many of the closest lines
+    pub best: Option<usize>,
+}
+
+impl Default for FuzzyOptions {
+    fn default() -> Self {
+        FuzzyOptions {
+            max_distance: 1,
+            best: None,
+        }
+    }
+}
+
+/// A query compiled for approximate matching.
+#[derive(Debug, Clone)]
+pub struct FuzzyMatcher {
+    query: Vec<char>,
+    // for every char of the query, the positions where it occurs as a bit mask
+    peq: HashMap<char, u64>,
+    ignore_case: bool,
+}
+
+impl FuzzyMatcher {
+    pub fn new(query: &str, ignore_case: bool) -> FuzzyMatcher {
+        let query: Vec<char> = fold(query.chars(), ignore_case).collect();
+        let mut peq = HashMap::new();
+        if query.len() <= 64 {
+            for (i, &c) in query.iter().enumerate() {
+                *peq.entry(c).or_insert(0) |= 1 << i;
+            }
+        }
+        FuzzyMatcher {
+            query,
+            peq,
+            ignore_case,
+        }
+    }
+
+    /// The fewest edits that turn the query into some substring of `line`.
+    pub fn distance(&self, line: &str) -> usize {
+        let line = fold(line.chars(), self.ignore_case);
+        match self.query.len() {
+            0 => 0,
+            1..=64 => self.myers(line),
+            _ => self.dynamic(line),
+        }
+    }
+
+    /// The distance to `line` if it is at most `max_distance`.
+    pub fn find(&self, line: &str, max_distance: usize) -> Option<usize> {
+        Some(self.distance(line)).filter(|&d| d <= max_distance)
+    }
+
+    fn myers(&self, line: impl Iterator<Item = char>) -> usize {
+        let m = self.query.len();
+        let last = 1u64 << (m - 1);
+        // vertical deltas of the current column: +1 (pv) or -1 (mv)
+        let mut pv = !0u64;
+        let mut mv = 0u64;
+        let mut score = m;
+        let mut best = m;
+        for c in line {
+            let eq = self.peq.get(&c).copied().unwrap_or(0);
+            let xv = eq | mv;
+            let xh = ((eq & pv).wrapping_add(pv) ^ pv) | eq;
+            // horizontal deltas
+            let ph = mv | !(xh | pv);
+            let mh = pv & xh;
+            if ph & last != 0 {
+                score += 1;
+            } else if mh & last != 0 {
+                score -= 1;
+            }
+            // the top row stays 0 since a match may start anywhere in the line,
+            // so nothing is shifted in
+            let ph = ph << 1;
+            let mh = mh << 1;
+            pv = mh | !(xv | ph);
+            mv = ph & xv;
+            best = best.min(score);
+        }
+        best
+    }
+
+    // Sellers' algorithm, one column at a time
+    fn dynamic(&self, line: impl Iterator<Item = char>) -> usize {
+        let mut column: Vec<usize> = (0..=self.query.len()).collect();
+        let mut best = self.query.len();
+        for c in line {
+            let mut diagonal = 0;
+            for (i, &q) in self.query.iter().enumerate() {
+                let substitution = diagonal + usize::from(q != c);
+                diagonal = column[i + 1];
+                column[i + 1] = substitution.min(column[i] + 1).min(diagonal + 1);
+            }
+            best = best.min(column[self.query.len()]);
+        }
+        best
+    }
+}
+
+// `-i` folds case the way the exact matches do, so "STRASSE" is "straße"
+fn fold(chars: impl Iterator<Item = char>, ignore_case: bool) -> impl Iterator<Item = char> {
+    let (folded, same) = if ignore_case {
+        (Some(fold::fold(chars, false)), None)
+    } else {
+        (None, Some(chars))
+    };
+    folded
+        .into_iter()
+        .flatten()
+        .chain(same.into_iter().flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_edits_against_the_closest_substring() {
+        let matcher = FuzzyMatcher::new("connection", false);
+        assert_eq!(matcher.distance("error: connection refused"), 0);
+        // substitution, deletion, insertion
+        assert_eq!(matcher.distance("error: connectoin refused"), 2);
+        assert_eq!(matcher.distance("error: conection refused"), 1);
+        assert_eq!(matcher.distance("error: connnection refused"), 1);
+        assert_eq!(matcher.find("timeout", 2), None);
+        assert_eq!(matcher.find("conection", 2), Some(1));
+    }
+
+    #[test]
+    fn ignore_case_and_unicode() {
+        assert_eq!(FuzzyMatcher::new("Grüße", false).distance("grüße"), 1);
+        assert_eq!(FuzzyMatcher::new("Grüße", true).distance("GRÜßE"), 0);
+        assert_eq!(FuzzyMatcher::new("Grüße", true).distance("gruße"), 1);
+        // full case folding, like -i without --fuzzy
+        assert_eq!(FuzzyMatcher::new("STRASSE", true).distance("Die Straße"), 0);
+        assert_eq!(FuzzyMatcher::new("straße", true).distance("STRASSE"), 0);
+    }
+
+    #[test]
+    fn dynamic_program_agrees_with_bit_parallel() {
+        let line = "the quick brown fox jumps over the lazy dog";
+        let queries = ["quick brwn fx", "lazy cat", "", "x"];
+        for query in queries {
+            let matcher = FuzzyMatcher::new(query, false);
+            let dynamic = matcher.dynamic(line.chars());
+            assert_eq!(matcher.distance(line), dynamic, "{query}");
+        }
+
+        // longer than 64 chars, so only the dynamic program can handle it
+        let long = "a".repeat(70) + "b";
+        let matcher = FuzzyMatcher::new(&long, false);
+        let text = "a".repeat(69) + "c";
+        assert_eq!(matcher.distance(&text), 2);
+    }
+}
@@ -0,0 +1,119 @@
+// `--json` output: one JSON object per line for every event of a search,
+// modelled on ripgrep's JSON Lines format.
+//
+// Every object has a `type` (begin, match, context, end or summary) and the
+// event itself under `data`. `begin` and `end` are only written for files
+// with at least one selected line, the `summary` closes the whole run:
+//
+// {"type":"begin","data":{"path":"poem.txt"}}
+// {"type":"match","data":{"path":"poem.txt","line_number":2,"byte_offset":6,"line":"...","spans":[{"start":0,"end":4}]}}
+// {"type":"end","data":{"path":"poem.txt","stats":{"matched_lines":1,"matches":1,"elapsed_secs":0.0001}}}
+// {"type":"summary","data":{"stats":{"searches":1,"matched_lines":1,"elapsed_secs":0.0002}}}
+use crate::Match;
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// One line of `--json` output.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// the first line of a file is about to be printed
+    Begin { path: &'a str },
+    /// a selected line; `spans` are byte ranges inside `line`
+    Match {
+        path: &'a str,
+        #[serde(flatten)]
+        line: &'a Match<'a>,
+    },
+    /// a line printed for `-A`, `-B` or `-C`, `spans` is always empty
+    Context {
+        path: &'a str,
+        #[serde(flatten)]
+        line: &'a Match<'a>,
+    },
+    /// the last line of a file was printed
+    End { path: &'a str, stats: FileStats },
+    /// every file was searched
+    Summary { stats: SummaryStats },
+}
+
+/// Statistics written with the `end` event of a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct FileStats {
+    /// selected lines
+    pub matched_lines: usize,
+    /// matches inside those lines, a line can match more than once
+    pub matches: usize,
+    pub elapsed_secs: f64,
+}
+
+/// Statistics written with the final `summary` event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SummaryStats {
+    /// files (or standard input) searched
+    pub searches: usize,
+    pub matched_lines: usize,
+    pub elapsed_secs: f64,
+}
+
+/// Write `event` as a single line of JSON.
+pub fn write_event<W: Write>(out: &mut W, event: &Event) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, event)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Span;
+
+    fn to_string(event: &Event) -> String {
+        let mut out = Vec::new();
+        write_event(&mut out, event).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn match_event_flattens_the_match() {
+        let found = Match {
+            line_number: 2,
+            byte_offset: 6,
+            line: "safe, \"fast\"",
+            spans: vec![Span { start: 7, end: 11 }],
+        };
+        let event = Event::Match {
+            path: "poem.txt",
+            line: &found,
+        };
+        assert_eq!(
+            to_string(&event),
+            r#"{"type":"match","data":{"path":"poem.txt","line_number":2,"byte_offset":6,"line":"safe, \"fast\"","spans":[{"start":7,"end":11}]}}"#
+                .to_string()
+                + "\n"
+        );
+    }
+
+    #[test]
+    fn end_and_summary_events() {
+        let end = Event::End {
+            path: "a",
+            stats: FileStats {
+                matched_lines: 1,
+                matches: 2,
+                elapsed_secs: 0.5,
+            },
+        };
+        assert_eq!(
+            to_string(&end),
+            "{\"type\":\"end\",\"data\":{\"path\":\"a\",\"stats\":{\"matched_lines\":1,\"matches\":2,\"elapsed_secs\":0.5}}}\n"
+        );
+
+        let summary = Event::Summary {
+            stats: SummaryStats::default(),
+        };
+        assert_eq!(
+            to_string(&summary),
+            "{\"type\":\"summary\",\"data\":{\"stats\":{\"searches\":0,\"matched_lines\":0,\"elapsed_secs\":0.0}}}\n"
+        );
+    }
+}
@@ -1,54 +1,359 @@
-use std::{env, error::Error, fs};
+use std::{
+    cell::Cell,
+    env,
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+pub mod args;
+pub mod fold;
+pub mod fuzzy;
+pub mod json;
+pub mod matcher;
+pub mod output;
+pub mod parallel;
+pub mod reader;
+pub mod replace;
+pub mod walk;
+
+pub use args::ConfigError;
+pub use fold::{FoldOptions, FoldedQuery, Normalization};
+pub use fuzzy::{FuzzyMatcher, FuzzyOptions};
+pub use matcher::{Matcher, QueryMode, Span};
+use output::{OutputOptions, Printer};
+use replace::RewriteOptions;
+use serde::Serialize;
+use walk::WalkOptions;
 
 //adding documentation comments to the library's public API
 /// Config struct
 ///  
 pub struct Config {
-    query: String,
-    file_path: String,
+    patterns: Vec<String>,
+    paths: Vec<String>,
     ignore_case: bool,
+    mode: QueryMode,
+    walk: WalkOptions,
+    output: OutputOptions,
+    mmap: bool,
+    jobs: usize,
+    // set for `--in-place` and `--dry-run`: rewrite files instead of printing lines
+    rewrite: Option<RewriteOptions>,
+    // set for `--fuzzy`: rank lines by edit distance instead of matching exactly
+    fuzzy: Option<FuzzyOptions>,
 }
 
 impl Config {
-    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
-        args.next(); // skip the first argument which is the program name
-
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
-        let file_path = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file path"),
-        };
+    /// Parse the command line, see [`args::usage`] for the options.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::Help`] or [`ConfigError::Version`] when those
+    /// were asked for, and another [`ConfigError`] if the arguments are wrong.
+    pub fn build(args: impl Iterator<Item = String>) -> Result<Config, ConfigError> {
         let ignore_case = env::var("IGNORE_CASE").is_ok();
-        Ok(Config {
-            query,
-            file_path,
-            ignore_case,
-        })
+        args::parse(args, ignore_case)
+    }
+}
+
+/// The path that stands for standard input.
+pub const STDIN: &str = "-";
+
+/// Returned by [`run`] when some files or directories couldn't be searched.
+/// Each of them was already reported on stderr, and everything else was
+/// searched, like `grep -r` does.
+#[derive(Debug)]
+pub struct FileErrors(pub usize);
+
+impl fmt::Display for FileErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} file(s) couldn't be searched", self.0)
+    }
+}
+
+impl Error for FileErrors {}
+
+// counts the files that couldn't be searched after telling which and why
+#[derive(Default)]
+struct Errors(Cell<usize>);
+
+impl Errors {
+    fn report(&self, e: impl fmt::Display) {
+        eprintln!("minigrep: {e}");
+        self.0.set(self.0.get() + 1);
+    }
+
+    fn file(&self, file: &Path, e: io::Error) {
+        self.report(format_args!("{}: {e}", file.display()));
+    }
+
+    // keeps going unless it is our output that failed
+    fn file_or_stop(&self, file: &Path, e: io::Error) -> io::Result<()> {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            return Err(e);
+        }
+        self.file(file, e);
+        Ok(())
+    }
+
+    fn done<T>(self, result: T) -> Result<T, Box<dyn Error>> {
+        match self.0.get() {
+            0 => Ok(result),
+            n => Err(Box::new(FileErrors(n))),
+        }
     }
 }
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.file_path)?;
-    if !config.ignore_case {
-        for lines in search(&config.query, &contents) {
-            println!("{lines}")
+/// Search everything the config asks for and print the results.
+///
+/// Returns whether any line was selected, which decides grep's exit code.
+///
+/// # Errors
+///
+/// Files that can't be read are reported on stderr and skipped, and
+/// [`FileErrors`] is returned at the end. Any other error stops the search.
+pub fn run(config: Config) -> Result<bool, Box<dyn Error>> {
+    let started = Instant::now();
+    let errors = Errors::default();
+    let files = walk::collect_files(&config.paths, &config.walk, |e| errors.report(e))?;
+    let mut options = config.output.clone();
+    // like grep, prefix every line with its file once more than one file can match
+    options.with_filename =
+        config.paths.len() > 1 || config.paths.iter().any(|p| Path::new(p).is_dir());
+    // only colour the output for a human, not when it is piped somewhere
+    options.color = io::stdout().is_terminal() && !options.json;
+    if let Some(fuzzy) = config.fuzzy {
+        let mut stdout = io::stdout().lock();
+        let found = search_files_fuzzy(&files, &config, fuzzy, &options, &mut stdout, &errors)?;
+        return errors.done(found > 0);
+    }
+    let matcher = Matcher::with_patterns(&config.patterns, config.mode, config.ignore_case)?;
+    let mut stdout = io::stdout().lock();
+
+    if let (Some(rewrite), Some(template)) = (config.rewrite, &options.replace) {
+        let mut changed = 0;
+        for file in &files {
+            if file == Path::new(STDIN) {
+                return Err("standard input can't be rewritten in place".into());
+            }
+            let rewritten = match walk::is_binary(file) {
+                Ok(true) => Ok(0),
+                Ok(false) => replace::rewrite_file(file, &matcher, template, rewrite, &mut stdout),
+                Err(e) => Err(e),
+            };
+            match rewritten {
+                Ok(n) => changed += n,
+                Err(e) => errors.file_or_stop(file, e)?,
+            }
         }
+        return errors.done(changed > 0);
+    }
+
+    let selected = if config.jobs == 1 || files.len() == 1 {
+        // nothing to gain from threads, so print straight to stdout
+        let mut selected = 0;
+        for file in &files {
+            match search_file(file, &matcher, &options, config.mmap, &mut stdout) {
+                Ok(n) => selected += n,
+                Err(e) => errors.file_or_stop(file, e)?,
+            }
+        }
+        selected
     } else {
-        for lines in search_case_insensitive(&config.query, &contents) {
-            println!("{lines}")
+        parallel::search_files(
+            &files,
+            config.jobs,
+            |file, mut out| search_file(file, &matcher, &options, config.mmap, &mut out),
+            &mut stdout,
+            |file, e| errors.file(file, e),
+        )?
+    };
+    if options.json {
+        let stats = json::SummaryStats {
+            searches: files.len(),
+            matched_lines: selected,
+            elapsed_secs: started.elapsed().as_secs_f64(),
+        };
+        json::write_event(&mut stdout, &json::Event::Summary { stats })?;
+    }
+    errors.done(selected > 0)
+}
+
+// `--fuzzy` needs every close line of every file before it can rank them,
+// so unlike the other modes it collects the lines first and prints at the end
+fn search_files_fuzzy<W: Write>(
+    files: &[PathBuf],
+    config: &Config,
+    fuzzy: FuzzyOptions,
+    options: &OutputOptions,
+    out: &mut W,
+    errors: &Errors,
+) -> io::Result<usize> {
+    let matchers: Vec<FuzzyMatcher> = config
+        .patterns
+        .iter()
+        .map(|pattern| FuzzyMatcher::new(pattern, config.ignore_case))
+        .collect();
+    // (distance, file, line number, line)
+    let mut found = Vec::new();
+    for file in files {
+        let mut collect = |line_number, _, line: &str| {
+            let distance = matchers
+                .iter()
+                .filter_map(|matcher| matcher.find(line, fuzzy.max_distance))
+                .min();
+            if let Some(distance) = distance {
+                found.push((distance, file, line_number, line.to_string()));
+            }
+            Ok(true)
+        };
+        let read = if file == Path::new(STDIN) {
+            reader::read_lines(io::stdin().lock(), &mut collect)
+        } else {
+            match walk::is_binary(file) {
+                Ok(true) => Ok(()),
+                Ok(false) => File::open(file)
+                    .and_then(|opened| reader::file_lines(opened, config.mmap, &mut collect)),
+                Err(e) => Err(e),
+            }
+        };
+        // nothing is written yet, so every error is the file's
+        if let Err(e) = read {
+            errors.file(file, e);
         }
     }
-    Ok(())
+
+    // stable, so equally close lines stay in file order
+    found.sort_by_key(|(distance, ..)| *distance);
+    found.truncate(fuzzy.best.unwrap_or(found.len()));
+    for (_, file, line_number, line) in &found {
+        if options.with_filename {
+            if file.as_path() == Path::new(STDIN) {
+                write!(out, "(standard input):")?;
+            } else {
+                write!(out, "{}:", file.display())?;
+            }
+        }
+        if options.line_number {
+            write!(out, "{line_number}:")?;
+        }
+        writeln!(out, "{line}")?;
+    }
+    Ok(found.len())
+}
+
+/// Search one file and write the results for it to `out`, returning the
+/// number of selected lines. Binary files are skipped.
+///
+/// The path [`STDIN`] searches standard input instead.
+pub fn search_file<W: Write>(
+    file: &Path,
+    matcher: &Matcher,
+    options: &OutputOptions,
+    mmap: bool,
+    out: &mut W,
+) -> io::Result<usize> {
+    if file == Path::new(STDIN) {
+        let stdin = io::stdin().lock();
+        return search_reader(stdin, "(standard input)", matcher, options, out);
+    }
+    if walk::is_binary(file)? {
+        return Ok(0);
+    }
+    let path = file.display().to_string();
+    let mut printer = Printer::new(out, matcher, options, &path);
+    // streamed line by line, so even huge files are searched in constant memory
+    reader::file_lines(File::open(file)?, mmap, |number, offset, line| {
+        printer.line(number, offset, line)
+    })?;
+    printer.finish()
+}
+
+/// Like [`search_file`] for any reader, `name` is printed in place of a path.
+pub fn search_reader<R: BufRead, W: Write>(
+    mut reader: R,
+    name: &str,
+    matcher: &Matcher,
+    options: &OutputOptions,
+    out: &mut W,
+) -> io::Result<usize> {
+    // peek at what is already buffered to skip binary input, without consuming it
+    if reader.fill_buf()?.contains(&0) {
+        return Ok(0);
+    }
+    let mut printer = Printer::new(out, matcher, options, name);
+    reader::read_lines(reader, |number, offset, line| {
+        printer.line(number, offset, line)
+    })?;
+    printer.finish()
+}
+
+/// A line that matched, with its position in the searched text.
+///
+/// Serializes to the `data` of a `--json` match event, see [`json`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Match<'a> {
+    /// 1-based line number
+    pub line_number: usize,
+    /// byte offset of the start of the line
+    pub byte_offset: usize,
+    /// the line without its line terminator
+    pub line: &'a str,
+    /// where in the line the query matched
+    pub spans: Vec<Span>,
+}
+
+// (line number, byte offset, line) for every line, handling `\n` and `\r\n` like `str::lines`
+fn lines(contents: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    contents
+        .split_inclusive('\n')
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len();
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            Some((start, line.strip_suffix('\r').unwrap_or(line)))
+        })
+        .enumerate()
+        .map(|(i, (offset, line))| (i + 1, offset, line))
+}
+
+/// Like [`search`], but with any [`QueryMode`] and returning where each line matched.
+///
+/// # Examples
+///
+/// ```
+/// use minigrep::{search_matches, Matcher, QueryMode, Span};
+/// let matcher = Matcher::new(r"fa\w+", QueryMode::Regex, false).unwrap();
+/// let contents = "\
+/// Rust:
+/// safe, fast, productive.";
+/// let results = search_matches(&matcher, contents);
+/// assert_eq!(results[0].line, "safe, fast, productive.");
+/// assert_eq!(results[0].line_number, 2);
+/// assert_eq!(results[0].byte_offset, 6);
+/// assert_eq!(results[0].spans, [Span { start: 6, end: 10 }]);
+/// ```
+pub fn search_matches<'a>(matcher: &Matcher, contents: &'a str) -> Vec<Match<'a>> {
+    lines(contents)
+        .filter(|(_, _, line)| matcher.is_match(line))
+        .map(|(line_number, byte_offset, line)| Match {
+            line_number,
+            byte_offset,
+            line,
+            spans: matcher.find_spans(line),
+        })
+        .collect()
 }
 
 //adding documentation comments to the library's public API
 /// search function
-/// 
+///
 /// # Examples
-/// 
+///
 /// ```
 /// use minigrep::search;
 /// let query = "duct";
@@ -59,9 +364,9 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
 /// Duct tape.";
 /// assert_eq!(vec!["safe, fast, productive."], search(query, contents))
 /// ```
-/// 
+///
 /// # Panics
-/// 
+///
 /// ```
 /// use minigrep::search;
 /// let query = "duct";
@@ -72,9 +377,9 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
 /// Duct tape.";
 /// assert_eq!(vec!["safe, fast, productive."], search(query, contents))
 /// ```
-/// 
+///
 /// # Errors
-/// 
+///
 /// ```
 /// use minigrep::search;
 /// let query = "duct";
@@ -85,9 +390,9 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
 /// Duct tape.";
 /// assert_eq!(vec!["safe, fast, productive."], search(query, contents))
 /// ```
-/// 
+///
 /// # Safety
-/// 
+///
 /// ```
 /// use minigrep::search;
 /// let query = "duct";
@@ -112,19 +417,95 @@ pub fn search<'a>(query: &'a str, contents: &'a str) -> Vec<&'a str> {
         .collect::<Vec<_>>()
 }
 
-pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let query = query.to_lowercase();
-    let mut results = Vec::new();
-
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query) {
-            results.push(line);
-        }
-    }
+/// A line found by [`search_fuzzy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzyMatch<'a> {
+    /// 1-based line number
+    pub line_number: usize,
+    pub line: &'a str,
+    /// edits needed to turn the query into part of the line
+    pub distance: usize,
+}
 
+/// Like [`search`], but also finds lines where the query occurs with up to
+/// `max_distance` typos (inserted, deleted or substituted characters).
+///
+/// The lines are ranked by their distance, closest first; lines that are
+/// equally close keep their order.
+///
+/// # Examples
+///
+/// ```
+/// use minigrep::search_fuzzy;
+/// let contents = "\
+/// connection refused
+/// conection reset
+/// timeout";
+/// let results = search_fuzzy("connection", contents, 1);
+/// assert_eq!(results.len(), 2);
+/// assert_eq!(results[1].line, "conection reset");
+/// assert_eq!(results[1].distance, 1);
+/// ```
+pub fn search_fuzzy<'a>(
+    query: &str,
+    contents: &'a str,
+    max_distance: usize,
+) -> Vec<FuzzyMatch<'a>> {
+    let matcher = FuzzyMatcher::new(query, false);
+    let mut results: Vec<FuzzyMatch> = contents
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let distance = matcher.find(line, max_distance)?;
+            Some(FuzzyMatch {
+                line_number: i + 1,
+                line,
+                distance,
+            })
+        })
+        .collect();
+    results.sort_by_key(|found| found.distance);
     results
 }
 
+/// Like [`search`], ignoring case.
+///
+/// Uses full Unicode case folding, so "STRASSE" finds "straße", without
+/// allocating a lowercased copy of every line.
+pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    search_case_insensitive_with(query, contents, FoldOptions::default())
+}
+
+/// Like [`search_case_insensitive`], with a choice of normalization and
+/// Turkic folding.
+///
+/// # Examples
+///
+/// ```
+/// use minigrep::{search_case_insensitive_with, FoldOptions, Normalization};
+/// let options = FoldOptions {
+///     normalization: Normalization::Nfc,
+///     turkic: false,
+/// };
+/// // "é" written as "e" and a combining accent
+/// let contents = "Cafe\u{301} au lait\nTea";
+/// assert_eq!(
+///     search_case_insensitive_with("CAFÉ", contents, options),
+///     ["Cafe\u{301} au lait"]
+/// );
+/// ```
+pub fn search_case_insensitive_with<'a>(
+    query: &str,
+    contents: &'a str,
+    options: FoldOptions,
+) -> Vec<&'a str> {
+    let query = FoldedQuery::new(query, options);
+    contents
+        .lines()
+        .filter(|line| query.is_match(line))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -153,4 +534,167 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
+
+    #[test]
+    fn case_insensitive_non_ascii() {
+        let contents = "\
+Grüße aus der Straße.
+GRÜSSE AUS DER STRASSE.
+İstanbul'da kırk kişi.
+Ἀρχὴ ΤΟΥ ΛΟΓΟΥ.";
+
+        assert_eq!(
+            search_case_insensitive("grüsse", contents),
+            ["Grüße aus der Straße.", "GRÜSSE AUS DER STRASSE."]
+        );
+        assert_eq!(
+            search_case_insensitive("KIRK", contents),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            search_case_insensitive("του λογου", contents),
+            ["Ἀρχὴ ΤΟΥ ΛΟΓΟΥ."]
+        );
+
+        let turkic = FoldOptions {
+            turkic: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            search_case_insensitive_with("KIRK KİŞİ", contents, turkic),
+            ["İstanbul'da kırk kişi."]
+        );
+    }
+
+    #[test]
+    fn fuzzy_results_are_ranked() {
+        let contents = "\
+Pick thre.
+Pick three.
+Pikc thee.
+Duct tape.";
+
+        let results = search_fuzzy("three", contents, 2);
+        let ranked: Vec<_> = results
+            .iter()
+            .map(|m| (m.line_number, m.distance))
+            .collect();
+        assert_eq!(ranked, [(2, 0), (1, 1), (3, 1)]);
+        assert!(search_fuzzy("three", contents, 0).len() == 1);
+    }
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.into_iter()
+    }
+
+    #[test]
+    fn build_with_paths_and_globs() {
+        let config = Config::build(args(&[
+            "minigrep",
+            "to",
+            "src",
+            "--include=*.rs",
+            "poem.txt",
+            "--no-ignore",
+        ]))
+        .unwrap();
+
+        assert_eq!(config.paths, ["src", "poem.txt"]);
+        assert_eq!(config.walk.include, ["*.rs"]);
+        assert!(config.walk.exclude.is_empty());
+        assert!(config.walk.no_ignore);
+    }
+
+    #[test]
+    fn build_with_query_mode() {
+        let config = Config::build(args(&["minigrep", "t.o", "--regex", "poem.txt"])).unwrap();
+        assert_eq!(config.mode, QueryMode::Regex);
+        assert_eq!(config.paths, ["poem.txt"]);
+    }
+
+    #[test]
+    fn search_matches_whole_word() {
+        let matcher = Matcher::new("rust", QueryMode::WholeWord, true).unwrap();
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+
+        let results = search_matches(&matcher, contents);
+        assert_eq!(
+            results,
+            [Match {
+                line_number: 1,
+                byte_offset: 0,
+                line: "Rust:",
+                spans: vec![Span { start: 0, end: 4 }]
+            }]
+        );
+    }
+
+    #[test]
+    fn match_offsets_with_crlf() {
+        let matcher = Matcher::new("b", QueryMode::Literal, false).unwrap();
+        let results = search_matches(&matcher, "a\r\nb\r\nc b\n");
+
+        let positions: Vec<_> = results
+            .iter()
+            .map(|m| (m.line_number, m.byte_offset, m.line))
+            .collect();
+        assert_eq!(positions, [(2, 3, "b"), (3, 6, "c b")]);
+    }
+
+    #[test]
+    fn build_with_output_options() {
+        let config = Config::build(args(&[
+            "minigrep", "to", "-n", "-C", "2", "-m", "5", "-v", "poem.txt",
+        ]))
+        .unwrap();
+        assert_eq!(config.patterns, ["to"]);
+        assert!(config.output.line_number);
+        assert!(config.output.invert_match);
+        assert_eq!(config.output.before_context, 2);
+        assert_eq!(config.output.after_context, 2);
+        assert_eq!(config.output.max_count, Some(5));
+        assert_eq!(config.paths, ["poem.txt"]);
+
+        assert!(Config::build(args(&["minigrep", "to", "-A", "x", "poem.txt"])).is_err());
+    }
+
+    #[test]
+    fn build_without_path_reads_stdin() {
+        let config = Config::build(args(&["minigrep", "to", "--include=*.rs"])).unwrap();
+        assert_eq!(config.paths, [STDIN]);
+    }
+
+    #[test]
+    fn search_reader_with_name() {
+        let matcher = Matcher::new("duct", QueryMode::Literal, false).unwrap();
+        let options = OutputOptions {
+            with_filename: true,
+            line_number: true,
+            ..Default::default()
+        };
+        let input = "Rust:\nsafe, fast, productive.\nDuct tape.\n".as_bytes();
+        let name = "(standard input)";
+        let mut out = Vec::new();
+
+        let selected = search_reader(input, name, &matcher, &options, &mut out).unwrap();
+        assert_eq!(selected, 1);
+        assert_eq!(out, b"(standard input):2:safe, fast, productive.\n");
+    }
+
+    #[test]
+    fn search_reader_skips_binary_input() {
+        let matcher = Matcher::new("ELF", QueryMode::Literal, false).unwrap();
+        let mut out = Vec::new();
+        let input = &b"\x7fELF\0\0"[..];
+
+        let selected =
+            search_reader(input, "-", &matcher, &OutputOptions::default(), &mut out).unwrap();
+        assert_eq!(selected, 0);
+        assert!(out.is_empty());
+    }
 }
@@ -1,19 +1,43 @@
-
 // convention is to use struct directly and use the crate or module name for function
-use minigrep::Config; 
+use minigrep::{Config, ConfigError, FileErrors};
 use std::env;
+use std::error::Error;
+use std::io;
 use std::process;
 
 fn main() {
     // let args: Vec<String> = env::args().collect();
-    let config = Config::build(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {err}");
-        process::exit(1);
+    let config = Config::build(env::args()).unwrap_or_else(|err| match err {
+        // --help and --version print their text and stop successfully
+        ConfigError::Help | ConfigError::Version => {
+            println!("{err}");
+            process::exit(0);
+        }
+        err => {
+            eprintln!("Problem parsing arguments: {err}");
+            eprintln!("Try 'minigrep --help' for more information.");
+            process::exit(2);
+        }
     });
 
+    // exit codes follow grep: 0 if a line was selected, 1 if none was, 2 on error
     // minigrep is top level path like std so that we don't need to use `use minigerep`
-    if let Err(e) = minigrep::run(config) {
-        eprintln!("Application error:{e}");
-        process::exit(1)
+    match minigrep::run(config) {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        // whoever read our output went away (e.g. `minigrep ... | head`),
+        // there is nobody left to tell, so stop quietly
+        Err(e) if is_broken_pipe(e.as_ref()) => process::exit(0),
+        // each was reported as it happened, and the rest was searched
+        Err(e) if e.is::<FileErrors>() => process::exit(2),
+        Err(e) => {
+            eprintln!("Application error:{e}");
+            process::exit(2)
+        }
     }
 }
+
+fn is_broken_pipe(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
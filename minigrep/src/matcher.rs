@@ -0,0 +1,332 @@
+// Finding where a query matches inside a line.
+//
+// Every query mode is compiled down to a single `regex::Regex`, so a literal
+// query is simply an escaped regex. That keeps one code path for spans,
+// case-insensitivity and highlighting. The exception is ignoring the case of
+// literal and fixed-string patterns: the regex crate only knows simple case
+// folding, so "STRASSE" wouldn't find "straße", and those patterns are
+// searched as `FoldedQuery`s instead.
+use crate::fold::{FoldOptions, FoldedQuery};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// How the query given on the command line is interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryMode {
+    /// the query is a plain substring
+    #[default]
+    Literal,
+    /// the query is a regular expression
+    Regex,
+    /// the query is a plain string that must match a whole word
+    WholeWord,
+    /// the query is a newline separated list of plain strings, any of which may match
+    FixedStrings,
+}
+
+/// Byte range of a match inside a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A compiled query.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    kind: Kind,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Regex(Regex),
+    // any of these, with full case folding
+    Folded(Vec<FoldedQuery>),
+}
+
+impl Matcher {
+    /// Compile `query` according to `mode`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query is not a valid regular expression in
+    /// [`QueryMode::Regex`].
+    pub fn new(query: &str, mode: QueryMode, ignore_case: bool) -> Result<Matcher, regex::Error> {
+        Matcher::with_patterns(&[query], mode, ignore_case)
+    }
+
+    /// Compile several queries (`-e` given more than once), a line matches
+    /// if any of them matches.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a query is not a valid regular expression in
+    /// [`QueryMode::Regex`].
+    pub fn with_patterns<S: AsRef<str>>(
+        queries: &[S],
+        mode: QueryMode,
+        ignore_case: bool,
+    ) -> Result<Matcher, regex::Error> {
+        if ignore_case && matches!(mode, QueryMode::Literal | QueryMode::FixedStrings) {
+            return Ok(Matcher::folded(queries, mode));
+        }
+        let pattern = queries
+            .iter()
+            .map(|query| {
+                let query = query.as_ref();
+                match mode {
+                    QueryMode::Literal => regex::escape(query),
+                    QueryMode::Regex => format!("(?:{query})"),
+                    QueryMode::WholeWord => format!(r"\b(?:{})\b", regex::escape(query)),
+                    QueryMode::FixedStrings => query
+                        .lines()
+                        .filter(|pattern| !pattern.is_empty())
+                        .map(regex::escape)
+                        .collect::<Vec<_>>()
+                        .join("|"),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("|");
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()?;
+        Ok(Matcher {
+            kind: Kind::Regex(regex),
+        })
+    }
+
+    fn folded<S: AsRef<str>>(queries: &[S], mode: QueryMode) -> Matcher {
+        let mut folded = Vec::new();
+        for query in queries {
+            let query = query.as_ref();
+            let mut strings: Vec<&str> = match mode {
+                QueryMode::FixedStrings => query.lines().filter(|s| !s.is_empty()).collect(),
+                _ => vec![query],
+            };
+            // like the empty regex, an empty list matches every line
+            if strings.is_empty() {
+                strings.push("");
+            }
+            for string in strings {
+                folded.push(FoldedQuery::new(string, FoldOptions::default()));
+            }
+        }
+        Matcher {
+            kind: Kind::Folded(folded),
+        }
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        match &self.kind {
+            Kind::Regex(regex) => regex.is_match(line),
+            Kind::Folded(queries) => queries.iter().any(|query| query.is_match(line)),
+        }
+    }
+
+    /// Every non-overlapping match in `line`, from left to right.
+    pub fn find_spans(&self, line: &str) -> Vec<Span> {
+        let queries = match &self.kind {
+            Kind::Regex(regex) => {
+                return regex
+                    .find_iter(line)
+                    // an empty match (e.g. regex `x*`) still selects the line but has nothing to highlight
+                    .filter(|m| !m.is_empty())
+                    .map(|m| Span {
+                        start: m.start(),
+                        end: m.end(),
+                    })
+                    .collect();
+            }
+            Kind::Folded(queries) => queries,
+        };
+        let mut found: Vec<Span> = queries
+            .iter()
+            .flat_map(|query| query.find_spans(line))
+            .collect();
+        // leftmost first, and the earlier pattern on a tie, like an alternation
+        found.sort_by_key(|span| span.start);
+        let mut spans: Vec<Span> = Vec::with_capacity(found.len());
+        for span in found {
+            if spans.last().is_none_or(|last| last.end <= span.start) {
+                spans.push(span);
+            }
+        }
+        spans
+    }
+
+    /// Replace every match in `line` with `template`, which may refer to
+    /// capture groups as `$1` or `${name}`; `$$` is a literal `$`.
+    ///
+    /// Borrows `line` when nothing matched.
+    pub fn replace_all<'t>(&self, line: &'t str, template: &str) -> Cow<'t, str> {
+        if let Kind::Regex(regex) = &self.kind {
+            return regex.replace_all(line, template);
+        }
+        let spans = self.find_spans(line);
+        if spans.is_empty() {
+            return Cow::Borrowed(line);
+        }
+        let mut replaced = String::with_capacity(line.len());
+        let mut last = 0;
+        for span in spans {
+            replaced.push_str(&line[last..span.start]);
+            expand(template, &line[span.start..span.end], &mut replaced);
+            last = span.end;
+        }
+        replaced.push_str(&line[last..]);
+        Cow::Owned(replaced)
+    }
+}
+
+// `template` for a match without capture groups, the way the regex crate
+// expands it: `$0` is the match and any other group is empty
+fn expand(template: &str, matched: &str, out: &mut String) {
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", rest),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        // not a reference, so a plain `$`
+        if name.is_empty() {
+            out.push('$');
+            continue;
+        }
+        if name == "0" {
+            out.push_str(matched);
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+}
+
+// grep's default colour for matched text: bold red
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// Wrap every span of `line` in ANSI colour codes.
+pub fn highlight(line: &str, spans: &[Span]) -> String {
+    let mut out = String::with_capacity(line.len() + spans.len() * 11);
+    let mut last = 0;
+    for span in spans {
+        out.push_str(&line[last..span.start]);
+        out.push_str(MATCH_COLOR);
+        out.push_str(&line[span.start..span.end]);
+        out.push_str(RESET);
+        last = span.end;
+    }
+    out.push_str(&line[last..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(matcher: &Matcher, line: &str) -> Vec<(usize, usize)> {
+        matcher
+            .find_spans(line)
+            .iter()
+            .map(|span| (span.start, span.end))
+            .collect()
+    }
+
+    #[test]
+    fn literal_escapes_regex_syntax() {
+        let matcher = Matcher::new("a.b", QueryMode::Literal, false).unwrap();
+        assert!(matcher.is_match("x a.b y"));
+        assert!(!matcher.is_match("axb"));
+        assert_eq!(spans(&matcher, "a.b a.b"), [(0, 3), (4, 7)]);
+    }
+
+    #[test]
+    fn regex_mode() {
+        let matcher = Matcher::new(r"\d+", QueryMode::Regex, false).unwrap();
+        assert_eq!(spans(&matcher, "take 12 or 345"), [(5, 7), (11, 14)]);
+        assert!(Matcher::new("(", QueryMode::Regex, false).is_err());
+    }
+
+    #[test]
+    fn whole_word_mode() {
+        let matcher = Matcher::new("rust", QueryMode::WholeWord, true).unwrap();
+        assert!(matcher.is_match("Rust: safe"));
+        assert!(!matcher.is_match("Trust me."));
+    }
+
+    #[test]
+    fn fixed_strings_mode() {
+        let matcher = Matcher::new("fast\nthree", QueryMode::FixedStrings, false).unwrap();
+        assert_eq!(
+            spans(&matcher, "fast, fast, three"),
+            [(0, 4), (6, 10), (12, 17)]
+        );
+        assert!(!matcher.is_match("Duct tape."));
+    }
+
+    #[test]
+    fn several_patterns() {
+        let matcher = Matcher::with_patterns(&["fa.t", "^Pick"], QueryMode::Regex, false).unwrap();
+        assert!(matcher.is_match("safe, fast, productive."));
+        assert!(matcher.is_match("Pick three."));
+        assert!(!matcher.is_match("Duct tape. Pick"));
+    }
+
+    #[test]
+    fn ignores_case_with_full_folding() {
+        let matcher = Matcher::new("STRASSE", QueryMode::Literal, true).unwrap();
+        assert!(matcher.is_match("Die Straße ist lang."));
+        assert_eq!(spans(&matcher, "Die Straße ist lang."), [(4, 11)]);
+        assert_eq!(matcher.replace_all("Straße", "<$0>"), "<Straße>");
+        assert_eq!(matcher.replace_all("Straße", "$$1 ${1}x"), "$1 x");
+
+        let matcher = Matcher::new("ss\nstraße", QueryMode::FixedStrings, true).unwrap();
+        // the longer pattern starts first
+        assert_eq!(spans(&matcher, "STRASSE, MASS"), [(0, 7), (11, 13)]);
+        assert!(!matcher.is_match("street"));
+
+        // the regex modes keep the regex crate's simple folding
+        let matcher = Matcher::new("STRASSE", QueryMode::Regex, true).unwrap();
+        assert!(!matcher.is_match("Straße"));
+    }
+
+    #[test]
+    fn highlights_spans() {
+        let matcher = Matcher::new("duct", QueryMode::Literal, false).unwrap();
+        let line = "safe, fast, productive.";
+        assert_eq!(
+            highlight(line, &matcher.find_spans(line)),
+            "safe, fast, pro\x1b[1;31mduct\x1b[0mive."
+        );
+    }
+
+    #[test]
+    fn replaces_with_capture_groups() {
+        let matcher = Matcher::new(r"(\w+)@(?P<host>\w+)", QueryMode::Regex, false).unwrap();
+        assert_eq!(
+            matcher.replace_all("mail ann@home or bob@work", "${host}:$1"),
+            "mail home:ann or work:bob"
+        );
+        // nothing matched, nothing allocated
+        assert!(matches!(
+            matcher.replace_all("no mail", "$1"),
+            Cow::Borrowed(_)
+        ));
+    }
+}
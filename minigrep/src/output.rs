@@ -0,0 +1,427 @@
+// grep-style printing of search results.
+//
+// The `Printer` is fed one line at a time, so it never needs the whole file:
+// it keeps a small ring buffer for `-B` context, counts down `-A` context and
+// prints `--` between groups of lines that are not adjacent.
+use crate::{
+    json::{self, Event, FileStats},
+    matcher, Match, Matcher,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    time::Instant,
+};
+
+/// Which lines are printed, and how.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// `-n`: prefix each line with its line number
+    pub line_number: bool,
+    /// `-B`: lines of context printed before each match
+    pub before_context: usize,
+    /// `-A`: lines of context printed after each match
+    pub after_context: usize,
+    /// `-c`: only print the number of selected lines per file
+    pub count: bool,
+    /// `-l`: only print the names of files with a selected line
+    pub files_with_matches: bool,
+    /// `-L`: only print the names of files without a selected line
+    pub files_without_match: bool,
+    /// `-v`: select the lines that do not match
+    pub invert_match: bool,
+    /// `-m`: stop reading a file after this many selected lines
+    pub max_count: Option<usize>,
+    /// prefix each line with the file name
+    pub with_filename: bool,
+    /// highlight matches with ANSI colours
+    pub color: bool,
+    /// `--replace`: print selected lines with every match replaced by this template
+    pub replace: Option<String>,
+    /// `--json`: print JSON Lines events instead of text
+    pub json: bool,
+}
+
+impl OutputOptions {
+    // only file names or counts are printed, never the lines themselves
+    fn summary_only(&self) -> bool {
+        self.count || self.files_with_matches || self.files_without_match
+    }
+}
+
+/// Prints the results of searching one file.
+pub struct Printer<'a, W: Write> {
+    out: W,
+    matcher: &'a Matcher,
+    options: &'a OutputOptions,
+    path: &'a str,
+    // (line number, byte offset, line) of the last `before_context` lines not printed yet
+    before: VecDeque<(usize, usize, String)>,
+    // how many more lines are printed as after-context
+    after_left: usize,
+    last_printed: Option<usize>,
+    selected: usize,
+    // matches inside the printed lines, for the `--json` end event
+    matches: usize,
+    started: Instant,
+}
+
+impl<'a, W: Write> Printer<'a, W> {
+    pub fn new(out: W, matcher: &'a Matcher, options: &'a OutputOptions, path: &'a str) -> Self {
+        Printer {
+            out,
+            matcher,
+            options,
+            path,
+            before: VecDeque::with_capacity(options.before_context),
+            after_left: 0,
+            last_printed: None,
+            selected: 0,
+            matches: 0,
+            started: Instant::now(),
+        }
+    }
+
+    // reached -m, only trailing context may still be printed
+    fn is_full(&self) -> bool {
+        self.options
+            .max_count
+            .is_some_and(|max| self.selected >= max)
+    }
+
+    /// Feed the next line of the file.
+    ///
+    /// Returns `false` once nothing more will be printed for this file, so the
+    /// caller can stop reading.
+    pub fn line(&mut self, line_number: usize, byte_offset: usize, line: &str) -> io::Result<bool> {
+        if self.is_full() {
+            if self.after_left == 0 || self.options.summary_only() {
+                return Ok(false);
+            }
+            self.after_left -= 1;
+            self.print_line(&plain(line_number, byte_offset, line), '-')?;
+            return Ok(true);
+        }
+
+        let is_match = self.matcher.is_match(line);
+        if is_match == self.options.invert_match {
+            self.context_line(line_number, byte_offset, line)?;
+            return Ok(true);
+        }
+
+        self.selected += 1;
+        if self.options.files_with_matches {
+            // one selected line is enough to know the answer
+            return Ok(false);
+        }
+        if self.options.summary_only() {
+            return Ok(true);
+        }
+
+        while let Some((number, offset, before)) = self.before.pop_front() {
+            self.print_line(&plain(number, offset, &before), '-')?;
+        }
+        if let Some(template) = &self.options.replace {
+            // the matches are gone after replacing, so there is nothing to highlight
+            let replaced = self.matcher.replace_all(line, template);
+            self.print_line(&plain(line_number, byte_offset, &replaced), ':')?;
+        } else {
+            let spans = if self.options.invert_match {
+                Vec::new()
+            } else {
+                self.matcher.find_spans(line)
+            };
+            let found = Match {
+                line_number,
+                byte_offset,
+                line,
+                spans,
+            };
+            self.print_line(&found, ':')?;
+        }
+        self.after_left = self.options.after_context;
+        Ok(true)
+    }
+
+    fn context_line(
+        &mut self,
+        line_number: usize,
+        byte_offset: usize,
+        line: &str,
+    ) -> io::Result<()> {
+        if self.options.summary_only() {
+            return Ok(());
+        }
+        if self.after_left > 0 {
+            self.after_left -= 1;
+            return self.print_line(&plain(line_number, byte_offset, line), '-');
+        }
+        if self.options.before_context > 0 {
+            if self.before.len() == self.options.before_context {
+                self.before.pop_front();
+            }
+            self.before
+                .push_back((line_number, byte_offset, line.to_string()));
+        }
+        Ok(())
+    }
+
+    // `separator` is ':' for selected lines and '-' for context, like grep
+    fn print_line(&mut self, line: &Match, separator: char) -> io::Result<()> {
+        if self.options.json {
+            return self.print_event(line, separator == ':');
+        }
+        let has_context = self.options.before_context > 0 || self.options.after_context > 0;
+        if let Some(last) = self.last_printed {
+            if has_context && line.line_number > last + 1 {
+                writeln!(self.out, "--")?;
+            }
+        }
+        self.last_printed = Some(line.line_number);
+
+        if self.options.with_filename {
+            write!(self.out, "{}{separator}", self.path)?;
+        }
+        if self.options.line_number {
+            write!(self.out, "{}{separator}", line.line_number)?;
+        }
+        if self.options.color && !line.spans.is_empty() {
+            writeln!(self.out, "{}", matcher::highlight(line.line, &line.spans))
+        } else {
+            writeln!(self.out, "{}", line.line)
+        }
+    }
+
+    fn print_event(&mut self, line: &Match, selected: bool) -> io::Result<()> {
+        if self.last_printed.is_none() {
+            json::write_event(&mut self.out, &Event::Begin { path: self.path })?;
+        }
+        self.last_printed = Some(line.line_number);
+        let event = if selected {
+            self.matches += line.spans.len();
+            Event::Match {
+                path: self.path,
+                line,
+            }
+        } else {
+            Event::Context {
+                path: self.path,
+                line,
+            }
+        };
+        json::write_event(&mut self.out, &event)
+    }
+
+    /// Print the per-file summary for `-c`, `-l` and `-L` and return the
+    /// number of selected lines.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if self.options.json {
+            if self.last_printed.is_some() {
+                let stats = FileStats {
+                    matched_lines: self.selected,
+                    matches: self.matches,
+                    elapsed_secs: self.started.elapsed().as_secs_f64(),
+                };
+                let end = Event::End {
+                    path: self.path,
+                    stats,
+                };
+                json::write_event(&mut self.out, &end)?;
+            }
+        } else if self.options.count {
+            if self.options.with_filename {
+                write!(self.out, "{}:", self.path)?;
+            }
+            writeln!(self.out, "{}", self.selected)?;
+        } else if (self.options.files_with_matches && self.selected > 0)
+            || (self.options.files_without_match && self.selected == 0)
+        {
+            writeln!(self.out, "{}", self.path)?;
+        }
+        Ok(self.selected)
+    }
+}
+
+// context and replaced lines are printed like a match without anything to highlight
+fn plain(line_number: usize, byte_offset: usize, line: &str) -> Match<'_> {
+    Match {
+        line_number,
+        byte_offset,
+        line,
+        spans: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueryMode;
+
+    const CONTENTS: &str = "\
+one
+two match
+three
+four
+five
+six match
+seven
+eight
+nine
+ten match";
+
+    fn print(query: &str, options: &OutputOptions) -> String {
+        let matcher = Matcher::new(query, QueryMode::Literal, false).unwrap();
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &matcher, options, "file.txt");
+        let mut offset = 0;
+        for (i, line) in CONTENTS.lines().enumerate() {
+            if !printer.line(i + 1, offset, line).unwrap() {
+                break;
+            }
+            offset += line.len() + 1;
+        }
+        printer.finish().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn line_numbers_and_file_names() {
+        let options = OutputOptions {
+            line_number: true,
+            with_filename: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            print("match", &options),
+            "file.txt:2:two match\nfile.txt:6:six match\nfile.txt:10:ten match\n"
+        );
+    }
+
+    #[test]
+    fn context_with_group_separators() {
+        let options = OutputOptions {
+            line_number: true,
+            before_context: 1,
+            after_context: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            print("match", &options),
+            "1-one\n2:two match\n3-three\n--\n5-five\n6:six match\n7-seven\n--\n9-nine\n10:ten match\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_context_is_printed_once() {
+        let options = OutputOptions {
+            before_context: 1,
+            after_context: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            print("match", &options),
+            "one\ntwo match\nthree\nfour\nfive\nsix match\nseven\neight\nnine\nten match\n"
+        );
+    }
+
+    #[test]
+    fn count_and_invert() {
+        let count = OutputOptions {
+            count: true,
+            ..Default::default()
+        };
+        assert_eq!(print("match", &count), "3\n");
+
+        let inverted = OutputOptions {
+            count: true,
+            invert_match: true,
+            ..count
+        };
+        assert_eq!(print("match", &inverted), "7\n");
+    }
+
+    #[test]
+    fn invert_prints_non_matching_lines() {
+        let options = OutputOptions {
+            invert_match: true,
+            ..Default::default()
+        };
+        assert_eq!(print("e", &options), "two match\nfour\nsix match\n");
+    }
+
+    #[test]
+    fn file_lists() {
+        let with = OutputOptions {
+            files_with_matches: true,
+            ..Default::default()
+        };
+        assert_eq!(print("match", &with), "file.txt\n");
+        assert_eq!(print("missing", &with), "");
+
+        let without = OutputOptions {
+            files_without_match: true,
+            ..Default::default()
+        };
+        assert_eq!(print("match", &without), "");
+        assert_eq!(print("missing", &without), "file.txt\n");
+    }
+
+    #[test]
+    fn replace_rewrites_selected_lines_only() {
+        let options = OutputOptions {
+            line_number: true,
+            after_context: 1,
+            color: true,
+            replace: Some("hit".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            print("match", &options),
+            "2:two hit\n3-three\n--\n6:six hit\n7-seven\n--\n10:ten hit\n"
+        );
+    }
+
+    #[test]
+    fn json_events() {
+        let options = OutputOptions {
+            json: true,
+            after_context: 1,
+            max_count: Some(1),
+            ..Default::default()
+        };
+        let events: Vec<serde_json::Value> = print("match", &options)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let types: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["begin", "match", "context", "end"]);
+
+        let found = &events[1]["data"];
+        assert_eq!(found["path"], "file.txt");
+        assert_eq!(found["line_number"], 2);
+        assert_eq!(found["byte_offset"], 4);
+        assert_eq!(found["spans"], serde_json::json!([{"start": 4, "end": 9}]));
+        assert_eq!(events[2]["data"]["line"], "three");
+        assert_eq!(events[3]["data"]["stats"]["matched_lines"], 1);
+        assert_eq!(events[3]["data"]["stats"]["matches"], 1);
+
+        assert_eq!(print("missing", &options), "");
+    }
+
+    #[test]
+    fn max_count_keeps_trailing_context() {
+        let options = OutputOptions {
+            max_count: Some(1),
+            after_context: 2,
+            ..Default::default()
+        };
+        assert_eq!(print("match", &options), "two match\nthree\nfour\n");
+
+        let count = OutputOptions {
+            max_count: Some(2),
+            count: true,
+            ..Default::default()
+        };
+        assert_eq!(print("match", &count), "2\n");
+    }
+}
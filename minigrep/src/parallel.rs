@@ -0,0 +1,284 @@
+// Searching many files on all cores.
+//
+// Each worker takes the next file index from a shared counter and sends the
+// writing thread a channel for that file, then searches it and sends its
+// output down the channel in chunks. The calling thread writes the output
+// of the files strictly in path order, so the output is the same as a
+// sequential run. The file next in line is written as it is searched; the
+// others wait in their channels, which hold a few chunks each, so memory
+// stays bounded by the number of jobs however much a file prints.
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+};
+
+// output is sent to the writing thread in chunks of about this size
+const CHUNK: usize = 64 * 1024;
+
+// chunks of a file that wait for the writing thread before its worker does
+const QUEUED_CHUNKS: usize = 4;
+
+/// Number of worker threads used when `-j` is not given.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Run `search` on every file with up to `jobs` threads and write each
+/// file's output to `out` in the order of `files`.
+///
+/// `search` writes the output for one file to the writer it is given and
+/// returns the number of selected lines; the total is returned. A file
+/// whose search fails is passed to `report`, in path order after whatever
+/// it wrote, and the other files are searched all the same.
+///
+/// # Errors
+///
+/// Stops when writing to `out` fails.
+pub fn search_files<F, W>(
+    files: &[PathBuf],
+    jobs: usize,
+    search: F,
+    out: &mut W,
+    mut report: impl FnMut(&Path, io::Error),
+) -> io::Result<usize>
+where
+    F: Fn(&Path, &mut dyn Write) -> io::Result<usize> + Sync,
+    W: Write,
+{
+    let jobs = jobs.clamp(1, files.len().max(1));
+    let next = AtomicUsize::new(0);
+    // set when the output side gives up, so workers stop picking up files
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel(jobs);
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let (next, stop, search) = (&next, &stop, &search);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(index) else { break };
+                    let (parts, file_parts) = mpsc::sync_channel(QUEUED_CHUNKS);
+                    if sender.send((index, file_parts)).is_err() {
+                        break;
+                    }
+                    let mut pipe = Pipe {
+                        parts,
+                        buf: Vec::new(),
+                    };
+                    let result = search(file, &mut pipe);
+                    // the rest of the output goes before how the search went
+                    let result = match pipe.send() {
+                        Ok(()) => result,
+                        Err(e) => Err(e),
+                    };
+                    if pipe.parts.send(Part::Done(result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // only the workers hold senders now, so the loop below ends once they are done
+        drop(sender);
+
+        let result = write_in_order(receiver, out, |index, e| report(&files[index], e));
+        if result.is_err() {
+            stop.store(true, Ordering::Relaxed);
+        }
+        result
+    })
+}
+
+// what a worker sends about its file: the output, then how the search went
+enum Part {
+    Output(Vec<u8>),
+    Done(io::Result<usize>),
+}
+
+// the writer a worker searches into, sending full chunks as it goes
+struct Pipe {
+    parts: SyncSender<Part>,
+    buf: Vec<u8>,
+}
+
+impl Pipe {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::take(&mut self.buf);
+        // the writing thread gave up
+        self.parts
+            .send(Part::Output(chunk))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_in_order<W: Write>(
+    receiver: Receiver<(usize, Receiver<Part>)>,
+    out: &mut W,
+    mut report: impl FnMut(usize, io::Error),
+) -> io::Result<usize> {
+    // channels of files that come later, at most about one per job since
+    // workers hand them over as they start on a file
+    let mut pending = BTreeMap::new();
+    let mut next_index = 0;
+    let mut total = 0;
+
+    loop {
+        let parts = match pending.remove(&next_index) {
+            Some(parts) => parts,
+            None => match receiver.recv() {
+                Ok((index, parts)) => {
+                    pending.insert(index, parts);
+                    continue;
+                }
+                // every file is done
+                Err(_) => return Ok(total),
+            },
+        };
+        for part in parts {
+            match part {
+                Part::Output(chunk) => out.write_all(&chunk)?,
+                Part::Done(Ok(selected)) => total += selected,
+                Part::Done(Err(e)) => report(next_index, e),
+            }
+        }
+        next_index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn files(n: usize) -> Vec<PathBuf> {
+        (0..n).map(|i| PathBuf::from(i.to_string())).collect()
+    }
+
+    // early files take longest, so they finish last
+    fn slow_search(path: &Path, buf: &mut dyn Write) -> io::Result<usize> {
+        let i: u64 = path.to_str().unwrap().parse().unwrap();
+        thread::sleep(Duration::from_millis(20u64.saturating_sub(i * 2)));
+        writeln!(buf, "file {i}")?;
+        Ok(1)
+    }
+
+    #[test]
+    fn output_is_in_path_order() {
+        let files = files(10);
+        let mut out = Vec::new();
+        let total = search_files(&files, 4, slow_search, &mut out, |_, e| panic!("{e}")).unwrap();
+
+        let expected: String = (0..10).map(|i| format!("file {i}\n")).collect();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn single_job_and_no_files() {
+        let mut out = Vec::new();
+        assert_eq!(
+            search_files(&files(3), 1, slow_search, &mut out, |_, e| panic!("{e}")).unwrap(),
+            3
+        );
+        let none = search_files(&[], 8, slow_search, &mut out, |_, e| panic!("{e}"));
+        assert_eq!(none.unwrap(), 0);
+        assert_eq!(out, b"file 0\nfile 1\nfile 2\n");
+    }
+
+    #[test]
+    fn reports_errors_in_order_and_carries_on() {
+        let mut out = Vec::new();
+        let mut failed = Vec::new();
+        let total = search_files(
+            &files(6),
+            3,
+            |path, buf| {
+                if path == Path::new("3") {
+                    writeln!(buf, "part of 3")?;
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "missing"));
+                }
+                slow_search(path, buf)
+            },
+            &mut out,
+            |path, e| failed.push((path.to_path_buf(), e.kind())),
+        );
+        assert_eq!(total.unwrap(), 5);
+        assert_eq!(failed, [(PathBuf::from("3"), io::ErrorKind::NotFound)]);
+        // what the failing file wrote before it failed is kept
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "file 0\nfile 1\nfile 2\npart of 3\nfile 4\nfile 5\n"
+        );
+    }
+
+    // notes when anything was written
+    struct Watched<'a> {
+        written: &'a AtomicBool,
+        out: Vec<u8>,
+    }
+
+    impl Write for Watched<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.store(true, Ordering::SeqCst);
+            self.out.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_the_file_next_in_line_as_it_is_searched() {
+        let written = AtomicBool::new(false);
+        let mut out = Watched {
+            written: &written,
+            out: Vec::new(),
+        };
+        // the first file only finishes once its first chunk is out
+        let search = |path: &Path, buf: &mut dyn Write| {
+            if path != Path::new("0") {
+                return slow_search(path, buf);
+            }
+            buf.write_all(&[b'.'; CHUNK])?;
+            let started = Instant::now();
+            while !written.load(Ordering::SeqCst) {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(1));
+            }
+            writeln!(buf)?;
+            Ok(1)
+        };
+        let total = search_files(&files(8), 2, search, &mut out, |_, e| panic!("{e}")).unwrap();
+
+        let expected: String = (1..8).map(|i| format!("file {i}\n")).collect();
+        let expected = format!("{}\n{expected}", ".".repeat(CHUNK));
+        assert_eq!(String::from_utf8(out.out).unwrap(), expected);
+        assert_eq!(total, 8);
+    }
+}
@@ -0,0 +1,205 @@
+// Reading the searched text line by line instead of loading it all at once.
+//
+// Lines are read as bytes, so a file that is not valid UTF-8 can still be
+// searched: invalid sequences are replaced with U+FFFD (lossy decoding), which
+// doesn't allocate at all for lines that are valid UTF-8. Memory use only
+// depends on the longest line, not on the size of the file.
+use memmap2::Mmap;
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufRead, BufReader},
+};
+
+/// Read buffer size for files, larger than std's 8 KiB default to cut down
+/// on read calls for big files.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Call `f(line_number, byte_offset, line)` for every line of `reader`.
+///
+/// Line numbers start at 1, the byte offset is the position of the line in
+/// the raw input. `\n` and `\r\n` terminators are stripped. `f` returns
+/// `false` to stop reading early.
+pub fn read_lines<R, F>(mut reader: R, mut f: F) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(usize, usize, &str) -> io::Result<bool>,
+{
+    // one buffer reused for every line
+    let mut buf = Vec::new();
+    let mut line_number = 0;
+    let mut offset = 0;
+    loop {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        line_number += 1;
+        if !f(line_number, offset, &decode(&buf))? {
+            return Ok(());
+        }
+        offset += read;
+    }
+}
+
+/// Like [`read_lines`], but over bytes that are already in memory (e.g. a
+/// memory mapped file), so lines are never copied.
+pub fn slice_lines<F>(bytes: &[u8], mut f: F) -> io::Result<()>
+where
+    F: FnMut(usize, usize, &str) -> io::Result<bool>,
+{
+    let mut offset = 0;
+    for (i, line) in bytes.split_inclusive(|&b| b == b'\n').enumerate() {
+        if !f(i + 1, offset, &decode(line))? {
+            break;
+        }
+        offset += line.len();
+    }
+    Ok(())
+}
+
+/// Search `file` with [`slice_lines`] over a memory map if `mmap` is set,
+/// with [`read_lines`] over a buffered reader otherwise.
+pub fn file_lines<F>(file: File, mmap: bool, f: F) -> io::Result<()>
+where
+    F: FnMut(usize, usize, &str) -> io::Result<bool>,
+{
+    // empty files can't be mapped
+    if mmap && file.metadata()?.len() > 0 {
+        // SAFETY: the map is only read while searching; if another process
+        // truncates the file meanwhile we may read garbage or fault, which
+        // is the usual caveat every grep using mmap lives with
+        let map = unsafe { Mmap::map(&file)? };
+        slice_lines(&map, f)
+    } else {
+        read_lines(BufReader::with_capacity(BUFFER_SIZE, file), f)
+    }
+}
+
+// strip the line terminator and decode, borrowing when the line is valid UTF-8
+fn decode(line: &[u8]) -> Cow<'_, str> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, Write};
+
+    fn collect(input: &[u8]) -> Vec<(usize, usize, String)> {
+        let mut lines = Vec::new();
+        read_lines(input, |n, offset, line| {
+            lines.push((n, offset, line.to_string()));
+            Ok(true)
+        })
+        .unwrap();
+
+        // both paths must see exactly the same lines
+        let mut sliced = Vec::new();
+        slice_lines(input, |n, offset, line| {
+            sliced.push((n, offset, line.to_string()));
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(lines, sliced);
+        lines
+    }
+
+    #[test]
+    fn strips_crlf_and_tracks_offsets() {
+        assert_eq!(
+            collect(b"one\r\ntwo\nthree"),
+            [
+                (1, 0, "one".to_string()),
+                (2, 5, "two".to_string()),
+                (3, 9, "three".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_lossily() {
+        assert_eq!(
+            collect(b"caf\xe9\nok\n"),
+            [(1, 0, "caf\u{fffd}".to_string()), (2, 5, "ok".to_string())]
+        );
+    }
+
+    #[test]
+    fn stops_early() {
+        let mut seen = 0;
+        read_lines(&b"a\nb\nc\n"[..], |n, _, _| {
+            seen = n;
+            Ok(n < 2)
+        })
+        .unwrap();
+        assert_eq!(seen, 2);
+    }
+
+    #[test]
+    fn mmap_and_buffered_agree() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"alpha\r\nbeta\n\xffgamma").unwrap();
+
+        let mut results = Vec::new();
+        for mmap in [false, true] {
+            file.rewind().unwrap();
+            let mut lines = Vec::new();
+            file_lines(file.try_clone().unwrap(), mmap, |n, offset, line| {
+                lines.push((n, offset, line.to_string()));
+                Ok(true)
+            })
+            .unwrap();
+            results.push(lines);
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0][2], (3, 12, "\u{fffd}gamma".to_string()));
+    }
+
+    // produces "line 1\n", "line 2\n", ... without ever holding them all in memory
+    struct Generated {
+        next: usize,
+        last: usize,
+        pending: Vec<u8>,
+    }
+
+    impl Read for Generated {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                if self.next > self.last {
+                    return Ok(0);
+                }
+                self.pending = format!("line {}\n", self.next).into_bytes();
+                self.next += 1;
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streams_large_input() {
+        let input = Generated {
+            next: 1,
+            last: 200_000,
+            pending: Vec::new(),
+        };
+        let mut count = 0;
+        let mut last = String::new();
+        read_lines(BufReader::new(input), |_, _, line| {
+            count += 1;
+            if line.ends_with("99999") {
+                last = line.to_string();
+            }
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(count, 200_000);
+        assert_eq!(last, "line 199999");
+    }
+}
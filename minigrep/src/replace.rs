@@ -0,0 +1,268 @@
+// Rewriting files in place with `--replace`.
+//
+// The new contents are written to a temporary file next to the original and
+// then renamed over it, so a crash halfway never leaves a half written file
+// behind. `--dry-run` prints a unified diff of what would change instead.
+use crate::Matcher;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Lines of unchanged context around every hunk of `--dry-run` output.
+const DIFF_CONTEXT: usize = 3;
+
+/// How [`rewrite_file`] treats the file it changes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RewriteOptions {
+    /// keep the original as `<file>.bak`
+    pub backup: bool,
+    /// don't touch the file, print a unified diff to `out` instead
+    pub dry_run: bool,
+}
+
+/// Replace every match in `path` with `template` and return the number of
+/// changed lines.
+///
+/// `template` can refer to capture groups like [`regex::Regex::replace_all`]
+/// does: `$1`, `${name}`, and `$$` for a literal `$`. Line endings are kept as
+/// they are. Files without a match are left alone.
+///
+/// # Errors
+///
+/// Returns an error if the file is not valid UTF-8 or can't be read or written.
+pub fn rewrite_file<W: Write>(
+    path: &Path,
+    matcher: &Matcher,
+    template: &str,
+    options: RewriteOptions,
+    out: &mut W,
+) -> io::Result<usize> {
+    // unlike searching, rewriting must not lose bytes to lossy decoding
+    let contents = fs::read_to_string(path)?;
+
+    let mut old_lines = Vec::new();
+    let mut new_lines = Vec::new();
+    let mut changed = Vec::new();
+    for (i, raw) in contents.split_inclusive('\n').enumerate() {
+        let (line, ending) = split_ending(raw);
+        let replaced = matcher.replace_all(line, template);
+        if replaced != line {
+            changed.push(i);
+        }
+        new_lines.push(format!("{replaced}{ending}"));
+        old_lines.push(raw);
+    }
+    if changed.is_empty() {
+        return Ok(0);
+    }
+
+    if options.dry_run {
+        write_diff(out, path, &old_lines, &new_lines, &changed)?;
+        return Ok(changed.len());
+    }
+
+    if options.backup {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        fs::copy(path, backup)?;
+    }
+
+    // the temporary file must be on the same file system for the rename to be atomic
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    for line in &new_lines {
+        temp.write_all(line.as_bytes())?;
+    }
+    temp.as_file().sync_all()?;
+    temp.as_file()
+        .set_permissions(fs::metadata(path)?.permissions())?;
+    temp.persist(path).map_err(|e| e.error)?;
+
+    Ok(changed.len())
+}
+
+fn split_ending(raw: &str) -> (&str, &str) {
+    for ending in ["\r\n", "\n"] {
+        if let Some(line) = raw.strip_suffix(ending) {
+            return (line, ending);
+        }
+    }
+    (raw, "")
+}
+
+// `changed` holds the (sorted) indexes of the lines that differ; replacing
+// never adds or removes lines, so old and new line numbers are the same
+fn write_diff<W: Write>(
+    out: &mut W,
+    path: &Path,
+    old_lines: &[&str],
+    new_lines: &[String],
+    changed: &[usize],
+) -> io::Result<()> {
+    // plain paths like `diff -u`, so `patch -p0` applies the output
+    writeln!(out, "--- {}", path.display())?;
+    writeln!(out, "+++ {}", path.display())?;
+
+    let mut i = 0;
+    while i < changed.len() {
+        // grow the hunk while the next change is close enough to share context
+        let mut j = i;
+        while j + 1 < changed.len() && changed[j + 1] - changed[j] <= 2 * DIFF_CONTEXT {
+            j += 1;
+        }
+        let start = changed[i].saturating_sub(DIFF_CONTEXT);
+        let end = (changed[j] + DIFF_CONTEXT + 1).min(old_lines.len());
+        let len = end - start;
+        writeln!(out, "@@ -{},{len} +{},{len} @@", start + 1, start + 1)?;
+
+        for line in start..end {
+            if changed[i..=j].contains(&line) {
+                write_diff_line(out, '-', old_lines[line])?;
+                write_diff_line(out, '+', &new_lines[line])?;
+            } else {
+                write_diff_line(out, ' ', old_lines[line])?;
+            }
+        }
+        i = j + 1;
+    }
+    Ok(())
+}
+
+fn write_diff_line<W: Write>(out: &mut W, prefix: char, raw: &str) -> io::Result<()> {
+    let (line, ending) = split_ending(raw);
+    writeln!(out, "{prefix}{line}")?;
+    if ending.is_empty() {
+        writeln!(out, "\\ No newline at end of file")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueryMode;
+
+    const POEM: &str = "\
+I'm nobody! Who are you?
+Are you nobody, too?
+Then there's a pair of us - don't tell!
+They'd banish us, you know.
+
+How dreary to be somebody!
+How public, like a frog
+To tell your name the livelong day
+To an admiring bog!
+";
+
+    fn regex(pattern: &str) -> Matcher {
+        Matcher::new(pattern, QueryMode::Regex, false).unwrap()
+    }
+
+    #[test]
+    fn rewrites_in_place_with_capture_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, "a=1\r\nb=2\nnothing\nc=3").unwrap();
+
+        let changed = rewrite_file(
+            &path,
+            &regex(r"(\w)=(\d)"),
+            "${2}=$1",
+            RewriteOptions::default(),
+            &mut io::sink(),
+        )
+        .unwrap();
+        assert_eq!(changed, 3);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "1=a\r\n2=b\nnothing\n3=c"
+        );
+        assert!(!dir.path().join("poem.txt.bak").exists());
+    }
+
+    #[test]
+    fn keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, POEM).unwrap();
+        let options = RewriteOptions {
+            backup: true,
+            ..Default::default()
+        };
+
+        rewrite_file(&path, &regex("frog"), "toad", options, &mut io::sink()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("poem.txt.bak")).unwrap(),
+            POEM
+        );
+        assert!(fs::read_to_string(&path).unwrap().contains("like a toad"));
+    }
+
+    #[test]
+    fn untouched_without_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, POEM).unwrap();
+        let options = RewriteOptions {
+            backup: true,
+            ..Default::default()
+        };
+
+        let changed = rewrite_file(&path, &regex("toad"), "frog", options, &mut io::sink());
+        assert_eq!(changed.unwrap(), 0);
+        assert!(!dir.path().join("poem.txt.bak").exists());
+    }
+
+    #[test]
+    fn dry_run_prints_unified_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, POEM).unwrap();
+        let options = RewriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        let changed = rewrite_file(&path, &regex("nobody"), "somebody", options, &mut out);
+        assert_eq!(changed.unwrap(), 2);
+        // the file itself is not changed
+        assert_eq!(fs::read_to_string(&path).unwrap(), POEM);
+
+        let diff = String::from_utf8(out).unwrap();
+        let header = format!("--- {path}\n+++ {path}\n", path = path.display());
+        let hunk = [
+            "@@ -1,5 +1,5 @@",
+            "-I'm nobody! Who are you?",
+            "+I'm somebody! Who are you?",
+            "-Are you nobody, too?",
+            "+Are you somebody, too?",
+            " Then there's a pair of us - don't tell!",
+            " They'd banish us, you know.",
+            " ",
+        ];
+        assert_eq!(diff, header + &hunk.join("\n") + "\n");
+    }
+
+    #[test]
+    fn dry_run_splits_distant_hunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, POEM).unwrap();
+        let options = RewriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        rewrite_file(&path, &regex("^(I'm|To an)"), "X", options, &mut out).unwrap();
+        let diff = String::from_utf8(out).unwrap();
+        let hunks: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(hunks, ["@@ -1,4 +1,4 @@", "@@ -6,4 +6,4 @@"]);
+    }
+}
@@ -0,0 +1,201 @@
+// Turning the paths given on the command line into the list of files to search.
+//
+// Directories are walked recursively like `grep -r`, `.gitignore` (and
+// `.ignore`) rules are honoured and `--include` / `--exclude` globs filter the
+// file names. The walking itself is done by the `ignore` crate, the same one
+// ripgrep uses.
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use std::{
+    error::Error,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Options controlling which files a directory walk yields.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// only search files whose name matches one of these globs
+    pub include: Vec<String>,
+    /// skip files whose name matches one of these globs
+    pub exclude: Vec<String>,
+    /// don't read `.gitignore` and `.ignore` files
+    pub no_ignore: bool,
+}
+
+/// Collect every file below `paths`, sorted by path within each directory.
+///
+/// Files named directly in `paths` are always returned, even if a glob or an
+/// ignore file would have excluded them. Like `grep -r`, a directory entry
+/// that can't be read is passed to `report` and the walk carries on.
+///
+/// # Errors
+///
+/// Returns an error if a glob is invalid.
+pub fn collect_files(
+    paths: &[String],
+    options: &WalkOptions,
+    mut report: impl FnMut(ignore::Error),
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        let root = Path::new(path);
+        if !root.is_dir() {
+            files.push(root.to_path_buf());
+            continue;
+        }
+
+        // the ignore crate calls include/exclude globs "overrides",
+        // a plain glob whitelists and a `!` glob blacklists
+        let mut overrides = OverrideBuilder::new(root);
+        for glob in &options.include {
+            overrides.add(glob)?;
+        }
+        for glob in &options.exclude {
+            overrides.add(&format!("!{glob}"))?;
+        }
+
+        let walker = WalkBuilder::new(root)
+            .overrides(overrides.build()?)
+            // like grep -r, hidden files are searched but .git never is
+            .hidden(false)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .git_ignore(!options.no_ignore)
+            .git_exclude(!options.no_ignore)
+            .git_global(false)
+            .ignore(!options.no_ignore)
+            // honour .gitignore files even outside of a git repository
+            .require_git(false)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+
+        for entry in walker {
+            match entry {
+                Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                    files.push(entry.into_path());
+                }
+                Ok(_) => {}
+                Err(e) => report(e),
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Whether the file looks binary, i.e. has a NUL byte in its first 8 KiB.
+/// This is the same heuristic grep uses.
+pub fn is_binary(path: &Path) -> std::io::Result<bool> {
+    let mut head = Vec::with_capacity(8192);
+    File::open(path)?.take(8192).read_to_end(&mut head)?;
+    Ok(head.contains(&0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn names(files: &[PathBuf], root: &Path) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| {
+                f.strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect()
+    }
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(root.join("a.txt"), "one").unwrap();
+        fs::write(root.join("debug.log"), "two").unwrap();
+        fs::write(root.join("src/main.rs"), "three").unwrap();
+        fs::write(root.join("src/nested/lib.rs"), "four").unwrap();
+        fs::write(root.join("target/out.txt"), "five").unwrap();
+        dir
+    }
+
+    #[test]
+    fn walks_recursively_and_honours_gitignore() {
+        let dir = tree();
+        let root = dir.path().to_str().unwrap().to_string();
+
+        let files = collect_files(&[root], &WalkOptions::default(), |e| panic!("{e}")).unwrap();
+        assert_eq!(
+            names(&files, dir.path()),
+            [".gitignore", "a.txt", "src/main.rs", "src/nested/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn no_ignore_searches_everything() {
+        let dir = tree();
+        let root = dir.path().to_str().unwrap().to_string();
+        let options = WalkOptions {
+            no_ignore: true,
+            ..Default::default()
+        };
+
+        let files = collect_files(&[root], &options, |e| panic!("{e}")).unwrap();
+        assert_eq!(files.len(), 6);
+    }
+
+    #[test]
+    fn include_and_exclude_globs() {
+        let dir = tree();
+        let root = dir.path().to_str().unwrap().to_string();
+        let options = WalkOptions {
+            include: vec!["*.rs".to_string()],
+            exclude: vec!["lib.rs".to_string()],
+            ..Default::default()
+        };
+
+        let files = collect_files(&[root], &options, |e| panic!("{e}")).unwrap();
+        assert_eq!(names(&files, dir.path()), ["src/main.rs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_unreadable_directories_and_carries_on() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tree();
+        let locked = dir.path().join("src/nested");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // root can read it anyway
+        if fs::read_dir(&locked).is_ok() {
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+        let root = dir.path().to_str().unwrap().to_string();
+
+        let mut errors = Vec::new();
+        let files = collect_files(&[root], &WalkOptions::default(), |e| errors.push(e));
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            names(&files.unwrap(), dir.path()),
+            [".gitignore", "a.txt", "src/main.rs"]
+        );
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn detects_binary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        let binary = dir.path().join("binary");
+        fs::write(&text, "plain text").unwrap();
+        fs::write(&binary, b"\x7fELF\0\0\x01").unwrap();
+
+        assert!(!is_binary(&text).unwrap());
+        assert!(is_binary(&binary).unwrap());
+    }
+}
@@ -1,18 +1,83 @@
 use multithreaded_web_server::ThreadPool;
 use std::{
-    fs,
+    env, fs,
     io::{prelude::*, BufReader},
     net::{TcpListener, TcpStream},
-    thread,
+    process, thread,
     time::Duration,
 };
 
+const USAGE: &str = "\
+Usage: multithreaded_web_server [--expose]
+
+Options:
+  --expose    forward the port from the gateway while the server runs,
+              until Ctrl-C (needs the `expose` feature)";
+
 fn main() {
+    let mut expose = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--expose" => expose = true,
+            _ => {
+                eprintln!("unexpected argument '{arg}'\n\n{USAGE}");
+                process::exit(2);
+            }
+        }
+    }
+
+    if expose && !cfg!(feature = "expose") {
+        eprintln!("--expose needs a build with the `expose` feature");
+        process::exit(2);
+    }
+
     let port = 7878u16;
     let listen_address = format!("0.0.0.0:{port}");
     let listener = TcpListener::bind(listen_address).unwrap();
 
     println!("Listening on port {}", port);
+    #[cfg(feature = "expose")]
+    if expose {
+        serve_exposed(listener);
+        return;
+    }
+    serve(listener);
+}
+
+// serves until Ctrl-C or SIGTERM, with the port forwarded from the gateway
+// if it can be; the mapping goes when the server does
+#[cfg(feature = "expose")]
+fn serve_exposed(listener: TcpListener) {
+    let addr = listener.local_addr().unwrap();
+    // serving locally is fine without it
+    let exposed = match igd_port_forwarding::expose(addr, "multithreaded_web_server") {
+        Ok(exposed) => {
+            println!("Exposed as {}", exposed.mapping());
+            exposed
+        }
+        Err(e) => {
+            eprintln!("Failed to expose port {}: {}", addr.port(), e);
+            return serve(listener);
+        }
+    };
+    // the accept loop never ends by itself, so it is left behind
+    let serving = thread::spawn(move || serve(listener));
+    match igd_port_forwarding::wait_for_stop() {
+        Ok(()) => {
+            println!("Stopping, removing the port mapping");
+            drop(exposed);
+        }
+        Err(e) => {
+            eprintln!(
+                "Can't wait for signals, the mapping stays until it expires: {}",
+                e
+            );
+            serving.join().unwrap();
+        }
+    }
+}
+
+fn serve(listener: TcpListener) {
     // using thread pool
     let thread_pool = ThreadPool::new(4);
     for stream in listener.incoming() {
@@ -0,0 +1,523 @@
+// Load test for an echo server.
+//
+// Every sender has its own socket and sends plain datagrams (no
+// retransmission, so lost datagrams stay lost) at its share of the rate,
+// while a second thread receives the echoes. Each datagram carries who sent
+// it, a sequence number and when it was sent, so an echo is enough to tell
+// the round-trip time and whether it arrived late or out of order:
+//
+//     sender: u32 | seq: u64 | sent at: u64 nanoseconds | padding
+//
+// The server has to send datagrams back as they are, like `session_server`
+// does; `udp_server` speaks the request/response protocol instead. As every
+// sender is a peer of its own, `session_server` needs a --rate above this
+// bench's rate divided by --senders, or its rate limit shows up as loss.
+use std::{
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use udp_server::ClientOptions;
+
+pub const USAGE: &str = "\
+Usage: udp_client bench [OPTIONS]
+
+Sends datagrams to a server that echoes them back as they are and reports
+latency, loss, reordering and throughput. Run it against `session_server`
+with a --rate higher than what each sender sends, e.g.
+`session_server --rate 10000`; `udp_server` doesn't echo plain datagrams.
+
+Options:
+  --senders N      concurrent senders, each with its own port [default: 4]
+  --size BYTES     datagram size, at least 20 [default: 64]
+  --rate N         datagrams per second, over all senders [default: 1000]
+  --duration SECS  how long to send [default: 5]
+  --wait SECS      how long to wait for late echoes [default: 1]
+and --server, --discover, --multicast, --bind, --interface, --ttl and
+--no-loopback as without `bench`. A --bind address with a port other than 0
+needs --senders 1, as every sender has its own port.";
+
+// sender id, sequence number and timestamp
+const HEADER_LEN: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchOptions {
+    pub client: ClientOptions,
+    pub senders: usize,
+    pub size: usize,
+    /// datagrams per second over all senders
+    pub rate: f64,
+    pub duration: Duration,
+    /// how long to keep listening after the last datagram was sent
+    pub wait: Duration,
+}
+
+impl BenchOptions {
+    /// Parse the arguments after `bench`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<BenchOptions, String> {
+        let mut senders = 4;
+        let mut size = 64;
+        let mut rate: f64 = 1000.0;
+        let mut duration = 5.0;
+        let mut wait = 1.0;
+        let mut rest = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--senders" => parse(&arg, args.next(), &mut senders)?,
+                "--size" => parse(&arg, args.next(), &mut size)?,
+                "--rate" => parse(&arg, args.next(), &mut rate)?,
+                "--duration" => parse(&arg, args.next(), &mut duration)?,
+                "--wait" => parse(&arg, args.next(), &mut wait)?,
+                // the rest says where the server is
+                _ => rest.push(arg),
+            }
+        }
+        if senders == 0 {
+            return Err("--senders must be at least 1".to_string());
+        }
+        if !(HEADER_LEN..=udp_server::MAX_DATAGRAM).contains(&size) {
+            return Err(format!(
+                "--size must be between {} and {}",
+                HEADER_LEN,
+                udp_server::MAX_DATAGRAM
+            ));
+        }
+        let seconds = |secs: f64| secs.is_finite() && secs >= 0.0;
+        if !rate.is_finite() || rate <= 0.0 || !seconds(duration) || !seconds(wait) {
+            return Err("--rate must be positive, --duration and --wait not negative".to_string());
+        }
+        let client = ClientOptions::parse(rest)?;
+        if senders > 1 && client.bind.is_some_and(|bind| bind.port() != 0) {
+            return Err("--bind with a port needs --senders 1".to_string());
+        }
+        Ok(BenchOptions {
+            client,
+            senders,
+            size,
+            rate,
+            duration: Duration::from_secs_f64(duration),
+            wait: Duration::from_secs_f64(wait),
+        })
+    }
+}
+
+fn parse<T: std::str::FromStr>(
+    option: &str,
+    value: Option<String>,
+    to: &mut T,
+) -> Result<(), String> {
+    let value = value.ok_or_else(|| format!("{option} needs a value"))?;
+    *to = value
+        .parse()
+        .map_err(|_| format!("invalid value '{value}' for {option}"))?;
+    Ok(())
+}
+
+fn encode(sender: u32, seq: u64, sent_at: Duration, buf: &mut [u8]) {
+    buf[..4].copy_from_slice(&sender.to_be_bytes());
+    buf[4..12].copy_from_slice(&seq.to_be_bytes());
+    buf[12..20].copy_from_slice(&(sent_at.as_nanos() as u64).to_be_bytes());
+}
+
+fn decode(buf: &[u8]) -> Option<(u32, u64, Duration)> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    let sender = u32::from_be_bytes(buf[..4].try_into().unwrap());
+    let seq = u64::from_be_bytes(buf[4..12].try_into().unwrap());
+    let sent_at = u64::from_be_bytes(buf[12..20].try_into().unwrap());
+    Some((sender, seq, Duration::from_nanos(sent_at)))
+}
+
+/// What a benchmark measured.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub senders: usize,
+    pub size: usize,
+    pub sent: u64,
+    /// echoes of distinct datagrams
+    pub received: u64,
+    pub duplicates: u64,
+    /// echoes that came after an echo of a later datagram of the same sender
+    pub reordered: u64,
+    /// round-trip times, sorted
+    pub latencies: Vec<Duration>,
+    /// how long the senders were sending
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// The round-trip time `p` percent of echoes were at most as slow as.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        // nearest rank
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+
+    /// Share of datagrams that never came back, 0.0 to 1.0.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.received as f64 / self.sent as f64
+    }
+
+    fn merge(&mut self, sender: SenderReport) {
+        self.sent += sender.sent;
+        self.received += sender.seen.iter().filter(|&&seen| seen).count() as u64;
+        self.duplicates += sender.duplicates;
+        self.reordered += sender.reordered;
+        self.latencies.extend(sender.latencies);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "Sent {} datagrams of {} bytes from {} senders in {:.2}s",
+            self.sent, self.size, self.senders, secs
+        )?;
+        writeln!(
+            f,
+            "Received {} ({:.2}% loss), {} reordered, {} duplicates",
+            self.received,
+            self.loss() * 100.0,
+            self.reordered,
+            self.duplicates
+        )?;
+        let ms = |p| match self.percentile(p) {
+            Some(latency) => format!("{:.3}ms", latency.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        writeln!(
+            f,
+            "Latency: p50 {}, p90 {}, p99 {}, max {}",
+            ms(50.0),
+            ms(90.0),
+            ms(99.0),
+            ms(100.0)
+        )?;
+        write!(
+            f,
+            "Throughput: {:.1} datagrams/s, {:.3} Mbit/s echoed",
+            self.received as f64 / secs,
+            (self.received * self.size as u64 * 8) as f64 / secs / 1_000_000.0
+        )
+    }
+}
+
+// what one sender saw
+#[derive(Default)]
+struct SenderReport {
+    sent: u64,
+    // which sequence numbers came back
+    seen: Vec<bool>,
+    duplicates: u64,
+    reordered: u64,
+    latencies: Vec<Duration>,
+}
+
+impl SenderReport {
+    // `sent` is how many datagrams went out so far; an echo of anything
+    // beyond wasn't sent by us and is ignored
+    fn record(&mut self, seq: u64, sent: u64, latency: Duration, highest: &mut Option<u64>) {
+        if seq >= sent {
+            return;
+        }
+        let seq = seq as usize;
+        if seq >= self.seen.len() {
+            self.seen.resize(seq + 1, false);
+        }
+        if self.seen[seq] {
+            self.duplicates += 1;
+            return;
+        }
+        self.seen[seq] = true;
+        self.latencies.push(latency);
+        match highest {
+            Some(highest) if seq as u64 <= *highest => self.reordered += 1,
+            _ => *highest = Some(seq as u64),
+        }
+    }
+}
+
+/// Run the benchmark against `server`.
+pub fn run(options: &BenchOptions, server: SocketAddr) -> io::Result<Report> {
+    let sockets = (0..options.senders)
+        .map(|_| options.client.socket(server))
+        .collect::<io::Result<Vec<_>>>()?;
+    let start = Instant::now();
+    // every sender sends at its share of the rate
+    let interval = Duration::from_secs_f64(options.senders as f64 / options.rate);
+    let end = start + options.duration;
+    // when the receivers should give up, known once sending is done
+    let stop_at = Arc::new(Mutex::new(None));
+
+    let handles = sockets
+        .into_iter()
+        .enumerate()
+        .map(|(id, socket)| {
+            let receiver = socket.try_clone()?;
+            let stop_at = Arc::clone(&stop_at);
+            let sent = Arc::new(AtomicU64::new(0));
+            let sending = Arc::clone(&sent);
+            let receiving =
+                thread::spawn(move || receive(receiver, id as u32, start, &sent, stop_at));
+            let pace = Pace {
+                // senders start spread over the first interval instead of all at once
+                first: start + interval.mul_f64(id as f64 / options.senders as f64),
+                interval,
+                end,
+            };
+            let size = options.size;
+            let sending =
+                thread::spawn(move || send(socket, server, id as u32, size, pace, start, &sending));
+            Ok((sending, receiving))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut sent = Vec::new();
+    let mut receiving = Vec::new();
+    for (sending, receiver) in handles {
+        sent.push(sending.join().unwrap()?);
+        receiving.push(receiver);
+    }
+    let elapsed = start.elapsed();
+    *stop_at.lock().unwrap() = Some(Instant::now() + options.wait);
+
+    let mut report = Report {
+        senders: options.senders,
+        size: options.size,
+        elapsed,
+        ..Report::default()
+    };
+    for (sent, receiver) in sent.into_iter().zip(receiving) {
+        let mut sender = receiver.join().unwrap()?;
+        sender.sent = sent;
+        report.merge(sender);
+    }
+    report.latencies.sort();
+    Ok(report)
+}
+
+// when a sender sends
+#[derive(Clone, Copy)]
+struct Pace {
+    first: Instant,
+    interval: Duration,
+    // nothing is sent from here on
+    end: Instant,
+}
+
+// returns how many datagrams were sent, counting them in `sent` as it goes
+fn send(
+    socket: UdpSocket,
+    server: SocketAddr,
+    id: u32,
+    size: usize,
+    pace: Pace,
+    start: Instant,
+    sent: &AtomicU64,
+) -> io::Result<u64> {
+    let mut buf = vec![0; size];
+    let mut seq = 0;
+    let mut next = pace.first;
+    while next < pace.end {
+        // falling behind sends right away to catch up
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        encode(id, seq, start.elapsed(), &mut buf);
+        // counted first, the echo may be quicker than this thread
+        sent.store(seq + 1, Ordering::Release);
+        socket.send_to(&buf, server)?;
+        seq += 1;
+        next += pace.interval;
+    }
+    Ok(seq)
+}
+
+fn receive(
+    socket: UdpSocket,
+    id: u32,
+    start: Instant,
+    sent: &AtomicU64,
+    stop_at: Arc<Mutex<Option<Instant>>>,
+) -> io::Result<SenderReport> {
+    let mut report = SenderReport::default();
+    let mut highest = None;
+    let mut buf = vec![0; udp_server::MAX_DATAGRAM];
+    loop {
+        let timeout = match *stop_at.lock().unwrap() {
+            Some(stop_at) => match stop_at.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => left,
+                _ => return Ok(report),
+            },
+            // check again soon whether sending is done
+            None => Duration::from_millis(50),
+        };
+        socket.set_read_timeout(Some(timeout))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e) if udp_server::socket::is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        let now = start.elapsed();
+        match decode(&buf[..len]) {
+            Some((sender, seq, sent_at)) if sender == id => {
+                let sent = sent.load(Ordering::Acquire);
+                report.record(seq, sent, now.saturating_sub(sent_at), &mut highest)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    // echoes until the test ends, `mangle` decides what goes back for the
+    // nth datagram: nothing, the datagram, or it and some held back earlier
+    fn spawn_echo<F>(mut mangle: F) -> SocketAddr
+    where
+        F: FnMut(u64, Vec<u8>) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            for n in 0.. {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                for echo in mangle(n, buf[..len].to_vec()) {
+                    socket.send_to(&echo, from).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    fn options(server: SocketAddr, extra: &str) -> BenchOptions {
+        let line = format!("--server {server} --wait 0.2 {extra}");
+        BenchOptions::parse(args(&line)).unwrap()
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = BenchOptions::parse(args(
+            "--senders 8 --size 1200 --rate 500 --duration 2.5 --server [::1]:9000",
+        ))
+        .unwrap();
+        assert_eq!(options.senders, 8);
+        assert_eq!(options.size, 1200);
+        assert_eq!(options.rate, 500.0);
+        assert_eq!(options.duration, Duration::from_millis(2500));
+        assert_eq!(options.wait, Duration::from_secs(1));
+        assert_eq!(
+            options.client.peer,
+            udp_server::Peer::Addr("[::1]:9000".parse().unwrap())
+        );
+
+        for bad in [
+            "--size 10",
+            "--senders 0",
+            "--rate 0",
+            "--rate",
+            "--duration -1",
+            "--bind 127.0.0.1:9000",
+        ] {
+            assert!(BenchOptions::parse(args(bad)).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn bind_needs_one_sender_or_any_port() {
+        for ok in ["--senders 1 --bind 127.0.0.1:9000", "--bind 127.0.0.1:0"] {
+            assert!(BenchOptions::parse(args(ok)).is_ok(), "{ok}");
+        }
+    }
+
+    #[test]
+    fn ignores_echoes_of_datagrams_never_sent() {
+        let mut report = SenderReport::default();
+        let mut highest = None;
+        report.record(u64::MAX, 3, Duration::ZERO, &mut highest);
+        report.record(3, 3, Duration::ZERO, &mut highest);
+        assert!(report.seen.is_empty());
+        report.record(2, 3, Duration::ZERO, &mut highest);
+        assert_eq!(report.seen, [false, false, true]);
+        assert_eq!(highest, Some(2));
+    }
+
+    #[test]
+    fn percentiles() {
+        let report = Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..Report::default()
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(report.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(Report::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn everything_comes_back_from_a_good_server() {
+        let server = spawn_echo(|_, datagram| vec![datagram]);
+        let options = options(server, "--senders 3 --rate 600 --duration 0.5 --size 100");
+        let report = run(&options, server).unwrap();
+
+        assert!((270..=330).contains(&report.sent), "sent {}", report.sent);
+        assert_eq!(report.received, report.sent);
+        assert_eq!(report.loss(), 0.0);
+        assert_eq!(report.reordered, 0);
+        assert_eq!(report.latencies.len() as u64, report.received);
+        assert!(report.to_string().contains("(0.00% loss)"));
+    }
+
+    #[test]
+    fn counts_loss_reordering_and_duplicates() {
+        let mut held = None;
+        let server = spawn_echo(move |n, datagram| match n % 10 {
+            // lost
+            0 => vec![],
+            // held back, so it comes after the next one
+            1 => {
+                held = Some(datagram);
+                vec![]
+            }
+            2 => {
+                let late = held.take().unwrap();
+                vec![datagram, late]
+            }
+            // echoed twice
+            3 => vec![datagram.clone(), datagram],
+            _ => vec![datagram],
+        });
+        let options = options(server, "--senders 1 --rate 1000 --duration 0.1");
+        let report = run(&options, server).unwrap();
+
+        assert_eq!(report.sent, 100);
+        assert_eq!(report.received, 90);
+        assert!((report.loss() - 0.1).abs() < 1e-9);
+        assert_eq!(report.reordered, 10);
+        assert_eq!(report.duplicates, 10);
+    }
+}
@@ -1,15 +1,42 @@
-use std::net::UdpSocket;
+use std::{env, process};
+use udp_server::options::CLIENT_USAGE;
+use udp_server::{Client, ClientOptions, FragmentSocket};
+
+mod bench;
 
 fn main() -> std::io::Result<()> {
-    let socket = UdpSocket::bind("127.0.0.1:8081")?;
-    socket.connect("127.0.0.1:8080")?;
+    let mut args = env::args().skip(1).peekable();
+    // udp_client bench [OPTIONS]: measure an echo server
+    if args.peek().map(String::as_str) == Some("bench") {
+        let options = bench::BenchOptions::parse(args.skip(1)).unwrap_or_else(|err| {
+            eprintln!("{}\n\n{}", err, bench::USAGE);
+            process::exit(2);
+        });
+        let server = options.client.server()?;
+        println!(
+            "Sending {} datagrams/s from {} senders to {} for {:.1}s",
+            options.rate,
+            options.senders,
+            server,
+            options.duration.as_secs_f64()
+        );
+        println!("{}", bench::run(&options, server)?);
+        return Ok(());
+    }
 
-    let message = b"Hello, UDP server!";
-    socket.send(message)?;
+    let options = ClientOptions::parse(args).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, CLIENT_USAGE);
+        process::exit(2);
+    });
+    // an ephemeral port unless --bind says otherwise, so clients don't collide
+    let (socket, server) = options.connect()?;
+    // the server expects fragments, even for a message that fits in one datagram
+    let socket = FragmentSocket::new(socket);
+    // resends the request until the server answers, gives up after a timeout
+    let mut client = Client::new(socket, server);
 
-    let mut buf = [0; 1024];
-    let amt = socket.recv(&mut buf)?;
-    let received_data = std::str::from_utf8(&buf[..amt]).expect("Invalid UTF-8 data received");
+    let response = client.request(options.message.as_bytes())?;
+    let received_data = String::from_utf8_lossy(&response);
     println!("Received '{}' from server", received_data);
 
     Ok(())
@@ -0,0 +1,96 @@
+// The echo server on tokio, with a session per peer: takes the same options
+// as `udp_server`, answers each peer at a limited rate and forgets peers that
+// went quiet. It echoes plain datagrams (try `nc -u 127.0.0.1 8080`), not
+// the request/response packets `udp_client` sends, so it is what
+// `udp_client bench` runs against; raise --rate for that, the default drops
+// most of what a benchmark sends.
+use std::{env, process, str, thread};
+use tokio::net::UdpSocket;
+use udp_server::discovery::Responder;
+use udp_server::options::SERVER_USAGE;
+use udp_server::sessions::{Handler, RateLimit, Session, SessionConfig, SessionServer};
+use udp_server::ServerOptions;
+
+const USAGE: &str = "\
+and for sessions:
+  --rate N            messages per second a peer may send [default: 100]
+  --burst N           messages a peer may send at once [default: 20]";
+
+// the rate limit, the other options are those of `udp_server`
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+) -> Result<(ServerOptions, RateLimit), String> {
+    let mut limit = RateLimit::default();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rate" => limit.messages_per_sec = parse(&arg, args.next())?,
+            "--burst" => limit.burst = parse(&arg, args.next())?,
+            _ => rest.push(arg),
+        }
+    }
+    if !limit.messages_per_sec.is_finite() || limit.messages_per_sec <= 0.0 || limit.burst == 0 {
+        return Err("--rate and --burst must be positive".to_string());
+    }
+    Ok((ServerOptions::parse(rest)?, limit))
+}
+
+fn parse<T: str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{option} needs a value"))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{value}' for {option}"))
+}
+
+// echoes like `udp_server` and says when a peer comes and goes
+struct LoggingEcho;
+
+impl Handler for LoggingEcho {
+    type State = ();
+
+    fn handle(&mut self, session: &mut Session<()>, payload: &[u8]) -> Option<Vec<u8>> {
+        if session.messages() == 1 {
+            println!("New session with {}", session.peer());
+        }
+        match str::from_utf8(payload) {
+            Ok(text) => println!("Received '{}' from {}", text, session.peer()),
+            Err(_) => println!(
+                "Received {} bytes of binary data from {}",
+                payload.len(),
+                session.peer()
+            ),
+        }
+        Some(payload.to_vec())
+    }
+
+    fn expired(&mut self, session: Session<()>) {
+        println!(
+            "Session with {} expired after {} messages",
+            session.peer(),
+            session.messages()
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let (options, rate_limit) = parse_args(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}\n{}", err, SERVER_USAGE, USAGE);
+        process::exit(2);
+    });
+    let socket = options.socket()?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    println!("Listening on {}", socket.local_addr()?);
+    if let Some(port) = options.discovery {
+        let responder = Responder::bind(port, socket.local_addr()?.port())?;
+        thread::spawn(move || responder.run());
+    }
+
+    let config = SessionConfig {
+        rate_limit,
+        ..SessionConfig::default()
+    };
+    SessionServer::new(socket, LoggingEcho, config).run().await
+}
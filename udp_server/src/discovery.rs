@@ -0,0 +1,143 @@
+// Finding the echo server on the local network.
+//
+// A client that doesn't know where the server is broadcasts a probe to the
+// discovery port. Every server listening there answers (by unicast) with the
+// port its echo service runs on; the client combines that with the address
+// the answer came from.
+//
+//     probe:  "UDP_ECHO_DISCOVER"
+//     answer: "UDP_ECHO_HERE <port>"
+use crate::socket::is_timeout;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    str,
+    time::{Duration, Instant},
+};
+
+/// The port servers listen on for probes unless told otherwise.
+pub const DISCOVERY_PORT: u16 = 8090;
+
+const PROBE: &[u8] = b"UDP_ECHO_DISCOVER";
+const ANSWER: &str = "UDP_ECHO_HERE ";
+
+/// Answers discovery probes for a service on `service_port`.
+pub struct Responder {
+    socket: UdpSocket,
+    service_port: u16,
+}
+
+impl Responder {
+    /// Listen for probes on `port` of every IPv4 address.
+    pub fn bind(port: u16, service_port: u16) -> io::Result<Responder> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        Ok(Responder {
+            socket,
+            service_port,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Wait up to `timeout` (forever with `None`) for a probe and answer it.
+    /// Returns who asked, if anyone did.
+    pub fn answer_one(&self, timeout: Option<Duration>) -> io::Result<Option<SocketAddr>> {
+        self.socket.set_read_timeout(timeout)?;
+        let mut buf = [0; 64];
+        let (len, from) = match self.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if &buf[..len] != PROBE {
+            return Ok(None);
+        }
+        let answer = format!("{ANSWER}{}", self.service_port);
+        self.socket.send_to(answer.as_bytes(), from)?;
+        Ok(Some(from))
+    }
+
+    /// Answer probes until an error occurs.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            self.answer_one(None)?;
+        }
+    }
+}
+
+/// Broadcast a probe to `broadcast` (e.g. `255.255.255.255:8090`) and return
+/// the address of the first server that answers within `timeout`.
+///
+/// # Errors
+///
+/// Returns [`io::ErrorKind::TimedOut`] if nobody answered.
+pub fn discover(broadcast: SocketAddr, timeout: Duration) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(PROBE, broadcast)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 64];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no server answered on {broadcast}"),
+            ));
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        let port = str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|answer| answer.strip_prefix(ANSWER))
+            .and_then(|port| port.parse().ok());
+        if let Some(port) = port {
+            return Ok(SocketAddr::new(from.ip(), port));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn finds_the_server_by_broadcast() {
+        let responder = Responder::bind(0, 8080).unwrap();
+        let port = responder.local_addr().unwrap().port();
+        let handle = thread::spawn(move || responder.answer_one(Some(Duration::from_secs(2))));
+
+        // the broadcast address of the loopback network, so nothing leaves the host
+        let broadcast = SocketAddr::from((Ipv4Addr::new(127, 255, 255, 255), port));
+        let server = discover(broadcast, Duration::from_secs(2)).unwrap();
+        assert_eq!(server, "127.0.0.1:8080".parse().unwrap());
+        assert!(handle.join().unwrap().unwrap().is_some());
+    }
+
+    #[test]
+    fn ignores_other_traffic() {
+        let responder = Responder::bind(0, 8080).unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, responder.local_addr().unwrap().port()));
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.send_to(b"hello?", addr).unwrap();
+        assert_eq!(
+            responder.answer_one(Some(Duration::from_secs(1))).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn times_out_when_nobody_answers() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = discover(silent.local_addr().unwrap(), Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
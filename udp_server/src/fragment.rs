@@ -0,0 +1,514 @@
+// Messages larger than one datagram.
+//
+// `FragmentSocket` splits every message it sends into fragments that fit a
+// typical MTU, each with an 8-byte header (big endian):
+//
+//     message id: u32, fragment index: u16, fragment count: u16
+//
+// and on the receiving side collects fragments per (sender, message id) until
+// the message is complete. Fragments may arrive in any order or twice; a
+// message whose fragments stop coming is thrown away after a timeout. What
+// incomplete messages may hold is limited per sender and in total, and a
+// fragment count that couldn't fit in the largest message with this socket's
+// fragment size is refused outright, so both ends should use the same
+// fragment size (or the receiver a smaller one). Since
+// it is a `Datagram` itself, the reliable protocol runs on top unchanged and
+// simply resends the whole message when a fragment got lost.
+use crate::socket::{Datagram, MAX_DATAGRAM};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io, mem,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Default size of a fragment including its header, small enough for the
+/// 1280-byte minimum MTU of IPv6 after the IP and UDP headers.
+pub const FRAGMENT_SIZE: usize = 1200;
+
+/// Default limit on the size of a whole message.
+pub const MAX_MESSAGE: usize = 1024 * 1024;
+
+/// Default limit on the bytes the incomplete messages of one sender hold.
+pub const MAX_PENDING_PER_PEER: usize = 4 * MAX_MESSAGE;
+
+/// Default limit on the bytes all incomplete messages hold.
+pub const MAX_PENDING: usize = 32 * MAX_MESSAGE;
+
+const HEADER_LEN: usize = 8;
+
+/// A [`Datagram`] socket that sends and receives messages of any size up to
+/// a limit, by fragmenting them.
+pub struct FragmentSocket<S = UdpSocket> {
+    inner: S,
+    fragment_size: usize,
+    max_message: usize,
+    expiry: Duration,
+    next_id: AtomicU32,
+    read_timeout: Mutex<Option<Duration>>,
+    reassembly: Mutex<Reassembly>,
+}
+
+struct Reassembly {
+    partial: HashMap<(SocketAddr, u32), Partial>,
+    // bytes held in `partial` per sender, and all of them, counting what
+    // every message takes before any of its data arrived
+    held: HashMap<SocketAddr, usize>,
+    total: usize,
+    max_per_peer: usize,
+    max_total: usize,
+    expired: u64,
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+impl Partial {
+    // memory taken by a message of `count` fragments without their data
+    fn overhead(count: usize) -> usize {
+        mem::size_of::<((SocketAddr, u32), Partial)>() + count * mem::size_of::<Option<Vec<u8>>>()
+    }
+}
+
+impl<S: Datagram> FragmentSocket<S> {
+    pub fn new(inner: S) -> FragmentSocket<S> {
+        FragmentSocket {
+            inner,
+            fragment_size: FRAGMENT_SIZE,
+            max_message: MAX_MESSAGE,
+            expiry: Duration::from_secs(5),
+            next_id: AtomicU32::new(0),
+            read_timeout: Mutex::new(None),
+            reassembly: Mutex::new(Reassembly::new(MAX_PENDING_PER_PEER, MAX_PENDING)),
+        }
+    }
+
+    /// Size of every fragment including its header, at most one datagram.
+    pub fn with_fragment_size(mut self, size: usize) -> FragmentSocket<S> {
+        self.fragment_size = size.clamp(HEADER_LEN + 1, MAX_DATAGRAM);
+        self
+    }
+
+    /// Largest message that is sent or reassembled.
+    pub fn with_max_message(mut self, size: usize) -> FragmentSocket<S> {
+        self.max_message = size;
+        self
+    }
+
+    /// How many bytes of incomplete messages, including what each one takes
+    /// to keep track of its fragments, are held from one sender and from all
+    /// of them. A fragment that doesn't fit throws away the message it
+    /// belongs to.
+    pub fn with_max_pending(mut self, per_peer: usize, total: usize) -> FragmentSocket<S> {
+        let reassembly = self.reassembly.get_mut().unwrap();
+        reassembly.max_per_peer = per_peer;
+        reassembly.max_total = total;
+        self
+    }
+
+    /// How long an incomplete message waits for its missing fragments.
+    pub fn with_expiry(mut self, expiry: Duration) -> FragmentSocket<S> {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// How many incomplete messages were thrown away so far, because they
+    /// took too long or grew too large.
+    pub fn expired(&self) -> u64 {
+        self.reassembly.lock().unwrap().expired
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.fragment_size - HEADER_LEN
+    }
+}
+
+impl<S: Datagram> Datagram for FragmentSocket<S> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if buf.len() > self.max_message() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message of {} bytes is too large", buf.len()),
+            ));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // an empty message is still one (empty) fragment
+        let count = buf.len().div_ceil(self.chunk_size()).max(1);
+        let mut datagram = Vec::with_capacity(self.fragment_size);
+        for index in 0..count {
+            let start = index * self.chunk_size();
+            let end = (start + self.chunk_size()).min(buf.len());
+            datagram.clear();
+            datagram.extend_from_slice(&id.to_be_bytes());
+            datagram.extend_from_slice(&(index as u16).to_be_bytes());
+            datagram.extend_from_slice(&(count as u16).to_be_bytes());
+            datagram.extend_from_slice(&buf[start..end]);
+            self.inner.send_to(&datagram, addr)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Receive the next complete message. Like a datagram, a message longer
+    /// than `buf` is cut off.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|t| Instant::now() + t);
+        let mut reassembly = self.reassembly.lock().unwrap();
+        let mut datagram = vec![0; MAX_DATAGRAM];
+        loop {
+            // the timeout is for the whole message, not each fragment
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                self.inner.set_read_timeout(Some(left))?;
+            }
+            let (len, from) = self.inner.recv_from(&mut datagram)?;
+            reassembly.expire(self.expiry);
+            let fragment = &datagram[..len];
+            if let Some(message) =
+                reassembly.add(from, fragment, self.max_message, self.chunk_size())
+            {
+                let len = message.len().min(buf.len());
+                buf[..len].copy_from_slice(&message[..len]);
+                return Ok((len, from));
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_message(&self) -> usize {
+        // the count in the header limits how many fragments a message has
+        self.max_message.min(self.chunk_size() * u16::MAX as usize)
+    }
+}
+
+impl Reassembly {
+    fn new(max_per_peer: usize, max_total: usize) -> Reassembly {
+        Reassembly {
+            partial: HashMap::new(),
+            held: HashMap::new(),
+            total: 0,
+            max_per_peer,
+            max_total,
+            expired: 0,
+        }
+    }
+
+    fn expire(&mut self, expiry: Duration) {
+        let expired: Vec<_> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() >= expiry)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in expired {
+            self.throw_away(key);
+        }
+    }
+
+    // the message `datagram` completes, if any; malformed fragments are dropped
+    fn add(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+        max_message: usize,
+        chunk_size: usize,
+    ) -> Option<Vec<u8>> {
+        let (header, data) = datagram.split_at_checked(HEADER_LEN)?;
+        let id = u32::from_be_bytes(header[..4].try_into().ok()?);
+        let index = u16::from_be_bytes(header[4..6].try_into().ok()?) as usize;
+        let count = u16::from_be_bytes(header[6..].try_into().ok()?) as usize;
+        if index >= count {
+            return None;
+        }
+        if count == 1 {
+            return (data.len() <= max_message).then(|| data.to_vec());
+        }
+        // more fragments than the largest message is cut into
+        if count > max_message.div_ceil(chunk_size) {
+            return None;
+        }
+
+        let key = (from, id);
+        if let Entry::Vacant(entry) = self.partial.entry(key) {
+            // a new message is charged for its slots up front, so that empty
+            // fragments can't make room for any number of them
+            entry.insert(Partial {
+                fragments: vec![None; count],
+                missing: count,
+                bytes: 0,
+                started: Instant::now(),
+            });
+            self.charge(from, Partial::overhead(count));
+        }
+        let held = self.held[&from];
+        let partial = self.partial.get_mut(&key)?;
+        if partial.fragments.len() != count || partial.fragments[index].is_some() {
+            // a duplicate, or not a fragment of this message after all
+            return None;
+        }
+        if partial.bytes + data.len() > max_message
+            || held + data.len() > self.max_per_peer
+            || self.total + data.len() > self.max_total
+        {
+            self.throw_away(key);
+            return None;
+        }
+        partial.fragments[index] = Some(data.to_vec());
+        partial.missing -= 1;
+        partial.bytes += data.len();
+        let complete = partial.missing == 0;
+        self.charge(from, data.len());
+        if !complete {
+            return None;
+        }
+
+        let partial = self.remove(key)?;
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    // an incomplete message that will never be
+    fn throw_away(&mut self, key: (SocketAddr, u32)) {
+        if self.remove(key).is_some() {
+            self.expired += 1;
+        }
+    }
+
+    fn charge(&mut self, from: SocketAddr, bytes: usize) {
+        *self.held.entry(from).or_default() += bytes;
+        self.total += bytes;
+    }
+
+    fn remove(&mut self, (from, id): (SocketAddr, u32)) -> Option<Partial> {
+        let partial = self.partial.remove(&(from, id))?;
+        let bytes = partial.bytes + Partial::overhead(partial.fragments.len());
+        self.total -= bytes;
+        if let Some(held) = self.held.get_mut(&from) {
+            *held -= bytes;
+            if *held == 0 {
+                self.held.remove(&from);
+            }
+        }
+        Some(partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reliable::{Client, RetryPolicy, Server},
+        socket::LossySocket,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").unwrap()
+    }
+
+    // bytes that are certainly not UTF-8
+    fn binary(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8 | 0x80).collect()
+    }
+
+    fn fragment(id: u32, index: u16, count: u16, data: &[u8]) -> Vec<u8> {
+        let mut datagram = id.to_be_bytes().to_vec();
+        datagram.extend_from_slice(&index.to_be_bytes());
+        datagram.extend_from_slice(&count.to_be_bytes());
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    #[test]
+    fn large_binary_message_round_trip() {
+        let sender = FragmentSocket::new(bind());
+        let receiver = FragmentSocket::new(bind());
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        let message = binary(60_000);
+        assert_eq!(sender.send_to(&message, to).unwrap(), 60_000);
+        sender.send_to(b"", to).unwrap();
+
+        let mut buf = vec![0; MAX_MESSAGE];
+        let (len, from) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(from, sender.local_addr().unwrap());
+        assert_eq!(&buf[..len], message);
+        assert_eq!(receiver.recv_from(&mut buf).unwrap().0, 0);
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_duplicate_fragments() {
+        let raw = bind();
+        let receiver = FragmentSocket::new(bind());
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        for (index, data) in [(2, "c"), (0, "a"), (2, "c"), (1, "b")] {
+            raw.send_to(&fragment(9, index, 3, data.as_bytes()), to)
+                .unwrap();
+        }
+        let mut buf = [0; 16];
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"abc");
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let raw = bind();
+        let receiver = FragmentSocket::new(bind()).with_expiry(Duration::from_millis(50));
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        raw.send_to(&fragment(1, 0, 2, b"never "), to).unwrap();
+        raw.send_to(&fragment(2, 5, 2, b"bad index"), to).unwrap();
+        let mut buf = [0; 16];
+        let err = receiver.recv_from(&mut buf).unwrap_err();
+        assert!(crate::socket::is_timeout(&err));
+
+        thread::sleep(Duration::from_millis(60));
+        raw.send_to(&fragment(3, 0, 1, b"done"), to).unwrap();
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"done");
+        assert_eq!(receiver.expired(), 1);
+
+        // the second half comes too late to complete the first message
+        raw.send_to(&fragment(1, 1, 2, b"finished"), to).unwrap();
+        assert!(receiver.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn limits_what_incomplete_messages_hold() {
+        let (first, second) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        let (two, three) = (Partial::overhead(2), Partial::overhead(3));
+        let mut reassembly = Reassembly::new(2 * two + three + 8, 3 * two + three + 10);
+        let mut add = |from, id, index, count, data: &[u8]| {
+            reassembly.add(from, &fragment(id, index, count, data), 6, 2)
+        };
+
+        // four fragments of two bytes can't make a message of six
+        assert_eq!(add(first, 1, 0, 4, b"ab"), None);
+        assert_eq!(add(first, 2, 0, 1, b"too long"), None);
+        // nor can larger fragments than the sender should send
+        assert_eq!(add(first, 3, 0, 3, b"abcd"), None);
+        assert_eq!(add(first, 3, 1, 3, b"efg"), None);
+
+        // besides the messages themselves, the first sender may hold eight
+        // bytes, both together ten
+        assert_eq!(add(first, 4, 0, 2, b"ab"), None);
+        assert_eq!(add(first, 5, 0, 3, b"abcd"), None);
+        assert_eq!(add(first, 6, 0, 2, b"xyz"), None);
+        assert_eq!(add(second, 7, 0, 2, b"ab"), None);
+        assert_eq!(add(second, 8, 0, 2, b"xyz"), None);
+        // a complete message frees its bytes again
+        assert_eq!(add(first, 4, 1, 2, b"cd").as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(add(second, 8, 0, 2, b"xyz"), None);
+
+        assert_eq!(reassembly.expired, 3);
+        assert_eq!(reassembly.total, 2 * two + three + 9);
+        assert_eq!(reassembly.held[&first], three + 4);
+        assert_eq!(reassembly.held[&second], 2 * two + 5);
+    }
+
+    #[test]
+    fn limits_messages_of_empty_fragments() {
+        let from = "127.0.0.1:1".parse().unwrap();
+        let chunk_size = FRAGMENT_SIZE - HEADER_LEN;
+        let count = MAX_MESSAGE.div_ceil(chunk_size) as u16;
+        let mut reassembly = Reassembly::new(MAX_PENDING_PER_PEER, MAX_PENDING);
+
+        // every fragment starts another message with the largest count
+        for id in 0..10_000 {
+            let datagram = fragment(id, 0, count, b"");
+            assert_eq!(
+                reassembly.add(from, &datagram, MAX_MESSAGE, chunk_size),
+                None
+            );
+        }
+        let most = MAX_PENDING_PER_PEER / Partial::overhead(count as usize);
+        assert_eq!(reassembly.partial.len(), most);
+        assert!(reassembly.held[&from] <= MAX_PENDING_PER_PEER);
+        assert_eq!(reassembly.expired, 10_000 - most as u64);
+    }
+
+    #[test]
+    fn rejects_messages_over_the_limit() {
+        let socket = FragmentSocket::new(bind()).with_max_message(10);
+        let to = bind().local_addr().unwrap();
+        let err = socket.send_to(&[0; 11], to).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reliable_requests_with_large_payloads_over_loss() {
+        let server_socket = FragmentSocket::new(LossySocket::new(bind(), 0.02, 3));
+        let mut server = Server::new(server_socket);
+        let addr = server.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                server
+                    .serve_one(Some(Duration::from_millis(20)), |payload, _| {
+                        payload.iter().rev().copied().collect()
+                    })
+                    .unwrap();
+            }
+        });
+
+        let socket = FragmentSocket::new(LossySocket::new(bind(), 0.02, 4));
+        let retry = RetryPolicy {
+            initial: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+        let mut client = Client::new(socket, addr).with_retry(retry);
+        for len in [0, 1, 5_000, 20_000] {
+            let payload = binary(len);
+            let response = client.request(&payload).unwrap();
+            assert!(response.iter().eq(payload.iter().rev()));
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,17 @@
+//! A small reliability layer for request/response over UDP, shared by
+//! `udp_server` and `udp_client`, with the options both use to find each
+//! other: plain addresses, multicast groups and broadcast discovery.
+pub mod discovery;
+pub mod fragment;
+pub mod multicast;
+pub mod options;
+pub mod reliable;
+pub mod sessions;
+pub mod socket;
+
+pub use fragment::FragmentSocket;
+pub use multicast::MulticastOptions;
+pub use options::{ClientOptions, Peer, ServerOptions};
+pub use reliable::{Client, Packet, RetryPolicy, Server};
+pub use sessions::{Handler, Session, SessionConfig, SessionServer};
+pub use socket::{Datagram, LossySocket, MAX_DATAGRAM};
@@ -15,7 +15,10 @@
     unused_unsafe
 )]
 use std::net::UdpSocket;
-use std::str;
+use std::{env, process, str, thread};
+use udp_server::discovery::Responder;
+use udp_server::options::SERVER_USAGE;
+use udp_server::{FragmentSocket, Server, ServerOptions};
 // what is udp?
 // UDP is a connectionless protocol. It is known as a datagram protocol because it is analogous to a
 // postal service: when the application layer (layer 7 in the OSI model) passes messages to the
@@ -30,17 +33,43 @@ use std::str;
 // UDP is also used in applications such as DNS, where the overhead of TCP is not necessary.
 
 fn main() -> std::io::Result<()> {
-    let socket = UdpSocket::bind("127.0.0.1:8080")?;
+    let options = ServerOptions::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, SERVER_USAGE);
+        process::exit(2);
+    });
+    let socket = options.socket()?;
+    match &options.multicast {
+        Some(multicast) => println!(
+            "Listening on {} and group {}",
+            socket.local_addr()?,
+            multicast.group
+        ),
+        None => println!("Listening on {}", socket.local_addr()?),
+    }
+    if let Some(port) = options.discovery {
+        let responder = Responder::bind(port, socket.local_addr()?.port())?;
+        println!("Answering discovery probes on {}", responder.local_addr()?);
+        thread::spawn(move || responder.run());
+    }
+    // messages of any size arrive in MTU-sized fragments and are put back together
+    let socket = FragmentSocket::new(socket);
+    // resent requests are answered from memory, so every request is handled once
+    let mut server = Server::new(socket);
 
     loop {
-        let mut buf = [0; 1024];
-        let (amt, src) = socket.recv_from(&mut buf)?;
-
-        let received_data = str::from_utf8(&buf[..amt]).expect("Invalid UTF-8 data received");
-        println!("Received '{}' from {}", received_data, src);
-
-        socket.send_to(&buf[..amt], src)?;
-        println!("Sent '{}' to {}", received_data, src)
+        server.serve_one(None, |payload, src| {
+            // payloads are bytes, only show them as text when they are text
+            match str::from_utf8(payload) {
+                Ok(text) => println!("Received '{}' from {}", text, src),
+                Err(_) => println!(
+                    "Received {} bytes of binary data from {}",
+                    payload.len(),
+                    src
+                ),
+            }
+            println!("Sent {} bytes back to {}", payload.len(), src);
+            payload.to_vec()
+        })?;
     }
     #[derive(Debug)]
     enum Message {
@@ -52,8 +81,7 @@ fn main() -> std::io::Result<()> {
     let m = Message::Write(String::from("hello"));
     let a = m;
     println!("{:?}", m);
-    let a = vec![1,2];
+    let a = vec![1, 2];
     let v = a;
     println!("{:?}", a);
-
 }
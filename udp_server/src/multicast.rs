@@ -0,0 +1,182 @@
+// Multicast sockets for the echo server and client.
+//
+// The server binds the group's port on every address and joins the group,
+// so several servers on one host can share the port. Clients send their
+// requests to the group; every server that joined answers with a plain
+// unicast response. std's `UdpSocket` can join groups but can't choose the
+// interface outgoing multicast leaves on, which is needed to keep it on the
+// loopback interface, so the sockets are set up with socket2.
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+/// How to use a multicast group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MulticastOptions {
+    pub group: IpAddr,
+    /// IPv4: the address of the interface to join on and send from,
+    /// unspecified lets the system pick
+    pub interface: Ipv4Addr,
+    /// IPv6: the index of the interface, 0 lets the system pick
+    pub interface_index: u32,
+    /// how many routers a datagram may cross (hop limit for IPv6)
+    pub ttl: u32,
+    /// whether the sender's own host receives what it sends
+    pub loopback: bool,
+}
+
+impl MulticastOptions {
+    pub fn new(group: IpAddr) -> MulticastOptions {
+        MulticastOptions {
+            group,
+            interface: Ipv4Addr::UNSPECIFIED,
+            interface_index: 0,
+            ttl: 1,
+            loopback: true,
+        }
+    }
+}
+
+/// A socket that receives what is sent to `options.group` on `port`.
+///
+/// # Errors
+///
+/// Returns an error if `group` is not a multicast address, or if binding or
+/// joining fails.
+pub fn bind_group(port: u16, options: &MulticastOptions) -> io::Result<UdpSocket> {
+    if !options.group.is_multicast() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a multicast address", options.group),
+        ));
+    }
+    let any: IpAddr = match options.group {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = socket(SocketAddr::new(any, port))?;
+    match options.group {
+        IpAddr::V4(group) => socket.join_multicast_v4(&group, &options.interface)?,
+        IpAddr::V6(group) => socket.join_multicast_v6(&group, options.interface_index)?,
+    }
+    // the server answers through the same socket, with the same settings
+    configure_sender(&socket, options)?;
+    Ok(socket.into())
+}
+
+/// A socket bound to `bind` that sends to `options.group` with the TTL,
+/// loopback and interface of `options`.
+pub fn sender(bind: SocketAddr, options: &MulticastOptions) -> io::Result<UdpSocket> {
+    let socket = socket(bind)?;
+    configure_sender(&socket, options)?;
+    Ok(socket.into())
+}
+
+fn socket(bind: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(bind), Type::DGRAM, Some(Protocol::UDP))?;
+    // more than one server may listen to the same group and port
+    socket.set_reuse_address(true)?;
+    socket.bind(&bind.into())?;
+    Ok(socket)
+}
+
+fn configure_sender(socket: &Socket, options: &MulticastOptions) -> io::Result<()> {
+    match options.group {
+        IpAddr::V4(_) => {
+            socket.set_multicast_ttl_v4(options.ttl)?;
+            socket.set_multicast_loop_v4(options.loopback)?;
+            if !options.interface.is_unspecified() {
+                socket.set_multicast_if_v4(&options.interface)?;
+            }
+        }
+        IpAddr::V6(_) => {
+            socket.set_multicast_hops_v6(options.ttl)?;
+            socket.set_multicast_loop_v6(options.loopback)?;
+            if options.interface_index != 0 {
+                socket.set_multicast_if_v6(options.interface_index)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reliable::{Client, RetryPolicy, Server};
+    use std::{thread, time::Duration};
+
+    // a group on the loopback interface, so the test needs no network
+    fn loopback_group(last: u8) -> MulticastOptions {
+        MulticastOptions {
+            interface: Ipv4Addr::LOCALHOST,
+            ..MulticastOptions::new(Ipv4Addr::new(239, 255, 42, last).into())
+        }
+    }
+
+    #[test]
+    fn every_member_receives_group_datagrams() {
+        let options = loopback_group(1);
+        let first = bind_group(0, &options).unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second = bind_group(port, &options).unwrap();
+
+        let sender = sender("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        sender
+            .send_to(b"to all", SocketAddr::new(options.group, port))
+            .unwrap();
+        for member in [first, second] {
+            member
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            let mut buf = [0; 16];
+            let (len, _) = member.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"to all");
+        }
+    }
+
+    #[test]
+    fn sender_takes_ttl_and_loopback() {
+        let options = MulticastOptions {
+            ttl: 4,
+            loopback: false,
+            ..loopback_group(2)
+        };
+        let sender = sender("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        // lo delivers everything it sends to itself anyway, so check the
+        // settings rather than whether a datagram arrives
+        let socket = socket2::SockRef::from(&sender);
+        assert_eq!(socket.multicast_ttl_v4().unwrap(), 4);
+        assert!(!socket.multicast_loop_v4().unwrap());
+    }
+
+    #[test]
+    fn request_to_a_group_is_answered_by_unicast() {
+        let options = loopback_group(3);
+        let mut server = Server::new(bind_group(0, &options).unwrap());
+        let group = SocketAddr::new(options.group, server.local_addr().unwrap().port());
+        let handle = thread::spawn(move || {
+            server
+                .serve_one(Some(Duration::from_secs(2)), |payload, _| payload.to_vec())
+                .unwrap()
+        });
+
+        let socket = sender("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let retry = RetryPolicy {
+            timeout: Duration::from_secs(2),
+            ..RetryPolicy::default()
+        };
+        let mut client = Client::new(socket, group).with_retry(retry);
+        assert_eq!(client.request(b"anyone there?").unwrap(), b"anyone there?");
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn rejects_unicast_groups() {
+        let options = MulticastOptions::new(Ipv4Addr::LOCALHOST.into());
+        let err = bind_group(0, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,375 @@
+// Command line options of `udp_server` and `udp_client`.
+//
+// The server listens on one address, or on a multicast group, and can also
+// answer discovery probes. The client either knows the server's address,
+// sends to a multicast group, or finds the server by broadcast. The client
+// binds an ephemeral port of the server's address family unless told
+// otherwise, so any number of clients can run at once.
+use crate::{discovery, multicast::MulticastOptions};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+pub const SERVER_USAGE: &str = "\
+Usage: udp_server [OPTIONS]
+
+Options:
+  --bind ADDR         address to listen on [default: 127.0.0.1:8080]
+  --multicast GROUP   also receive requests sent to GROUP on the bind port
+  --interface IF      interface for multicast: an IPv4 address or an IPv6 index
+  --ttl N             hops multicast responses may travel [default: 1]
+  --no-loopback       don't deliver our own multicast to this host
+  --discovery PORT    answer broadcast discovery probes on PORT";
+
+pub const CLIENT_USAGE: &str = "\
+Usage: udp_client [OPTIONS] [MESSAGE]
+       udp_client bench [OPTIONS]
+
+Options:
+  --server ADDR          where the server listens [default: 127.0.0.1:8080]
+  --discover ADDR        find the server by a broadcast to ADDR,
+                         e.g. 255.255.255.255:8090
+  --multicast GROUP:PORT send the request to a multicast group
+  --bind ADDR            local address [default: port 0 of any address]
+  --interface IF         interface for multicast: an IPv4 address or an IPv6 index
+  --ttl N                hops the request may travel [default: 1]
+  --no-loopback          don't deliver our own multicast to this host";
+
+/// How long the client waits for discovery answers.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerOptions {
+    pub bind: SocketAddr,
+    pub multicast: Option<MulticastOptions>,
+    /// the port to answer discovery probes on
+    pub discovery: Option<u16>,
+}
+
+/// Where the client sends its request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Addr(SocketAddr),
+    /// find the server by broadcasting a probe to this address
+    Discover(SocketAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// `None` binds port 0 of the unspecified address of the peer's family
+    pub bind: Option<SocketAddr>,
+    pub peer: Peer,
+    /// set when the peer is a multicast group
+    pub multicast: Option<MulticastOptions>,
+    pub message: String,
+}
+
+// what `--interface`, `--ttl` and `--no-loopback` say, applied to the group
+// once it is known
+#[derive(Default)]
+struct GroupSettings {
+    interface: Option<Interface>,
+    ttl: Option<u32>,
+    no_loopback: bool,
+}
+
+enum Interface {
+    V4(Ipv4Addr),
+    Index(u32),
+}
+
+impl GroupSettings {
+    // returns whether `arg` was one of ours
+    fn parse(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, String> {
+        match arg {
+            "--interface" => {
+                let value = value(arg, args)?;
+                self.interface = Some(match value.parse::<Ipv4Addr>() {
+                    Ok(addr) => Interface::V4(addr),
+                    Err(_) => Interface::Index(parse(arg, &value)?),
+                });
+            }
+            "--ttl" => self.ttl = Some(parse(arg, &value(arg, args)?)?),
+            "--no-loopback" => self.no_loopback = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn apply(self, group: IpAddr) -> Result<MulticastOptions, String> {
+        let mut options = MulticastOptions::new(group);
+        match (self.interface, group) {
+            (Some(Interface::V4(addr)), IpAddr::V4(_)) => options.interface = addr,
+            (Some(Interface::Index(index)), IpAddr::V6(_)) => options.interface_index = index,
+            (Some(_), _) => {
+                return Err(format!(
+                    "--interface takes an IPv4 address for an IPv4 group and an index for an IPv6 group, got group {group}"
+                ))
+            }
+            (None, _) => {}
+        }
+        if let Some(ttl) = self.ttl {
+            options.ttl = ttl;
+        }
+        options.loopback = !self.no_loopback;
+        Ok(options)
+    }
+
+    fn is_set(&self) -> bool {
+        self.interface.is_some() || self.ttl.is_some() || self.no_loopback
+    }
+}
+
+impl ServerOptions {
+    /// Parse the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<ServerOptions, String> {
+        let mut bind = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+        let mut group = None;
+        let mut discovery = None;
+        let mut settings = GroupSettings::default();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if settings.parse(&arg, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--bind" => bind = parse(&arg, &value(&arg, &mut args)?)?,
+                "--multicast" => group = Some(parse::<IpAddr>(&arg, &value(&arg, &mut args)?)?),
+                "--discovery" => discovery = Some(parse(&arg, &value(&arg, &mut args)?)?),
+                _ => return Err(format!("unexpected argument '{arg}'")),
+            }
+        }
+
+        let multicast = match group {
+            Some(group) => Some(settings.apply(group)?),
+            None if settings.is_set() => {
+                return Err("--interface, --ttl and --no-loopback need --multicast".to_string())
+            }
+            None => None,
+        };
+        Ok(ServerOptions {
+            bind,
+            multicast,
+            discovery,
+        })
+    }
+
+    /// The socket to serve on: the bind address, or the group on the bind
+    /// port.
+    pub fn socket(&self) -> io::Result<UdpSocket> {
+        match &self.multicast {
+            Some(multicast) => crate::multicast::bind_group(self.bind.port(), multicast),
+            None => UdpSocket::bind(self.bind),
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Parse the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<ClientOptions, String> {
+        let mut bind = None;
+        let mut peer = None;
+        let mut group = None;
+        let mut message = None;
+        let mut settings = GroupSettings::default();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if settings.parse(&arg, &mut args)? {
+                continue;
+            }
+            let new_peer = match arg.as_str() {
+                "--bind" => {
+                    bind = Some(parse(&arg, &value(&arg, &mut args)?)?);
+                    None
+                }
+                "--server" => Some(Peer::Addr(parse(&arg, &value(&arg, &mut args)?)?)),
+                "--discover" => Some(Peer::Discover(parse(&arg, &value(&arg, &mut args)?)?)),
+                "--multicast" => {
+                    let addr: SocketAddr = parse(&arg, &value(&arg, &mut args)?)?;
+                    if !addr.ip().is_multicast() {
+                        return Err(format!("{} is not a multicast address", addr.ip()));
+                    }
+                    group = Some(addr.ip());
+                    Some(Peer::Addr(addr))
+                }
+                _ if arg.starts_with("--") => return Err(format!("unexpected argument '{arg}'")),
+                _ if message.is_none() => {
+                    message = Some(arg);
+                    None
+                }
+                _ => return Err(format!("unexpected argument '{arg}'")),
+            };
+            if let Some(new_peer) = new_peer {
+                if peer.is_some() {
+                    return Err(
+                        "--server, --discover and --multicast can't be combined".to_string()
+                    );
+                }
+                peer = Some(new_peer);
+            }
+        }
+
+        let multicast = match group {
+            Some(group) => Some(settings.apply(group)?),
+            None if settings.is_set() => {
+                return Err("--interface, --ttl and --no-loopback need --multicast".to_string())
+            }
+            None => None,
+        };
+        Ok(ClientOptions {
+            bind,
+            peer: peer.unwrap_or(Peer::Addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)))),
+            multicast,
+            message: message.unwrap_or_else(|| "Hello, UDP server!".to_string()),
+        })
+    }
+
+    /// Find the server (by discovery if asked to) and open a socket to talk
+    /// to it.
+    pub fn connect(&self) -> io::Result<(UdpSocket, SocketAddr)> {
+        let server = self.server()?;
+        Ok((self.socket(server)?, server))
+    }
+
+    /// The server's address, found by discovery if asked to.
+    pub fn server(&self) -> io::Result<SocketAddr> {
+        match self.peer {
+            Peer::Addr(addr) => Ok(addr),
+            Peer::Discover(broadcast) => discovery::discover(broadcast, DISCOVERY_TIMEOUT),
+        }
+    }
+
+    /// A new socket to talk to `server` from; every call gets its own port
+    /// unless `--bind` gave one.
+    pub fn socket(&self, server: SocketAddr) -> io::Result<UdpSocket> {
+        let bind = self.bind.unwrap_or_else(|| ephemeral(server));
+        match &self.multicast {
+            Some(multicast) => crate::multicast::sender(bind, multicast),
+            None => UdpSocket::bind(bind),
+        }
+    }
+}
+
+/// Port 0 of the unspecified address of `peer`'s family, so the system
+/// picks a free port.
+pub fn ephemeral(peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+fn value(option: &str, args: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{option} needs a value"))
+}
+
+fn parse<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{value}' for {option}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reliable::{Client, Server};
+    use std::thread;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn server_defaults() {
+        let options = ServerOptions::parse(args("")).unwrap();
+        assert_eq!(options.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(options.multicast, None);
+        assert_eq!(options.discovery, None);
+    }
+
+    #[test]
+    fn server_multicast() {
+        let options = ServerOptions::parse(args(
+            "--bind 0.0.0.0:9000 --multicast 239.1.2.3 --ttl 4 --no-loopback --interface 127.0.0.1 --discovery 8090",
+        ))
+        .unwrap();
+        let multicast = options.multicast.unwrap();
+        assert_eq!(multicast.group, "239.1.2.3".parse::<IpAddr>().unwrap());
+        assert_eq!(multicast.ttl, 4);
+        assert!(!multicast.loopback);
+        assert_eq!(multicast.interface, Ipv4Addr::LOCALHOST);
+        assert_eq!(options.discovery, Some(8090));
+
+        let options = ServerOptions::parse(args("--multicast ff02::1234 --interface 2")).unwrap();
+        assert_eq!(options.multicast.unwrap().interface_index, 2);
+    }
+
+    #[test]
+    fn client_options() {
+        let options = ClientOptions::parse(args("--server [::1]:9000 hi")).unwrap();
+        assert_eq!(options.peer, Peer::Addr("[::1]:9000".parse().unwrap()));
+        assert_eq!(options.message, "hi");
+        assert_eq!(options.bind, None);
+
+        let options = ClientOptions::parse(args("--discover 255.255.255.255:8090")).unwrap();
+        assert_eq!(
+            options.peer,
+            Peer::Discover("255.255.255.255:8090".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_combinations() {
+        let errors = [
+            ClientOptions::parse(args("--server 127.0.0.1:1 --discover 127.0.0.1:2")),
+            ClientOptions::parse(args("--multicast 10.0.0.1:80")),
+            ClientOptions::parse(args("--ttl 2")),
+            ClientOptions::parse(args("--server")),
+            ClientOptions::parse(args("one two")),
+        ];
+        for error in errors {
+            assert!(error.is_err(), "{error:?}");
+        }
+        assert!(ServerOptions::parse(args("--multicast 239.1.2.3 --interface 3")).is_err());
+        assert!(ServerOptions::parse(args("--bind nowhere")).is_err());
+    }
+
+    #[test]
+    fn clients_get_ephemeral_ports() {
+        let options = ClientOptions::parse(args("")).unwrap();
+        let (first, server) = options.connect().unwrap();
+        let (second, _) = options.connect().unwrap();
+        assert_eq!(server, "127.0.0.1:8080".parse().unwrap());
+        assert_ne!(
+            first.local_addr().unwrap().port(),
+            second.local_addr().unwrap().port()
+        );
+    }
+
+    #[test]
+    fn echo_over_ipv6() {
+        let options = ServerOptions::parse(args("--bind [::1]:0")).unwrap();
+        let mut server = Server::new(options.socket().unwrap());
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            server
+                .serve_one(Some(Duration::from_secs(2)), |payload, _| payload.to_vec())
+                .unwrap()
+        });
+
+        let options = ClientOptions::parse(vec!["--server".to_string(), addr.to_string()]).unwrap();
+        let (socket, server) = options.connect().unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv6());
+        let mut client = Client::new(socket, server);
+        assert_eq!(client.request(b"over v6").unwrap(), b"over v6");
+        assert!(handle.join().unwrap());
+    }
+}
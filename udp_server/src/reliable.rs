@@ -0,0 +1,544 @@
+// Request/response over UDP that survives lost and duplicated datagrams.
+//
+// Every datagram starts with a kind byte and a 32-bit sequence number (big
+// endian), followed by the payload:
+//
+//     request:  1, seq, payload   client -> server
+//     response: 2, seq, payload   server -> client, answers request `seq`
+//     ack:      3, seq            client -> server, response `seq` arrived
+//
+// The client resends a request with exponential backoff until the response
+// arrives or its deadline passes. The server remembers the response it sent
+// for every (peer, seq), so a resent request gets the same response again
+// instead of running the handler twice. The ack lets the server drop the
+// stored response early; the (peer, seq) itself is kept a while longer so
+// that late duplicates are still recognised. What the server remembers is
+// capped per peer and in total, oldest first, so a peer that floods it with
+// new sequence numbers only makes it forget sooner.
+use crate::socket::{is_timeout, Datagram};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const HEADER_LEN: usize = 5;
+const REQUEST: u8 = 1;
+const RESPONSE: u8 = 2;
+const ACK: u8 = 3;
+
+/// One datagram of the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Request { seq: u32, payload: Vec<u8> },
+    Response { seq: u32, payload: Vec<u8> },
+    Ack { seq: u32 },
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, seq, payload) = match self {
+            Packet::Request { seq, payload } => (REQUEST, seq, &payload[..]),
+            Packet::Response { seq, payload } => (RESPONSE, seq, &payload[..]),
+            Packet::Ack { seq } => (ACK, seq, &[][..]),
+        };
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.push(kind);
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// `None` for anything that isn't a packet of this protocol.
+    pub fn decode(buf: &[u8]) -> Option<Packet> {
+        let (header, payload) = buf.split_at_checked(HEADER_LEN)?;
+        let seq = u32::from_be_bytes(header[1..].try_into().ok()?);
+        let payload = payload.to_vec();
+        match header[0] {
+            REQUEST => Some(Packet::Request { seq, payload }),
+            RESPONSE => Some(Packet::Response { seq, payload }),
+            ACK if payload.is_empty() => Some(Packet::Ack { seq }),
+            _ => None,
+        }
+    }
+}
+
+/// How long and how often a [`Client`] resends a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// wait this long for the response before the first resend
+    pub initial: Duration,
+    /// the wait doubles after every resend, up to this
+    pub max: Duration,
+    /// give up once the request has gone unanswered this long
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Sends requests to one server and waits for their responses.
+pub struct Client<S = UdpSocket> {
+    socket: S,
+    server: SocketAddr,
+    next_seq: u32,
+    retry: RetryPolicy,
+    retransmissions: u64,
+    buf: Vec<u8>,
+}
+
+impl<S: Datagram> Client<S> {
+    pub fn new(socket: S, server: SocketAddr) -> Client<S> {
+        // start somewhere new every run, so a restarted client on the same
+        // port isn't mistaken for a duplicate of its previous run
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        Client {
+            socket,
+            server,
+            next_seq: nanos,
+            retry: RetryPolicy::default(),
+            retransmissions: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Client<S> {
+        self.retry = retry;
+        self
+    }
+
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    /// How many requests had to be sent more than once.
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions
+    }
+
+    /// Send `payload` and wait for the server's response.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`io::ErrorKind::TimedOut`] if no response arrived within the
+    /// [`RetryPolicy`]'s timeout, [`io::ErrorKind::InvalidInput`] if the
+    /// payload is larger than the socket's [`Datagram::max_message`], and
+    /// any error of the socket.
+    pub fn request(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let max_message = self.socket.max_message();
+        if payload.len() > max_message - HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload of {} bytes doesn't fit in a message",
+                    payload.len()
+                ),
+            ));
+        }
+        self.buf.resize(max_message, 0);
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let request = Packet::Request {
+            seq,
+            payload: payload.to_vec(),
+        }
+        .encode();
+
+        let deadline = Instant::now() + self.retry.timeout;
+        let mut wait = self.retry.initial;
+        loop {
+            self.socket.send_to(&request, self.server)?;
+            let resend_at = (Instant::now() + wait).min(deadline);
+            if let Some(response) = self.receive(seq, resend_at)? {
+                return Ok(response);
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "no response from {} in {:?}",
+                        self.server, self.retry.timeout
+                    ),
+                ));
+            }
+            wait = (wait * 2).min(self.retry.max);
+            self.retransmissions += 1;
+        }
+    }
+
+    // wait until `until` for the response to `seq`, skipping everything else
+    fn receive(&mut self, seq: u32, until: Instant) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            let (len, from) = match self.socket.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e) if is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            // a request sent to a multicast group is answered by whichever
+            // member got it, from its own address
+            if from != self.server && !self.server.ip().is_multicast() {
+                continue;
+            }
+            // a response to an earlier request is a duplicate that came late,
+            // acking it again is harmless and spares the server more resends
+            if let Some(Packet::Response {
+                seq: answered,
+                payload,
+            }) = Packet::decode(&self.buf[..len])
+            {
+                self.socket
+                    .send_to(&Packet::Ack { seq: answered }.encode(), from)?;
+                if answered == seq {
+                    return Ok(Some(payload));
+                }
+            }
+        }
+    }
+}
+
+// what the server remembers about a request it has answered
+struct Answered {
+    // dropped once the client acked it
+    response: Option<Vec<u8>>,
+    at: Instant,
+}
+
+/// Answers requests with a handler, at most once per request.
+pub struct Server<S = UdpSocket> {
+    socket: S,
+    answered: HashMap<(SocketAddr, u32), Answered>,
+    // every (peer, seq) as it was answered, oldest first; entries forgotten
+    // because of `per_peer` stay here until they come up
+    order: VecDeque<(SocketAddr, u32, Instant)>,
+    // the seqs of each peer in `answered`, oldest first
+    peers: HashMap<SocketAddr, VecDeque<u32>>,
+    // how long a request is remembered to catch duplicates
+    memory: Duration,
+    per_peer: usize,
+    total: usize,
+    duplicates: u64,
+    buf: Vec<u8>,
+}
+
+impl<S: Datagram> Server<S> {
+    pub fn new(socket: S) -> Server<S> {
+        Server {
+            socket,
+            answered: HashMap::new(),
+            order: VecDeque::new(),
+            peers: HashMap::new(),
+            memory: Duration::from_secs(30),
+            per_peer: 256,
+            total: 16384,
+            duplicates: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Remember at most `per_peer` requests of one peer and `total` of all
+    /// of them (at least one each); beyond that the oldest are forgotten
+    /// before their time and would be handled again if they came back.
+    pub fn with_limits(mut self, per_peer: usize, total: usize) -> Server<S> {
+        self.per_peer = per_peer.max(1);
+        self.total = total.max(1);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// How many requests arrived again after they were answered.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Wait up to `timeout` (forever with `None`) for one datagram and handle
+    /// it. Returns whether a datagram arrived.
+    ///
+    /// `handler` is called with the payload and sender of every new request
+    /// and returns the response.
+    pub fn serve_one<F>(&mut self, timeout: Option<Duration>, mut handler: F) -> io::Result<bool>
+    where
+        F: FnMut(&[u8], SocketAddr) -> Vec<u8>,
+    {
+        self.socket.set_read_timeout(timeout)?;
+        self.buf.resize(self.socket.max_message(), 0);
+        let (len, peer) = match self.socket.recv_from(&mut self.buf) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        self.expire();
+
+        match Packet::decode(&self.buf[..len]) {
+            Some(Packet::Request { seq, payload }) => {
+                if let Some(answered) = self.answered.get(&(peer, seq)) {
+                    self.duplicates += 1;
+                    // the response got lost, send it again without asking the handler
+                    if let Some(response) = &answered.response {
+                        self.socket.send_to(response, peer)?;
+                    }
+                    return Ok(true);
+                }
+                let payload = handler(&payload, peer);
+                let response = Packet::Response { seq, payload }.encode();
+                self.socket.send_to(&response, peer)?;
+                self.remember(peer, seq, response);
+            }
+            Some(Packet::Ack { seq }) => {
+                if let Some(answered) = self.answered.get_mut(&(peer, seq)) {
+                    answered.response = None;
+                }
+            }
+            // responses only go to clients, and garbage is ignored
+            Some(Packet::Response { .. }) | None => {}
+        }
+        Ok(true)
+    }
+
+    fn remember(&mut self, peer: SocketAddr, seq: u32, response: Vec<u8>) {
+        let at = Instant::now();
+        let seqs = self.peers.entry(peer).or_default();
+        if seqs.len() >= self.per_peer {
+            if let Some(oldest) = seqs.pop_front() {
+                self.answered.remove(&(peer, oldest));
+            }
+        }
+        seqs.push_back(seq);
+        let answered = Answered {
+            response: Some(response),
+            at,
+        };
+        self.answered.insert((peer, seq), answered);
+        self.order.push_back((peer, seq, at));
+        self.expire();
+    }
+
+    // forget what is too old or too much, looking no further than the
+    // first entry that is neither
+    fn expire(&mut self) {
+        while let Some(&(peer, seq, at)) = self.order.front() {
+            if at.elapsed() < self.memory && self.order.len() <= self.total {
+                break;
+            }
+            self.order.pop_front();
+            // unless `per_peer` forgot it already (and it came back since)
+            if self.answered.get(&(peer, seq)).is_none_or(|a| a.at != at) {
+                continue;
+            }
+            self.answered.remove(&(peer, seq));
+            // the peer's oldest, as both are in the order they were answered
+            if let Some(seqs) = self.peers.get_mut(&peer) {
+                seqs.pop_front();
+                if seqs.is_empty() {
+                    self.peers.remove(&peer);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::LossySocket;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").unwrap()
+    }
+
+    // runs `server` in a thread until the returned flag is set; the handler
+    // echoes the payload in upper case and counts its calls in `calls`
+    fn spawn_server<S: Datagram + Send + 'static>(
+        mut server: Server<S>,
+        calls: Arc<AtomicUsize>,
+    ) -> (Arc<AtomicBool>, thread::JoinHandle<u64>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                server
+                    .serve_one(Some(Duration::from_millis(20)), |payload, _| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        payload.to_ascii_uppercase()
+                    })
+                    .unwrap();
+            }
+            server.duplicates()
+        });
+        (stop, handle)
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(80),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let packets = [
+            Packet::Request {
+                seq: 7,
+                payload: b"hi".to_vec(),
+            },
+            Packet::Response {
+                seq: u32::MAX,
+                payload: Vec::new(),
+            },
+            Packet::Ack { seq: 0 },
+        ];
+        for packet in packets {
+            assert_eq!(Packet::decode(&packet.encode()), Some(packet));
+        }
+        assert_eq!(Packet::decode(b"\x01\x00"), None);
+        assert_eq!(Packet::decode(b"\x09\x00\x00\x00\x01"), None);
+    }
+
+    #[test]
+    fn request_and_response_on_loopback() {
+        let server = Server::new(bind());
+        let addr = server.local_addr().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (stop, handle) = spawn_server(server, Arc::clone(&calls));
+
+        let mut client = Client::new(bind(), addr);
+        assert_eq!(client.request(b"hello").unwrap(), b"HELLO");
+        assert_eq!(client.request(&[0xff, 0x00]).unwrap(), [0xff, 0x00]);
+        assert_eq!(client.retransmissions(), 0);
+
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(handle.join().unwrap(), 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn survives_packet_loss_without_running_requests_twice() {
+        // a third of the datagrams in both directions never arrive
+        let server = Server::new(LossySocket::new(bind(), 0.33, 1));
+        let addr = server.local_addr().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (stop, handle) = spawn_server(server, Arc::clone(&calls));
+
+        let mut client =
+            Client::new(LossySocket::new(bind(), 0.33, 2), addr).with_retry(fast_retry());
+        for i in 0..50 {
+            let request = format!("request {i}");
+            let response = client.request(request.as_bytes()).unwrap();
+            assert_eq!(response, request.to_uppercase().as_bytes());
+        }
+        assert!(client.retransmissions() > 0);
+        assert!(client.socket().dropped() > 0);
+
+        stop.store(true, Ordering::Relaxed);
+        let duplicates = handle.join().unwrap();
+        // lost responses were resent from memory, not recomputed
+        assert!(duplicates > 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 50);
+    }
+
+    #[test]
+    fn forgets_the_oldest_requests_beyond_its_limits() {
+        let mut server = Server::new(bind()).with_limits(2, 3);
+        let addr = server.local_addr().unwrap();
+        let (first, second) = (bind(), bind());
+        let mut calls = 0;
+        // sends request `seq` from `client` and serves it, counting handler calls
+        let mut send = |client: &UdpSocket, seq| {
+            let request = Packet::Request {
+                seq,
+                payload: Vec::new(),
+            };
+            client.send_to(&request.encode(), addr).unwrap();
+            server
+                .serve_one(Some(Duration::from_secs(5)), |_, _| {
+                    calls += 1;
+                    Vec::new()
+                })
+                .unwrap();
+            calls
+        };
+
+        assert_eq!(send(&first, 1), 1);
+        assert_eq!(send(&first, 2), 2);
+        assert_eq!(send(&first, 1), 2);
+        // a third request pushes out the first's oldest
+        assert_eq!(send(&first, 3), 3);
+        assert_eq!(send(&first, 2), 3);
+        assert_eq!(send(&first, 1), 4);
+        // and the other peer's ones push out the oldest of all
+        assert_eq!(send(&second, 1), 5);
+        assert_eq!(send(&second, 2), 6);
+        assert_eq!(send(&first, 3), 7);
+        assert_eq!(send(&second, 1), 7);
+        assert_eq!(send(&first, 1), 8);
+        assert_eq!(server.answered.len(), 3);
+        assert!(server.order.len() <= 3);
+        assert!(server.peers.values().all(|seqs| seqs.len() <= 2));
+    }
+
+    #[test]
+    fn times_out_without_a_server() {
+        let silent = bind();
+        let retry = RetryPolicy {
+            timeout: Duration::from_millis(300),
+            ..fast_retry()
+        };
+        let mut client = Client::new(bind(), silent.local_addr().unwrap()).with_retry(retry);
+
+        let started = Instant::now();
+        let err = client.request(b"anyone?").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // 10, 20, 40, 80, 80... ms between the resends
+        assert!((3..=6).contains(&client.retransmissions()));
+
+        // every attempt reached the socket
+        silent
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 64];
+        let mut attempts = 0;
+        while let Ok((len, _)) = silent.recv_from(&mut buf) {
+            assert!(matches!(
+                Packet::decode(&buf[..len]),
+                Some(Packet::Request { .. })
+            ));
+            attempts += 1;
+        }
+        assert_eq!(attempts, client.retransmissions() + 1);
+    }
+
+    #[test]
+    fn rejects_payloads_that_do_not_fit() {
+        let mut client = Client::new(bind(), bind().local_addr().unwrap());
+        let err = client.request(&vec![0; crate::MAX_DATAGRAM]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,470 @@
+// An async UDP server that remembers who it talks to.
+//
+// Every peer (source address) gets a session the first time a datagram
+// arrives from it. The session carries the handler's per-peer state and is
+// dropped again once the peer has been quiet for `idle_timeout`.
+//
+// A UDP server answers whoever the source address claims to be, which can be
+// spoofed, so each session is rate-limited twice:
+// - a token bucket caps how many messages per second reach the handler,
+// - the bytes sent back may not exceed `max_amplification` times the bytes
+//   received, so the server can't be used to flood a victim with more
+//   traffic than the attacker sent.
+// The number of sessions is capped as well, so spoofed sources can't use up
+// memory.
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time};
+
+/// Per-peer limits of [`SessionServer`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// messages per second a peer may send on average
+    pub messages_per_sec: f64,
+    /// messages a peer may send at once after being quiet
+    pub burst: u32,
+    /// bytes sent to a peer may be at most this many times the bytes
+    /// received from it
+    pub max_amplification: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit {
+            messages_per_sec: 100.0,
+            burst: 20,
+            max_amplification: 3,
+        }
+    }
+}
+
+/// Settings for [`SessionServer`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionConfig {
+    /// sessions without traffic for this long are dropped
+    pub idle_timeout: Duration,
+    /// datagrams from new peers are ignored while this many sessions exist
+    pub max_sessions: usize,
+    pub rate_limit: RateLimit,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            idle_timeout: Duration::from_secs(60),
+            max_sessions: 10_000,
+            rate_limit: RateLimit::default(),
+        }
+    }
+}
+
+/// What the server knows about one peer.
+#[derive(Debug)]
+pub struct Session<S> {
+    peer: SocketAddr,
+    started: Instant,
+    last_seen: Instant,
+    messages: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    // tokens left in the message bucket and when it was last refilled
+    tokens: f64,
+    refilled: Instant,
+    /// the handler's state for this peer
+    pub state: S,
+}
+
+impl<S: Default> Session<S> {
+    fn new(peer: SocketAddr, now: Instant, limit: &RateLimit) -> Session<S> {
+        Session {
+            peer,
+            started: now,
+            last_seen: now,
+            messages: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            tokens: limit.burst as f64,
+            refilled: now,
+            state: S::default(),
+        }
+    }
+}
+
+impl<S> Session<S> {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Messages handled in this session, including the current one.
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    // takes a token from the bucket if there is one
+    fn admit(&mut self, now: Instant, limit: &RateLimit) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.messages_per_sec).min(limit.burst as f64);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn may_send(&self, len: usize, limit: &RateLimit) -> bool {
+        self.bytes_out + len as u64 <= self.bytes_in * limit.max_amplification
+    }
+}
+
+/// Handles the messages of a [`SessionServer`].
+pub trait Handler: Send + 'static {
+    /// What the handler remembers per peer, fresh for every session.
+    type State: Default + Send;
+
+    /// Handle one message, the returned bytes (if any) go back to the peer.
+    fn handle(&mut self, session: &mut Session<Self::State>, payload: &[u8]) -> Option<Vec<u8>>;
+
+    /// Called when a session is dropped for being idle.
+    fn expired(&mut self, _session: Session<Self::State>) {}
+}
+
+/// Sends every message back unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Echo;
+
+impl Handler for Echo {
+    type State = ();
+
+    fn handle(&mut self, _session: &mut Session<()>, payload: &[u8]) -> Option<Vec<u8>> {
+        Some(payload.to_vec())
+    }
+}
+
+/// Counters of a running [`SessionServer`].
+#[derive(Debug, Default)]
+pub struct Stats {
+    sessions: AtomicUsize,
+    expired: AtomicU64,
+    handled: AtomicU64,
+    rate_limited: AtomicU64,
+    amplification_limited: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Stats {
+    /// Sessions open right now.
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Sessions dropped for being idle.
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    /// Messages passed to the handler.
+    pub fn handled(&self) -> u64 {
+        self.handled.load(Ordering::SeqCst)
+    }
+
+    /// Messages dropped because the peer sent too many.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::SeqCst)
+    }
+
+    /// Responses not sent because they would amplify the peer's traffic
+    /// too much.
+    pub fn amplification_limited(&self) -> u64 {
+        self.amplification_limited.load(Ordering::SeqCst)
+    }
+
+    /// Messages from new peers ignored because there were too many sessions.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+}
+
+/// A UDP server that keeps a session per peer and hands messages to a
+/// [`Handler`].
+pub struct SessionServer<H: Handler> {
+    socket: UdpSocket,
+    handler: H,
+    config: SessionConfig,
+    sessions: HashMap<SocketAddr, Session<H::State>>,
+    stats: Arc<Stats>,
+}
+
+impl<H: Handler> SessionServer<H> {
+    pub async fn bind(addr: SocketAddr, handler: H, config: SessionConfig) -> io::Result<Self> {
+        Ok(SessionServer::new(
+            UdpSocket::bind(addr).await?,
+            handler,
+            config,
+        ))
+    }
+
+    pub fn new(socket: UdpSocket, handler: H, config: SessionConfig) -> Self {
+        SessionServer {
+            socket,
+            handler,
+            config,
+            sessions: HashMap::new(),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The server's counters, they stay readable while it runs.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Serve until receiving fails.
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut buf = vec![0; crate::MAX_DATAGRAM];
+        // idle sessions are found by sweeping, a session may outlive its
+        // timeout by at most one sweep
+        let mut sweep =
+            time::interval((self.config.idle_timeout / 2).max(Duration::from_millis(10)));
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, peer) = received?;
+                    self.dispatch(&buf[..len], peer).await;
+                }
+                _ = sweep.tick() => self.expire(Instant::now()),
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, payload: &[u8], peer: SocketAddr) {
+        let now = Instant::now();
+        let config = self.config;
+        // a session that timed out but wasn't swept yet starts over
+        if let Some(session) = self.sessions.get(&peer) {
+            if now.duration_since(session.last_seen) >= config.idle_timeout {
+                let session = self.sessions.remove(&peer).unwrap();
+                self.expired(session);
+            }
+        }
+        if !self.sessions.contains_key(&peer) {
+            if self.sessions.len() >= config.max_sessions {
+                self.stats.rejected.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+            self.sessions
+                .insert(peer, Session::new(peer, now, &config.rate_limit));
+            self.stats
+                .sessions
+                .store(self.sessions.len(), Ordering::SeqCst);
+        }
+
+        let session = self.sessions.get_mut(&peer).unwrap();
+        session.last_seen = now;
+        session.bytes_in += payload.len() as u64;
+        if !session.admit(now, &config.rate_limit) {
+            self.stats.rate_limited.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+
+        session.messages += 1;
+        self.stats.handled.fetch_add(1, Ordering::SeqCst);
+        let Some(response) = self.handler.handle(session, payload) else {
+            return;
+        };
+        if !session.may_send(response.len(), &config.rate_limit) {
+            self.stats
+                .amplification_limited
+                .fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        session.bytes_out += response.len() as u64;
+        // one peer that can't be reached is no reason to stop serving the others
+        if let Err(e) = self.socket.send_to(&response, peer).await {
+            println!("Failed to answer {}: {}", peer, e);
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let idle_timeout = self.config.idle_timeout;
+        let idle: Vec<SocketAddr> = self
+            .sessions
+            .values()
+            .filter(|session| now.duration_since(session.last_seen) >= idle_timeout)
+            .map(|session| session.peer)
+            .collect();
+        for peer in idle {
+            let session = self.sessions.remove(&peer).unwrap();
+            self.expired(session);
+        }
+    }
+
+    fn expired(&mut self, session: Session<H::State>) {
+        self.stats.expired.fetch_add(1, Ordering::SeqCst);
+        self.stats
+            .sessions
+            .store(self.sessions.len(), Ordering::SeqCst);
+        self.handler.expired(session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    // answers with how many messages the peer sent in this session
+    struct Counter;
+
+    impl Handler for Counter {
+        type State = u32;
+
+        fn handle(&mut self, session: &mut Session<u32>, _payload: &[u8]) -> Option<Vec<u8>> {
+            session.state += 1;
+            Some(session.state.to_string().into_bytes())
+        }
+    }
+
+    async fn spawn<H: Handler>(handler: H, config: SessionConfig) -> (SocketAddr, Arc<Stats>) {
+        let server = SessionServer::bind("127.0.0.1:0".parse().unwrap(), handler, config)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let stats = server.stats();
+        tokio::spawn(server.run());
+        (addr, stats)
+    }
+
+    async fn client() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    // `None` if nothing comes back in time
+    async fn request(socket: &UdpSocket, server: SocketAddr, payload: &[u8]) -> Option<String> {
+        socket.send_to(payload, server).await.unwrap();
+        let mut buf = [0; 1024];
+        let (len, _) = timeout(Duration::from_millis(200), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    #[tokio::test]
+    async fn echoes() {
+        let (server, stats) = spawn(Echo, SessionConfig::default()).await;
+        let socket = client().await;
+        assert_eq!(request(&socket, server, b"hello").await.unwrap(), "hello");
+        assert_eq!(stats.handled(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_state_per_peer() {
+        let (server, stats) = spawn(Counter, SessionConfig::default()).await;
+        let (first, second) = (client().await, client().await);
+        assert_eq!(request(&first, server, b"x").await.unwrap(), "1");
+        assert_eq!(request(&first, server, b"x").await.unwrap(), "2");
+        assert_eq!(request(&second, server, b"x").await.unwrap(), "1");
+        assert_eq!(request(&first, server, b"x").await.unwrap(), "3");
+        assert_eq!(stats.sessions(), 2);
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire() {
+        let config = SessionConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..SessionConfig::default()
+        };
+        let (server, stats) = spawn(Counter, config).await;
+        let socket = client().await;
+        assert_eq!(request(&socket, server, b"x").await.unwrap(), "1");
+        assert_eq!(request(&socket, server, b"x").await.unwrap(), "2");
+
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(stats.expired(), 1);
+        assert_eq!(stats.sessions(), 0);
+        // a new session, with fresh state
+        assert_eq!(request(&socket, server, b"x").await.unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn limits_messages_per_peer() {
+        let config = SessionConfig {
+            rate_limit: RateLimit {
+                messages_per_sec: 1.0,
+                burst: 5,
+                ..RateLimit::default()
+            },
+            ..SessionConfig::default()
+        };
+        let (server, stats) = spawn(Echo, config).await;
+        let greedy = client().await;
+        for _ in 0..20 {
+            greedy.send_to(b"flood", server).await.unwrap();
+        }
+        let mut answered = 0;
+        let mut buf = [0; 16];
+        while timeout(Duration::from_millis(200), greedy.recv_from(&mut buf))
+            .await
+            .is_ok()
+        {
+            answered += 1;
+        }
+        assert_eq!(answered, 5);
+        assert_eq!(stats.rate_limited(), 15);
+
+        // other peers have their own budget
+        let polite = client().await;
+        assert_eq!(request(&polite, server, b"hi").await.unwrap(), "hi");
+    }
+
+    #[tokio::test]
+    async fn does_not_amplify() {
+        // answers a byte with a kilobyte
+        struct Amplifier;
+
+        impl Handler for Amplifier {
+            type State = ();
+
+            fn handle(&mut self, _session: &mut Session<()>, payload: &[u8]) -> Option<Vec<u8>> {
+                Some(payload.repeat(1000))
+            }
+        }
+
+        let (server, stats) = spawn(Amplifier, SessionConfig::default()).await;
+        let socket = client().await;
+        assert_eq!(request(&socket, server, b"x").await, None);
+        assert_eq!(stats.amplification_limited(), 1);
+    }
+
+    #[tokio::test]
+    async fn caps_the_number_of_sessions() {
+        let config = SessionConfig {
+            max_sessions: 1,
+            ..SessionConfig::default()
+        };
+        let (server, stats) = spawn(Echo, config).await;
+        let (first, second) = (client().await, client().await);
+        assert!(request(&first, server, b"in").await.is_some());
+        assert_eq!(request(&second, server, b"too").await, None);
+        assert_eq!(stats.rejected(), 1);
+        // the existing session carries on
+        assert!(request(&first, server, b"still").await.is_some());
+    }
+}
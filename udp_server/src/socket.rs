@@ -0,0 +1,149 @@
+// The datagram socket the protocol runs on.
+//
+// The reliability layer only needs to send, receive and wait with a timeout,
+// so it is written against the `Datagram` trait instead of `UdpSocket`
+// directly. That lets tests put a `LossySocket` in between, which throws
+// datagrams away on purpose.
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// The largest payload a single UDP datagram can carry over IPv4.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// What the protocol needs from a socket, implemented by [`UdpSocket`].
+pub trait Datagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    /// `None` blocks forever, like [`UdpSocket::set_read_timeout`].
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The largest message `send_to` accepts.
+    fn max_message(&self) -> usize {
+        MAX_DATAGRAM
+    }
+}
+
+impl Datagram for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// A socket that silently drops a share of the datagrams it sends, to see
+/// how the protocol copes with a bad network.
+///
+/// The drops come from a seeded generator, so a test sees the same losses on
+/// every run.
+pub struct LossySocket<S = UdpSocket> {
+    inner: S,
+    // chance of dropping each datagram, 0.0 to 1.0
+    loss: f64,
+    rng: Mutex<u64>,
+    dropped: Mutex<u64>,
+}
+
+impl<S: Datagram> LossySocket<S> {
+    pub fn new(inner: S, loss: f64, seed: u64) -> LossySocket<S> {
+        LossySocket {
+            inner,
+            loss,
+            // xorshift gets stuck at zero
+            rng: Mutex::new(seed.max(1)),
+            dropped: Mutex::new(0),
+        }
+    }
+
+    /// How many datagrams were thrown away so far.
+    pub fn dropped(&self) -> u64 {
+        *self.dropped.lock().unwrap()
+    }
+
+    fn should_drop(&self) -> bool {
+        let mut state = self.rng.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state as f64 / u64::MAX as f64) < self.loss
+    }
+}
+
+impl<S: Datagram> Datagram for LossySocket<S> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if self.should_drop() {
+            *self.dropped.lock().unwrap() += 1;
+            // as far as the sender can tell, it went out fine
+            return Ok(buf.len());
+        }
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_message(&self) -> usize {
+        self.inner.max_message()
+    }
+}
+
+/// Whether `err` is what a read timeout looks like (which one depends on
+/// the platform).
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossy_socket_drops_about_the_given_share() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sender = LossySocket::new(UdpSocket::bind("127.0.0.1:0").unwrap(), 0.3, 42);
+        let to = receiver.local_addr().unwrap();
+
+        let mut buf = [0; 4];
+        for i in 0..500u32 {
+            let dropped = sender.dropped();
+            sender.send_to(&i.to_be_bytes(), to).unwrap();
+            if sender.dropped() == dropped {
+                // everything that isn't dropped arrives
+                receiver.recv_from(&mut buf).unwrap();
+                assert_eq!(u32::from_be_bytes(buf), i);
+            }
+        }
+        let dropped = sender.dropped();
+        assert!((100..200).contains(&dropped), "dropped {dropped}");
+    }
+}